@@ -0,0 +1,119 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::AccessLogFormat;
+use crate::log_access;
+use crate::structs::{AccessLogEntry, Context, NetIqLoadBalancer};
+use chrono::Utc;
+use pingora::prelude::Session;
+use serde_json::json;
+use std::time::Duration;
+
+impl NetIqLoadBalancer {
+    pub fn write_access_log(&self, session: &mut Session, ctx: &Context) {
+        let format = self.live_config.rp_config.load().access_log;
+        if format == AccessLogFormat::Off {
+            return;
+        }
+        let host = ctx.hostname.clone().or_else(|| self.get_host(session));
+        let req = session.req_header();
+        let header = |name: &str| req.headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let entry = AccessLogEntry {
+            time: Utc::now(),
+            client_ip: session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip().to_string()),
+            host,
+            method: req.method.to_string(),
+            path: req.uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+            protocol: format!("{:?}", req.version),
+            status: session.response_written().map_or(0, |r| r.status.as_u16()),
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+            upstream: ctx.fully_qualified_upstream.clone(),
+            backend: ctx.backend.clone(),
+            upstream_latency: ctx.upstream_latency,
+            total_latency: ctx.started.elapsed(),
+            sso_subject: ctx.sso_subject.clone(),
+            request_id: Some(ctx.request_id.clone()),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        };
+        log_access!("{}", entry.format(format));
+    }
+}
+
+impl AccessLogEntry {
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Combined => self.combined(),
+            AccessLogFormat::Json | AccessLogFormat::Off => self.json(),
+        }
+    }
+
+    fn json(&self) -> String {
+        json!({
+            "time": self.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "client_ip": self.client_ip,
+            "host": self.host,
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "status": self.status,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "upstream": self.upstream,
+            "backend": self.backend,
+            "upstream_latency_ms": self.upstream_latency.map(millis),
+            "total_latency_ms": millis(self.total_latency),
+            "sso_subject": self.sso_subject,
+            "request_id": self.request_id,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+
+    /// `client - subject [time] "request" status bytes "referer" "user agent"`, then
+    /// key=value pairs with the latencies in seconds, as nginx's `$request_time`.
+    fn combined(&self) -> String {
+        let quoted = |v: &Option<String>| v.as_deref().map_or("-".to_string(), |v| escape(v, true));
+        let bare = |v: &Option<String>| v.as_deref().map_or("-".to_string(), |v| escape(v, false));
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" host={} upstream={} backend={} bytes_in={} rt={:.3} urt={} request_id={}",
+            bare(&self.client_ip),
+            bare(&self.sso_subject),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.method, true),
+            escape(&self.path, true),
+            self.protocol,
+            self.status,
+            self.bytes_out,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            bare(&self.host),
+            bare(&self.upstream),
+            bare(&self.backend),
+            self.bytes_in,
+            self.total_latency.as_secs_f64(),
+            self.upstream_latency.map_or("-".to_string(), |d| format!("{:.3}", d.as_secs_f64())),
+            bare(&self.request_id),
+        )
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    (d.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/// Keeps client supplied values from breaking out of their quotes, field or line.
+fn escape(value: &str, quoted: bool) -> String {
+    value.chars().fold(String::with_capacity(value.len()), |mut out, c| {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            ' ' if !quoted => out.push_str("\\x20"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+        out
+    })
+}
@@ -0,0 +1,81 @@
+use super::*;
+use chrono::TimeZone;
+
+fn entry() -> AccessLogEntry {
+    AccessLogEntry {
+        time: Utc.with_ymd_and_hms(2026, 3, 14, 9, 26, 53).single().expect("valid time"),
+        client_ip: Some("192.0.2.10".to_string()),
+        host: Some("kibana.example.com".to_string()),
+        method: "GET".to_string(),
+        path: "/app/discover?q=1".to_string(),
+        protocol: "HTTP/1.1".to_string(),
+        status: 200,
+        bytes_in: 12,
+        bytes_out: 3456,
+        upstream: Some("pipeline-kibana".to_string()),
+        backend: Some("10.0.0.1:5601".to_string()),
+        upstream_latency: Some(Duration::from_micros(41_250)),
+        total_latency: Duration::from_micros(43_600),
+        sso_subject: Some("Jane Doe".to_string()),
+        request_id: Some("req-1".to_string()),
+        referer: None,
+        user_agent: Some("curl/8.5.0".to_string()),
+    }
+}
+
+#[test]
+fn json_line_has_every_field() {
+    let line: serde_json::Value =
+        serde_json::from_str(&entry().format(AccessLogFormat::Json)).expect("access log line is json");
+
+    assert_eq!(
+        line,
+        json!({
+            "time": "2026-03-14T09:26:53.000Z",
+            "client_ip": "192.0.2.10",
+            "host": "kibana.example.com",
+            "method": "GET",
+            "path": "/app/discover?q=1",
+            "protocol": "HTTP/1.1",
+            "status": 200,
+            "bytes_in": 12,
+            "bytes_out": 3456,
+            "upstream": "pipeline-kibana",
+            "backend": "10.0.0.1:5601",
+            "upstream_latency_ms": 41.25,
+            "total_latency_ms": 43.6,
+            "sso_subject": "Jane Doe",
+            "request_id": "req-1",
+            "referer": null,
+            "user_agent": "curl/8.5.0"
+        })
+    );
+}
+
+#[test]
+fn combined_line_starts_like_apache_combined() {
+    assert_eq!(
+        entry().format(AccessLogFormat::Combined),
+        "192.0.2.10 - Jane\\x20Doe [14/Mar/2026:09:26:53 +0000] \"GET /app/discover?q=1 HTTP/1.1\" 200 3456 \"-\" \"curl/8.5.0\" \
+         host=kibana.example.com upstream=pipeline-kibana backend=10.0.0.1:5601 bytes_in=12 rt=0.044 urt=0.041 request_id=req-1"
+    );
+}
+
+#[test]
+fn combined_line_escapes_client_values() {
+    let entry = AccessLogEntry {
+        path: "/\"quoted\"\n".to_string(),
+        user_agent: Some("evil\" agent".to_string()),
+        upstream: None,
+        backend: None,
+        upstream_latency: None,
+        ..entry()
+    };
+
+    let line = entry.format(AccessLogFormat::Combined);
+
+    assert!(line.contains("\"GET /\\\"quoted\\\"\\x0a HTTP/1.1\""));
+    assert!(line.contains("\"evil\\\" agent\""));
+    assert!(line.contains("upstream=- backend=- bytes_in=12 rt=0.044 urt=-"));
+    assert_eq!(line.lines().count(), 1);
+}
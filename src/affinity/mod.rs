@@ -0,0 +1,44 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::AffinityConfig;
+use crate::structs::AuthVerifier;
+use crate::utils::cookie_value;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use pingora::http::RequestHeader;
+
+impl AuthVerifier {
+    /// Backend the request is pinned to by a valid affinity cookie of the upstream.
+    pub fn pinned_backend(&self, upstream: &str, req: &RequestHeader, conf: &AffinityConfig) -> Option<String> {
+        let value = cookie_value(req, &conf.cookie_name)?;
+        let (backend, signature) = value.split_once('.')?;
+        let backend = String::from_utf8(URL_SAFE_NO_PAD.decode(backend).ok()?).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.verify_signature(affinity_payload(upstream, &backend).as_bytes(), &signature)
+            .then_some(backend)
+    }
+
+    /// `Set-Cookie` value pinning the client to `backend`.
+    pub fn affinity_cookie(&self, upstream: &str, backend: &str, conf: &AffinityConfig, secure: bool) -> anyhow::Result<String> {
+        let signature = self.sign(affinity_payload(upstream, backend).as_bytes())?;
+        let mut cookie = format!(
+            "{}={}.{}; Path=/; HttpOnly; SameSite=Lax",
+            conf.cookie_name,
+            URL_SAFE_NO_PAD.encode(backend),
+            URL_SAFE_NO_PAD.encode(signature)
+        );
+        if conf.max_age_secs > 0 {
+            cookie.push_str(&format!("; Max-Age={}", conf.max_age_secs));
+        }
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        Ok(cookie)
+    }
+}
+
+/// The upstream is signed too, so a cookie can't pin another upstream's requests.
+fn affinity_payload(upstream: &str, backend: &str) -> String {
+    format!("affinity\n{upstream}\n{backend}")
+}
@@ -0,0 +1,64 @@
+use super::*;
+use crate::config::RPConfig;
+
+fn conf(value: serde_json::Value) -> AffinityConfig {
+    serde_json::from_value(value).expect("valid affinity")
+}
+
+fn req_with_cookie(cookie: &str) -> RequestHeader {
+    let mut req = RequestHeader::build("GET", b"/", None).expect("valid request");
+    req.append_header("Cookie", cookie).expect("valid header");
+    req
+}
+
+/// `name=value` part of a `Set-Cookie` header.
+fn pair(set_cookie: &str) -> &str {
+    set_cookie.split(';').next().unwrap_or_default()
+}
+
+#[test]
+fn signed_cookie_pins_the_backend() {
+    let verifier = AuthVerifier::new_for_tests(RPConfig::default());
+    let conf = conf(serde_json::json!({}));
+
+    let cookie = verifier
+        .affinity_cookie("kibana", "10.0.0.1:5601", &conf, false)
+        .expect("cookie should be signed");
+    assert!(cookie.starts_with("rproxy_affinity="));
+    assert!(cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax"));
+
+    let req = req_with_cookie(&format!("theme=dark; {}", pair(&cookie)));
+    assert_eq!(
+        verifier.pinned_backend("kibana", &req, &conf).as_deref(),
+        Some("10.0.0.1:5601")
+    );
+}
+
+#[test]
+fn tampered_or_foreign_cookies_are_ignored() {
+    let verifier = AuthVerifier::new_for_tests(RPConfig::default());
+    let conf = conf(serde_json::json!({}));
+    let cookie = verifier
+        .affinity_cookie("kibana", "10.0.0.1:5601", &conf, false)
+        .expect("cookie should be signed");
+    let (_, signature) = pair(&cookie).split_once('.').expect("signed value");
+
+    let forged = format!("rproxy_affinity={}.{signature}", URL_SAFE_NO_PAD.encode("10.0.0.2:5601"));
+    assert_eq!(verifier.pinned_backend("kibana", &req_with_cookie(&forged), &conf), None);
+    assert_eq!(verifier.pinned_backend("grafana", &req_with_cookie(pair(&cookie)), &conf), None);
+    assert_eq!(verifier.pinned_backend("kibana", &req_with_cookie("rproxy_affinity=garbage"), &conf), None);
+    assert_eq!(verifier.pinned_backend("kibana", &req_with_cookie("theme=dark"), &conf), None);
+}
+
+#[test]
+fn cookie_attributes_follow_the_config() {
+    let verifier = AuthVerifier::new_for_tests(RPConfig::default());
+    let conf = conf(serde_json::json!({"cookie_name": "sticky", "max_age_secs": 3600}));
+
+    let cookie = verifier
+        .affinity_cookie("kibana", "10.0.0.1:5601", &conf, true)
+        .expect("cookie should be signed");
+
+    assert!(cookie.starts_with("sticky="));
+    assert!(cookie.ends_with("; Max-Age=3600; Secure"));
+}
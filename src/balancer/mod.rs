@@ -0,0 +1,145 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::{HashOn, LoadBalancing};
+use crate::structs::{Balancer, Context, InFlight, NetIqLoadBalancer};
+use crate::utils::cookie_value;
+use pingora::http::RequestHeader;
+use pingora::lb::discovery::Static;
+use pingora::lb::selection::{BackendIter, BackendSelection};
+use pingora::lb::{Backend, Backends, LoadBalancer};
+use prometheus::IntGauge;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+impl Balancer {
+    pub async fn build(backends: BTreeSet<Backend>, algorithm: &LoadBalancing) -> Option<Self> {
+        Some(match algorithm {
+            LoadBalancing::RoundRobin => Self::RoundRobin(load(backends).await?),
+            LoadBalancing::Random => Self::Random(load(backends).await?),
+            LoadBalancing::LeastConn => Self::LeastConn(load(backends).await?),
+            LoadBalancing::P2c => Self::P2c(load(backends).await?),
+            LoadBalancing::Ketama { hash_on, key } => Self::Ketama {
+                lb: load(backends).await?,
+                hash_on: *hash_on,
+                key: key.clone(),
+            },
+        })
+    }
+
+    pub fn backends(&self) -> Arc<BTreeSet<Backend>> {
+        match self {
+            Self::RoundRobin(lb) | Self::LeastConn(lb) | Self::P2c(lb) => lb.backends().get_backend(),
+            Self::Random(lb) => lb.backends().get_backend(),
+            Self::Ketama { lb, .. } => lb.backends().get_backend(),
+        }
+    }
+
+    /// What Ketama hashes for this request: the configured header or cookie, the
+    /// client IP when the request has neither. Empty for the other algorithms.
+    pub fn hash_key(&self, req: &RequestHeader, client_ip: Option<&str>) -> Vec<u8> {
+        let Self::Ketama { hash_on, key, .. } = self else {
+            return vec![];
+        };
+        let value = match hash_on {
+            HashOn::ClientIp => None,
+            HashOn::Header => req.headers.get(key.as_str()).map(|v| v.as_bytes().to_vec()),
+            HashOn::Cookie => cookie_value(req, key).map(String::into_bytes),
+        };
+        value.unwrap_or_else(|| client_ip.unwrap_or_default().as_bytes().to_vec())
+    }
+
+    /// Picks a backend that `accept` agrees to.
+    pub fn select_with(&self, key: &[u8], in_flight: &InFlight, accept: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let accept = |backend: &Backend, ready: bool| ready && accept(backend);
+        match self {
+            Self::RoundRobin(lb) => lb.select_with(key, 256, accept),
+            Self::Random(lb) => lb.select_with(key, 256, accept),
+            Self::Ketama { lb, .. } => lb.select_with(key, 256, accept),
+            Self::LeastConn(lb) => least_busy(lb, in_flight, accept, false),
+            Self::P2c(lb) => least_busy(lb, in_flight, accept, true),
+        }
+    }
+}
+
+async fn load<S>(backends: BTreeSet<Backend>) -> Option<LoadBalancer<S>>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let balancer = LoadBalancer::<S>::from_backends(Backends::new(Static::new(backends)));
+    balancer.update().await.ok()?;
+    Some(balancer)
+}
+
+/// Least requests in flight relative to the weight, among all candidates or among
+/// two random ones. Weight 0 backends are only candidates when nothing else is.
+fn least_busy<S>(
+    lb: &LoadBalancer<S>,
+    in_flight: &InFlight,
+    accept: impl Fn(&Backend, bool) -> bool,
+    two_choices: bool,
+) -> Option<Backend>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let backends = lb.backends().get_backend();
+    let accepted: Vec<&Backend> = backends.iter().filter(|b| accept(b, lb.backends().ready(b))).collect();
+    let weighted: Vec<&Backend> = accepted.iter().copied().filter(|b| b.weight > 0).collect();
+    let candidates = if weighted.is_empty() { accepted } else { weighted };
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let load = |b: &Backend| in_flight.get(&b.addr.to_string()).map_or(0, |n| *n) as u128;
+    let weight = |b: &Backend| b.weight.max(1) as u128;
+    // a/wa < b/wb, without dividing
+    let less_busy = |a: &Backend, b: &Backend| load(a) * weight(b) < load(b) * weight(a);
+
+    let len = candidates.len();
+    let start = rand::random_range(0..len);
+    if two_choices {
+        let first = candidates[start];
+        if len == 1 {
+            return Some(first.clone());
+        }
+        let second = candidates[(start + rand::random_range(1..len)) % len];
+        let pick = if less_busy(second, first) { second } else { first };
+        return Some(pick.clone());
+    }
+    let mut best = candidates[start];
+    for offset in 1..len {
+        let candidate = candidates[(start + offset) % len];
+        if less_busy(candidate, best) {
+            best = candidate;
+        }
+    }
+    Some(best.clone())
+}
+
+impl NetIqLoadBalancer {
+    /// Counts the request as in flight to its backend, releasing the one of a
+    /// previous attempt.
+    pub fn acquire_backend(&self, ctx: &mut Context, backend: &str) {
+        self.release_backend(ctx);
+        *self.in_flight.entry(backend.to_string()).or_default() += 1;
+        ctx.in_flight = Some(backend.to_string());
+        self.active_connections(ctx).inc();
+    }
+
+    pub fn release_backend(&self, ctx: &mut Context) {
+        if let Some(backend) = ctx.in_flight.take() {
+            self.in_flight.remove_if_mut(&backend, |_, n| {
+                *n = n.saturating_sub(1);
+                *n == 0
+            });
+            self.active_connections(ctx).dec();
+        }
+    }
+
+    fn active_connections(&self, ctx: &Context) -> IntGauge {
+        let upstream = ctx.fully_qualified_upstream.as_deref().unwrap_or_default();
+        self.metrics.active_connections.with_label_values(&[upstream])
+    }
+}
@@ -0,0 +1,129 @@
+use super::*;
+use crate::config::RPConfig;
+use pingora::prelude::ProxyHttp;
+use std::collections::BTreeMap;
+
+const BACKENDS: [&str; 3] = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"];
+
+async fn balancer(algorithm: serde_json::Value) -> Balancer {
+    let algorithm: LoadBalancing = serde_json::from_value(algorithm).expect("valid load_balancing");
+    let backends = BACKENDS.iter().map(|a| Backend::new(a).expect("valid address")).collect();
+    Balancer::build(backends, &algorithm).await.expect("balancer should be built")
+}
+
+fn counts(balancer: &Balancer, in_flight: &InFlight, rounds: usize) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for _ in 0..rounds {
+        let backend = balancer.select_with(b"", in_flight, |_| true).expect("backend should be selected");
+        *counts.entry(backend.to_string()).or_insert(0usize) += 1;
+    }
+    counts
+}
+
+fn req(headers: &[(&str, &str)]) -> RequestHeader {
+    let mut req = RequestHeader::build("GET", b"/", None).expect("valid request");
+    for (name, value) in headers {
+        req.append_header(name.to_string(), *value).expect("valid header");
+    }
+    req
+}
+
+#[tokio::test]
+async fn random_spreads_over_all_backends() {
+    let balancer = balancer(serde_json::json!({"algorithm": "random"})).await;
+
+    let counts = counts(&balancer, &InFlight::new(), 3_000);
+
+    assert_eq!(counts.len(), 3);
+    assert!(counts.values().all(|n| *n > 800));
+}
+
+#[tokio::test]
+async fn least_conn_picks_the_backend_with_fewest_requests_in_flight() {
+    let balancer = balancer(serde_json::json!({"algorithm": "least_conn"})).await;
+    let in_flight = InFlight::new();
+    in_flight.insert(BACKENDS[0].to_string(), 4);
+    in_flight.insert(BACKENDS[1].to_string(), 1);
+    in_flight.insert(BACKENDS[2].to_string(), 2);
+
+    assert_eq!(counts(&balancer, &in_flight, 100).get(BACKENDS[1]), Some(&100));
+
+    let accepted = balancer
+        .select_with(b"", &in_flight, |b| b.addr.to_string() != BACKENDS[1])
+        .expect("backend should be selected");
+    assert_eq!(accepted.to_string(), BACKENDS[2]);
+}
+
+#[tokio::test]
+async fn p2c_never_picks_the_busiest_backend() {
+    let balancer = balancer(serde_json::json!({"algorithm": "p2c"})).await;
+    let in_flight = InFlight::new();
+    in_flight.insert(BACKENDS[0].to_string(), 10);
+
+    let counts = counts(&balancer, &in_flight, 1_000);
+
+    assert_eq!(counts.get(BACKENDS[0]), None);
+    assert_eq!(counts.len(), 2);
+}
+
+#[tokio::test]
+async fn ketama_keeps_a_key_on_one_backend() {
+    let balancer = balancer(serde_json::json!({"algorithm": "ketama", "hash_on": "cookie", "key": "sid"})).await;
+    let in_flight = InFlight::new();
+
+    for sid in ["alice", "bob", "carol"] {
+        let key = balancer.hash_key(&req(&[("Cookie", &format!("theme=dark; sid={sid}"))]), Some("192.0.2.1"));
+        assert_eq!(key, sid.as_bytes());
+        let first = balancer.select_with(&key, &in_flight, |_| true).expect("backend should be selected");
+        for _ in 0..20 {
+            assert_eq!(balancer.select_with(&key, &in_flight, |_| true), Some(first.clone()));
+        }
+    }
+}
+
+#[tokio::test]
+async fn hash_key_falls_back_to_the_client_ip() {
+    let by_header = balancer(serde_json::json!({"algorithm": "ketama", "hash_on": "header", "key": "X-User"})).await;
+    let by_ip = balancer(serde_json::json!({"algorithm": "ketama"})).await;
+    let round_robin = balancer(serde_json::json!({"algorithm": "round_robin"})).await;
+
+    assert_eq!(by_header.hash_key(&req(&[("X-User", "u1")]), Some("192.0.2.1")), b"u1");
+    assert_eq!(by_header.hash_key(&req(&[]), Some("192.0.2.1")), b"192.0.2.1");
+    assert_eq!(by_ip.hash_key(&req(&[("X-User", "u1")]), Some("192.0.2.1")), b"192.0.2.1");
+    assert!(round_robin.hash_key(&req(&[]), Some("192.0.2.1")).is_empty());
+}
+
+#[test]
+fn in_flight_counts_follow_the_current_attempt() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let mut first = lb.new_ctx();
+    let mut second = lb.new_ctx();
+
+    lb.acquire_backend(&mut first, BACKENDS[0]);
+    lb.acquire_backend(&mut second, BACKENDS[0]);
+    assert_eq!(lb.in_flight.get(BACKENDS[0]).map(|n| *n), Some(2));
+
+    lb.acquire_backend(&mut first, BACKENDS[1]);
+    assert_eq!(lb.in_flight.get(BACKENDS[0]).map(|n| *n), Some(1));
+
+    lb.release_backend(&mut first);
+    lb.release_backend(&mut second);
+    lb.release_backend(&mut second);
+    assert!(lb.in_flight.is_empty());
+}
+
+#[test]
+fn active_connections_are_exposed_per_upstream() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let mut ctx = lb.new_ctx();
+    ctx.fully_qualified_upstream = Some("svc".to_string());
+    let gauge = lb.metrics.active_connections.with_label_values(&["svc"]);
+
+    lb.acquire_backend(&mut ctx, BACKENDS[0]);
+    lb.acquire_backend(&mut ctx, BACKENDS[1]);
+    assert_eq!(gauge.get(), 1);
+
+    lb.release_backend(&mut ctx);
+    lb.release_backend(&mut ctx);
+    assert_eq!(gauge.get(), 0);
+}
@@ -1,5 +1,5 @@
 use clap::Parser;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use serde_derive::{Deserialize, Serialize};
 use twelf::{Error, Layer, config};
@@ -28,7 +28,7 @@ pub fn load(path: PathBuf) -> Result<RPConfig, Error> {
     Ok(conf)
 }
 
-#[derive(Debug, Clone, Deserialize,Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize,Serialize)]
 pub struct UpstreamDetails {
     pub upstream: String,
 
@@ -61,39 +61,380 @@ pub struct UpstreamDetails {
 
     #[serde(default)]
     pub weight_on_false: u16,
+
+    /// Connect to the upstream over TLS, plaintext when not set.
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+
+    /// What the plaintext listener does with requests for this upstream.
+    #[serde(default)]
+    pub plain_http: PlainHttp,
+
+    /// `Strict-Transport-Security` added to HTTPS responses.
+    #[serde(default)]
+    pub hsts: Option<HstsConfig>,
+
+    /// Probe run by the proxy itself; backends failing it are not selected.
+    #[serde(default)]
+    pub active_health_check: Option<ActiveHealthCheckConfig>,
+
+    /// Ejects backends that keep failing proxied requests.
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+
+    /// Try other backends when a request fails, not retried when not set.
+    #[serde(default)]
+    pub retry: Option<RetryConfig>,
+
+    /// How a backend is picked for a request, round robin when not set.
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+
+    /// Pin clients to the backend that served their first request with a signed cookie.
+    #[serde(default)]
+    pub affinity: Option<AffinityConfig>,
+
+    /// `Host` sent to the backends, the client's `Host` when not set.
+    #[serde(default)]
+    pub host_rewrite: Option<String>,
+
+    /// Changes to the request before it goes to the backend.
+    #[serde(default)]
+    pub request_headers: Option<HeaderRules>,
+
+    /// Changes to the response before it goes to the client.
+    #[serde(default)]
+    pub response_headers: Option<HeaderRules>,
+}
+
+/// Headers removed, then set, then added. Values may use `{client_ip}`, `{host}`,
+/// `{upstream}`, `{request_id}`, `{method}`, `{path}`, `{scheme}`, `{sso_subject}`
+/// and `{sso_tenant}`; `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HeaderRules {
+    #[serde(default)]
+    pub remove: Vec<String>,
+
+    /// Replaces the header; removes it when the value renders empty.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+
+    /// Adds a value next to the existing ones, skipped when it renders empty.
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AffinityConfig {
+    #[serde(default = "default_affinity_cookie_name")]
+    pub cookie_name: String,
+
+    /// Lifetime of the cookie, 0 keeps it for the browser session.
+    #[serde(default)]
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    Random,
+    /// Fewest requests in flight relative to the weight.
+    LeastConn,
+    /// The less busy of two random backends.
+    P2c,
+    /// Consistent hashing, requests with the same key keep going to the same backend.
+    Ketama {
+        #[serde(default)]
+        hash_on: HashOn,
+        /// Header or cookie name; requests without it are hashed on the client IP.
+        #[serde(default)]
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashOn {
+    #[default]
+    ClientIp,
+    Header,
+    Cookie,
+}
+
+/// Line format of the access log, written to its own file next to the rproxy log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    /// Apache combined log format followed by rproxy's own fields.
+    Combined,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PlainHttp {
+    /// Moved permanently to https, clients may turn a POST into a GET.
+    #[default]
+    #[serde(rename = "redirect_301")]
+    Redirect301,
+    /// Permanent redirect to https keeping the method and body.
+    #[serde(rename = "redirect_308")]
+    Redirect308,
+    /// Served over plain http, for internal-only hosts.
+    #[serde(rename = "proxy")]
+    Proxy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HstsConfig {
+    #[serde(default = "default_hsts_max_age")]
+    pub max_age: u64,
+
+    #[serde(default)]
+    pub include_subdomains: bool,
+
+    #[serde(default)]
+    pub preload: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ActiveHealthCheckConfig {
+    /// HTTP GET of this path, a plain TCP connect when empty.
+    #[serde(default)]
+    pub path: String,
+
+    /// Host header (and SNI of TLS upstreams) of the HTTP check; when empty the
+    /// upstream's `tls.sni`, or the upstream name without one.
+    #[serde(default)]
+    pub host: String,
+
+    #[serde(default = "default_health_check_status")]
+    pub expected_status: u16,
+
+    /// Text the response body has to contain.
+    #[serde(default)]
+    pub expected_body: String,
+
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// Consecutive successes before an unhealthy backend is selected again.
+    #[serde(default = "default_health_check_rise")]
+    pub rise: u32,
+
+    /// Consecutive failures before a healthy backend stops being selected.
+    #[serde(default = "default_health_check_fall")]
+    pub fall: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OutlierDetectionConfig {
+    /// Connect errors, broken connections or 5xx responses in a row that eject a backend.
+    #[serde(default = "default_outlier_consecutive_failures")]
+    pub consecutive_failures: u32,
+
+    /// First ejection time, doubled on every further ejection of the same backend.
+    #[serde(default = "default_outlier_base_ejection_secs")]
+    pub base_ejection_secs: u64,
+
+    /// Longest ejection; a backend not ejected for this long starts over at the base time.
+    #[serde(default = "default_outlier_max_ejection_secs")]
+    pub max_ejection_secs: u64,
+
+    /// Share of the pool that may be ejected at the same time, rounded down.
+    #[serde(default = "default_outlier_max_ejection_percent")]
+    pub max_ejection_percent: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// Attempts after the first one, each on a backend that was not tried yet.
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+
+    /// Also retry idempotent requests answered with 502 or 503; connect errors are
+    /// always retried.
+    #[serde(default)]
+    pub on_502_503: bool,
+
+    /// Retries per second allowed as a percentage of the requests to the upstream.
+    #[serde(default = "default_retry_budget_percent")]
+    pub budget_percent: u32,
+
+    /// Retries per second allowed whatever the traffic.
+    #[serde(default = "default_retry_budget_min_per_sec")]
+    pub budget_min_per_sec: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct UpstreamTlsConfig {
+    /// SNI sent to the upstream and checked against its certificate, the request
+    /// hostname when empty.
+    #[serde(default)]
+    pub sni: String,
+
+    #[serde(default)]
+    pub verify: UpstreamTlsVerify,
+
+    /// PEM bundle trusted for the upstream certificate instead of the system roots.
+    #[serde(default)]
+    pub ca_file: String,
+
+    /// Client certificate chain (leaf first) and key presented to the upstream.
+    #[serde(default)]
+    pub client_cert_file: String,
+
+    #[serde(default)]
+    pub client_key_file: String,
+
+    /// Issue the client certificate from Vault PKI instead of reading it from files.
+    #[serde(default)]
+    pub vault_pki: Option<VaultPkiConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamTlsVerify {
+    /// Certificate chain and hostname.
+    #[default]
+    Full,
+    /// Certificate chain only.
+    Chain,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct VaultPkiConfig {
+    #[serde(default = "default_vault_pki_mount")]
+    pub mount: String,
+
+    pub role: String,
+
+    pub common_name: String,
+
+    /// Requested lifetime, e.g. `72h`; the role default when empty.
+    #[serde(default)]
+    pub ttl: String,
+}
+
+/// A routing rule evaluated before `host_to_upstream`. Every condition that is set
+/// has to match; rules are tried in the order they are declared.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RouteConfig {
+    /// Exact hostname, or `*.example.com` for any subdomain of `example.com`.
+    #[serde(default)]
+    pub host: String,
+
+    /// `example.com` itself and any of its subdomains.
+    #[serde(default)]
+    pub host_suffix: String,
+
+    #[serde(default)]
+    pub path_prefix: String,
+
+    #[serde(default)]
+    pub path_regex: String,
+
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    /// Request headers that must be present with exactly this value.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    #[serde(flatten)]
+    pub details: UpstreamDetails,
+}
+
+/// A certificate served to TLS handshakes whose SNI matches one of `hosts`, read
+/// either from a Vault secret laid out like `path_to_cert_secret` or from a
+/// private key file and a chain file (leaf first).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsCertConfig {
+    /// Exact hostnames, or `*.example.com` for a single label below `example.com`.
+    pub hosts: Vec<String>,
+
+    #[serde(default)]
+    pub vault_path: String,
+
+    #[serde(default)]
+    pub cert_file: String,
+
+    #[serde(default)]
+    pub key_file: String,
 }
 
 #[config]
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct RPConfig {
     pub port: u64,
     pub tls_port: u64,
+    /// Plaintext proxy listener, 0 disables it.
+    #[serde(default)]
+    pub http_port: u64,
 
     pub consul_url: String,
     pub consul_pool_secs: u64,
     pub consul_leader_pool_secs: u64,
+    #[serde(default = "default_consul_drain_grace_secs")]
+    pub consul_drain_grace_secs: u64,
 
     #[cfg_attr(debug_assertions, allow(dead_code))]
     pub log_path: String,
     pub log_level: String,
+    #[serde(default)]
+    pub access_log: AccessLogFormat,
 
     pub vault_address: String,
     pub role_id: String,
     pub secret_id: String,
     pub path_to_cert_secret: String,
+    /// How often the certificate secret is re-read from Vault, 0 disables renewal.
+    #[serde(default = "default_vault_cert_renew_secs")]
+    pub vault_cert_renew_secs: u64,
 
     pub tls_enabled: bool,
     pub tls_private_cert: String,
     pub tls_chain_cert: String,
     pub tls_enable_h2: bool,
+    /// Per-hostname certificates, the Vault certificate is the fallback.
+    #[serde(default)]
+    pub tls_certs: Vec<TlsCertConfig>,
     
     pub jwt_cert: String,
     pub jwt_private_cert: String,
     pub client_id: String,
     pub client_secret: String,
+    /// IdP whose `/.well-known/openid-configuration` provides the endpoints and the
+    /// ID token issuer; `auth_url`, `token_url`, `jwks_url` and `id_token_issuer`
+    /// are used when not set.
+    #[serde(default)]
+    pub oidc_issuer: String,
+    /// How often the discovery document is fetched again, 0 fetches it at startup only.
+    #[serde(default = "default_oidc_discovery_secs")]
+    pub oidc_discovery_secs: u64,
+    #[serde(default)]
     pub auth_url: String,
+    #[serde(default)]
     pub token_url: String,
+    /// `openid` is always requested.
+    #[serde(default)]
     pub scopes: Vec<String>,
+    /// Send a PKCE (S256) challenge to the IdP and its verifier with the code.
+    #[serde(default)]
+    pub pkce: bool,
+    /// Keys the IdP signs its ID tokens with.
+    #[serde(default)]
+    pub jwks_url: String,
+    /// `iss` of the IdP's ID tokens.
+    #[serde(default)]
+    pub id_token_issuer: String,
     pub sso_cookie_expire_dayz: u16,
 
     pub aws_access_key: String,
@@ -101,9 +442,102 @@ pub struct RPConfig {
     pub r53_zone_id: String,
 
     pub host_to_upstream: HashMap<String, UpstreamDetails>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
     pub r53_fqdns: Vec<String>,
+
+    /// How often the config file is checked for changes, 0 disables watching.
+    #[serde(default)]
+    pub config_watch_secs: u64,
+
+    /// Proxies in front of rproxy, as CIDRs or addresses. Their `X-Forwarded-*` and
+    /// `Forwarded` headers are appended to, anyone else's are overwritten.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+impl RPConfig {
+    /// Every upstream referenced by `routes` or `host_to_upstream`.
+    pub fn all_upstreams(&self) -> impl Iterator<Item = &UpstreamDetails> {
+        self.routes
+            .iter()
+            .map(|r| &r.details)
+            .chain(self.host_to_upstream.values())
+    }
 }
 
 fn default_health_checks() -> String {
     "passing".to_string()
 }
+
+fn default_consul_drain_grace_secs() -> u64 {
+    30
+}
+
+fn default_vault_cert_renew_secs() -> u64 {
+    3600
+}
+
+fn default_oidc_discovery_secs() -> u64 {
+    3600
+}
+
+fn default_vault_pki_mount() -> String {
+    "pki".to_string()
+}
+
+fn default_hsts_max_age() -> u64 {
+    31_536_000
+}
+
+fn default_health_check_status() -> u16 {
+    200
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    5
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_health_check_rise() -> u32 {
+    2
+}
+
+fn default_health_check_fall() -> u32 {
+    3
+}
+
+fn default_outlier_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_outlier_base_ejection_secs() -> u64 {
+    30
+}
+
+fn default_outlier_max_ejection_secs() -> u64 {
+    300
+}
+
+fn default_outlier_max_ejection_percent() -> u8 {
+    50
+}
+
+fn default_affinity_cookie_name() -> String {
+    "rproxy_affinity".to_string()
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_retry_budget_percent() -> u32 {
+    20
+}
+
+fn default_retry_budget_min_per_sec() -> u32 {
+    3
+}
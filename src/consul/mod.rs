@@ -1,115 +1,154 @@
+#[cfg(test)]
+mod tests;
+
 use crate::config::{RPConfig, UpstreamDetails};
-use crate::utils::get_consul_nodes;
+use crate::utils::get_consul_nodes_blocking;
 use crate::{log_error, log_info};
 use dashmap::DashMap;
 use pingora::prelude::sleep;
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 
-use crate::structs::{ConsulNode, ConsulNodes};
-use std::sync::Arc;
-use tokio::sync::Semaphore;
+use crate::structs::{ConsulNode, ConsulNodes, Metrics};
 use tokio::task::JoinSet;
 
 pub type VecConsulNode = Vec<ConsulNode>;
-pub type HashMapConsulNodes = HashMap<String, VecConsulNode>;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 pub struct ConsulDiscovery {
     rp_config: RPConfig,
+    http_client: reqwest::Client,
+    metrics: Metrics,
 }
 
 impl ConsulDiscovery {
-    pub fn new(rp_config: RPConfig) -> Self {
-        ConsulDiscovery { rp_config }
+    pub fn new(rp_config: RPConfig, metrics: Metrics) -> Self {
+        ConsulDiscovery {
+            rp_config,
+            http_client: reqwest::Client::new(),
+            metrics,
+        }
     }
 
+    /// Watches every configured upstream with its own Consul blocking query and
+    /// forwards node changes to `tx`. `consul_pool_secs` is the `wait` of each query.
     pub async fn fetch_nodes(&self, tx: Sender<ConsulNodes>) {
         log_info!("Starting consul discovery...");
 
-        const MAX_CONCURRENCY: usize = 16;
+        let upstreams: BTreeMap<String, UpstreamDetails> = self
+            .rp_config
+            .all_upstreams()
+            .map(|u| (u.upstream.clone(), u.clone()))
+            .collect();
 
-        let mut cache: HashMapConsulNodes = HashMap::new();
-        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENCY));
-        let poll_interval = Duration::from_secs(self.rp_config.consul_pool_secs);
+        let mut join_set = JoinSet::new();
+        for (_, upstream) in upstreams {
+            let tx = tx.clone();
+            if upstream.is_upstream_static {
+                send_update(&tx, &upstream.upstream, static_nodes(&upstream)).await;
+                continue;
+            }
+            let watcher = ServiceWatcher {
+                consul_url: self.rp_config.consul_url.clone(),
+                wait: Duration::from_secs(self.rp_config.consul_pool_secs),
+                http_client: self.http_client.clone(),
+                upstream,
+                metrics: self.metrics.clone(),
+            };
+            join_set.spawn(async move { watcher.watch(tx).await });
+        }
 
-        loop {
-            let consul_url = Arc::<str>::from(self.rp_config.consul_url.clone());
-            let upsteams: Vec<UpstreamDetails> =
-                self.rp_config.host_to_upstream.values().cloned().collect();
-
-            let mut join_set = JoinSet::new();
-
-            for upstream in upsteams {
-                let consul_url = Arc::clone(&consul_url);
-                let semaphore = Arc::clone(&semaphore);
-                
-                join_set.spawn(async move {
-                    let permit = semaphore.acquire_owned().await;
-                    let service_name = upstream.upstream;
-                    let health_checks = upstream.health_checks;
-
-                    if permit.is_err() {
-                        return (
-                            service_name,
-                            Err(anyhow::anyhow!("Semaphore closed while acquiring permit")),
-                        );
-                    }
-                    let _permit = permit.unwrap();
-                    let res = if upstream.is_upstream_static {
-                        let (address, port) = upstream.upstream_static_host_port.split_once(':')
-                            .expect(&format!("Malformed host:port upstream_static_host property in {}", service_name));
-                        Ok(vec![ConsulNode {
-                            service_name: service_name.clone(),
-                            address: address.to_string(),
-                            service_port: port.parse::<u16>().unwrap_or(0),
-                            weight: 1
-                        }])
-                    } else {
-                        get_consul_nodes(consul_url.as_ref(),
-                                         &service_name,
-                                         &health_checks,
-                                         upstream.weighted,
-                                         &upstream.check_name,
-                                         &upstream.check_condition,
-                                         upstream.weight_on_true,
-                                         upstream.weight_on_false,
-                        ).await
-                    };
-                    (service_name, res)
-                });
+        // Dropping the set (when this future is aborted) aborts every watcher.
+        while let Some(joined) = join_set.join_next().await {
+            if let Err(join_err) = joined {
+                log_error!("Consul discovery task failed: {}", join_err);
             }
+        }
+    }
+}
 
-            while let Some(joined) = join_set.join_next().await {
-                match joined {
-                    Ok((service_name, Ok(nodes))) => {
-                        let changed = match cache.get(service_name.as_str()) {
-                            Some(cached_nodes) => cached_nodes != &nodes,
-                            None => true,
-                        };
-
-                        if changed {
-                            let dash: ConsulNodes = DashMap::new();
-                            dash.insert(service_name.clone(), nodes.clone());
-
-                            cache.insert(service_name.clone(), nodes);
-                            let _ = tx.send(dash).await;
-                        }
-                    }
-                    Ok((service_name, Err(err))) => {
-                        log_error!(
-                            "Error happened during consul nodes serde (proceeding) for {}: {}",
-                            service_name,
-                            err
-                        );
-                    }
-                    Err(join_err) => {
-                        log_error!("Consul discovery task failed: {}", join_err);
+struct ServiceWatcher {
+    consul_url: String,
+    wait: Duration,
+    http_client: reqwest::Client,
+    upstream: UpstreamDetails,
+    metrics: Metrics,
+}
+
+impl ServiceWatcher {
+    async fn watch(&self, tx: Sender<ConsulNodes>) {
+        let service_name = &self.upstream.upstream;
+        let mut index = 0;
+        let mut backoff = MIN_BACKOFF;
+        let mut last_sent: Option<VecConsulNode> = None;
+
+        loop {
+            let started = Instant::now();
+            let polled = get_consul_nodes_blocking(
+                &self.http_client,
+                &self.consul_url,
+                &self.upstream,
+                index,
+                self.wait,
+            )
+            .await;
+            self.metrics.record_consul_poll(service_name, started.elapsed(), polled.is_ok());
+            match polled {
+                Ok((returned_index, nodes)) => {
+                    backoff = MIN_BACKOFF;
+                    index = next_index(index, returned_index);
+                    if last_sent.as_ref() != Some(&nodes) {
+                        send_update(&tx, service_name, nodes.clone()).await;
+                        last_sent = Some(nodes);
                     }
                 }
+                Err(err) => {
+                    log_error!(
+                        "Error happened during consul blocking query (retrying in {:?}) for {}: {}",
+                        backoff,
+                        service_name,
+                        err
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
             }
-
-            sleep(poll_interval).await;
         }
     }
 }
+
+/// Index to use for the next blocking query, following Consul's guidance: an
+/// index that goes backwards means the agent's state was reset, so the next
+/// query starts over from 0, and an index must never be 0 once seen.
+fn next_index(previous: u64, returned: u64) -> u64 {
+    if returned < previous {
+        0
+    } else {
+        returned.max(1)
+    }
+}
+
+fn static_nodes(upstream: &UpstreamDetails) -> VecConsulNode {
+    let Some((address, port)) = upstream.upstream_static_host_port.split_once(':') else {
+        log_error!(
+            "Malformed host:port upstream_static_host property in {}",
+            upstream.upstream
+        );
+        return vec![];
+    };
+    vec![ConsulNode {
+        service_name: upstream.upstream.clone(),
+        address: address.to_string(),
+        service_port: port.parse::<u16>().unwrap_or(0),
+        weight: 1,
+    }]
+}
+
+async fn send_update(tx: &Sender<ConsulNodes>, service_name: &str, nodes: VecConsulNode) {
+    let dash: ConsulNodes = DashMap::new();
+    dash.insert(service_name.to_string(), nodes);
+    let _ = tx.send(dash).await;
+}
@@ -0,0 +1,213 @@
+use super::*;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, mpsc};
+use tokio::time::{Instant, timeout};
+
+/// Minimal stand-in for the Consul health endpoint that follows blocking query
+/// semantics: a request whose `index` is not behind the current one is held
+/// until the state changes or `wait` elapses.
+#[derive(Clone)]
+struct MockConsul {
+    state: Arc<Mutex<(u64, String)>>,
+    changed: Arc<Notify>,
+    failures_left: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl MockConsul {
+    fn new(index: u64, body: String) -> Self {
+        Self {
+            state: Arc::new(Mutex::new((index, body))),
+            changed: Arc::new(Notify::new()),
+            failures_left: Arc::new(AtomicUsize::new(0)),
+            requests: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn set(&self, index: u64, body: String) {
+        *self.state.lock().expect("mock state lock") = (index, body);
+        self.changed.notify_waiters();
+    }
+
+    fn current(&self) -> (u64, String) {
+        self.state.lock().expect("mock state lock").clone()
+    }
+
+    async fn serve(self) -> String {
+        let router = Router::new()
+            .route("/v1/health/service/{name}", get(health))
+            .with_state(self);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock consul");
+        let addr = listener.local_addr().expect("mock consul addr");
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}/")
+    }
+}
+
+async fn health(
+    State(mock): State<MockConsul>,
+    Path(_name): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    mock.requests.fetch_add(1, Ordering::SeqCst);
+    if mock
+        .failures_left
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+    {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let requested: u64 = params.get("index").and_then(|i| i.parse().ok()).unwrap_or(0);
+    let wait = params
+        .get("wait")
+        .and_then(|w| w.trim_end_matches('s').parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(300));
+    let deadline = Instant::now() + wait;
+
+    loop {
+        let changed = mock.changed.notified();
+        let (index, body) = mock.current();
+        if requested == 0 || index > requested {
+            return ([("X-Consul-Index", index.to_string())], body).into_response();
+        }
+        if timeout(deadline.saturating_duration_since(Instant::now()), changed)
+            .await
+            .is_err()
+        {
+            let (index, body) = mock.current();
+            return ([("X-Consul-Index", index.to_string())], body).into_response();
+        }
+    }
+}
+
+fn health_body(addresses: &[&str]) -> String {
+    let entries: Vec<String> = addresses
+        .iter()
+        .map(|a| {
+            format!(
+                r#"{{"Node":{{"Address":"{a}"}},"Service":{{"Service":"svc","Address":"","Port":8080}},"Checks":[]}}"#
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+fn upstream() -> UpstreamDetails {
+    serde_json::from_value(serde_json::json!({ "upstream": "svc" })).expect("valid upstream details")
+}
+
+fn start_watcher(consul_url: String, wait: Duration) -> mpsc::Receiver<ConsulNodes> {
+    let (tx, rx) = mpsc::channel(1);
+    let watcher = ServiceWatcher {
+        consul_url,
+        wait,
+        http_client: reqwest::Client::new(),
+        upstream: upstream(),
+        metrics: Metrics::try_new().expect("metrics should register"),
+    };
+    tokio::spawn(async move { watcher.watch(tx).await });
+    rx
+}
+
+async fn next_addresses(rx: &mut mpsc::Receiver<ConsulNodes>, within: Duration) -> Vec<String> {
+    let update = timeout(within, rx.recv())
+        .await
+        .expect("update should arrive in time")
+        .expect("channel should stay open");
+    let nodes = update.get("svc").expect("update for svc").clone();
+    nodes.into_iter().map(|n| n.address).collect()
+}
+
+#[test]
+fn next_index_follows_returned_index() {
+    assert_eq!(next_index(0, 12), 12);
+    assert_eq!(next_index(12, 12), 12);
+    assert_eq!(next_index(12, 15), 15);
+}
+
+#[test]
+fn next_index_resets_when_index_goes_backwards() {
+    assert_eq!(next_index(12, 3), 0);
+}
+
+#[test]
+fn next_index_is_never_zero_after_a_response() {
+    assert_eq!(next_index(0, 0), 1);
+}
+
+#[tokio::test]
+async fn watcher_propagates_changes_without_waiting_for_the_wait_timeout() {
+    let mock = MockConsul::new(5, health_body(&["10.0.0.1", "10.0.0.2"]));
+    let url = mock.clone().serve().await;
+    let mut rx = start_watcher(url, Duration::from_secs(30));
+
+    assert_eq!(next_addresses(&mut rx, Duration::from_secs(2)).await, ["10.0.0.1", "10.0.0.2"]);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    mock.set(6, health_body(&["10.0.0.1"]));
+
+    assert_eq!(next_addresses(&mut rx, Duration::from_secs(1)).await, ["10.0.0.1"]);
+}
+
+#[tokio::test]
+async fn watcher_blocks_instead_of_polling_when_nothing_changes() {
+    let mock = MockConsul::new(5, health_body(&["10.0.0.1"]));
+    let url = mock.clone().serve().await;
+    let mut rx = start_watcher(url, Duration::from_secs(1));
+
+    next_addresses(&mut rx, Duration::from_secs(2)).await;
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+
+    assert!(mock.requests.load(Ordering::SeqCst) <= 5);
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn watcher_recovers_when_consul_index_goes_backwards() {
+    let mock = MockConsul::new(100, health_body(&["10.0.0.1"]));
+    let url = mock.clone().serve().await;
+    let mut rx = start_watcher(url, Duration::from_secs(1));
+
+    next_addresses(&mut rx, Duration::from_secs(2)).await;
+    mock.set(3, health_body(&["10.0.0.9"]));
+
+    assert_eq!(next_addresses(&mut rx, Duration::from_secs(3)).await, ["10.0.0.9"]);
+}
+
+#[tokio::test]
+async fn watcher_backs_off_and_retries_after_errors() {
+    let mock = MockConsul::new(5, health_body(&["10.0.0.1"]));
+    mock.failures_left.store(3, Ordering::SeqCst);
+    let url = mock.clone().serve().await;
+    let started = Instant::now();
+    let mut rx = start_watcher(url, Duration::from_secs(30));
+
+    assert_eq!(next_addresses(&mut rx, Duration::from_secs(5)).await, ["10.0.0.1"]);
+
+    // 250ms + 500ms + 1s of backoff before the fourth attempt
+    assert!(started.elapsed() >= Duration::from_millis(1750));
+    assert_eq!(mock.requests.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn watcher_reports_a_service_that_lost_all_nodes() {
+    let mock = MockConsul::new(5, health_body(&["10.0.0.1"]));
+    let url = mock.clone().serve().await;
+    let mut rx = start_watcher(url, Duration::from_secs(30));
+
+    next_addresses(&mut rx, Duration::from_secs(2)).await;
+    mock.set(6, "[]".to_string());
+
+    assert!(next_addresses(&mut rx, Duration::from_secs(1)).await.is_empty());
+}
@@ -0,0 +1,93 @@
+#[cfg(test)]
+mod tests;
+
+use pingora::http::RequestHeader;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+const FORWARDED: &str = "Forwarded";
+
+/// Tells the backend who the client is and what it asked for. The headers of a
+/// `trusted` proxy are kept, with the client appended to the `for` chains; the
+/// headers of anyone else are replaced so a client can't forge them.
+pub fn set_forwarding_headers(
+    req: &mut RequestHeader,
+    client_ip: Option<IpAddr>,
+    tls: bool,
+    trusted: bool,
+) -> pingora::Result<()> {
+    let proto = if tls { "https" } else { "http" };
+    let host = original_host(req);
+    let incoming = |req: &RequestHeader, name: &str| if trusted { joined(req, name) } else { None };
+
+    let client = client_ip.map_or("unknown".to_string(), |ip| ip.to_string());
+    let xff = match incoming(req, X_FORWARDED_FOR) {
+        Some(chain) => format!("{chain}, {client}"),
+        None => client,
+    };
+    let x_proto = incoming(req, X_FORWARDED_PROTO).unwrap_or_else(|| proto.to_string());
+    let x_host = incoming(req, X_FORWARDED_HOST).or_else(|| host.clone());
+
+    let mut element = format!("for={};proto={proto}", forwarded_node(client_ip));
+    if let Some(host) = &host {
+        element.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    let forwarded = match incoming(req, FORWARDED) {
+        Some(elements) => format!("{elements}, {element}"),
+        None => element,
+    };
+
+    req.insert_header(X_FORWARDED_FOR, xff)?;
+    req.insert_header(X_FORWARDED_PROTO, x_proto)?;
+    match x_host {
+        Some(x_host) => req.insert_header(X_FORWARDED_HOST, x_host)?,
+        None => {
+            req.remove_header(X_FORWARDED_HOST);
+        }
+    }
+    req.insert_header(FORWARDED, forwarded)?;
+    Ok(())
+}
+
+/// `Host` as sent by the client, port included; the authority for HTTP/2.
+fn original_host(req: &RequestHeader) -> Option<String> {
+    req.headers
+        .get("Host")
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| req.uri.authority().map(|a| a.to_string()))
+}
+
+/// Every value of a header, in order, as one comma separated list.
+fn joined(req: &RequestHeader, name: &str) -> Option<String> {
+    let values: Vec<&str> = req
+        .headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// RFC 7239 node: IPv6 addresses are bracketed and quoted.
+fn forwarded_node(ip: Option<IpAddr>) -> String {
+    match ip {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{ip}]\""),
+        None => "unknown".to_string(),
+    }
+}
+
+/// A token as is, anything else as a quoted-string.
+fn forwarded_value(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        return value.to_string();
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
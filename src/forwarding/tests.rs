@@ -0,0 +1,87 @@
+use super::*;
+
+fn req(headers: &[(&str, &str)]) -> RequestHeader {
+    let mut req = RequestHeader::build("GET", b"/app", None).expect("valid request");
+    for (name, value) in headers {
+        req.append_header(name.to_string(), *value).expect("valid header");
+    }
+    req
+}
+
+fn header(req: &RequestHeader, name: &str) -> Option<String> {
+    req.headers.get(name).map(|v| v.to_str().expect("ascii header").to_string())
+}
+
+fn ip(addr: &str) -> Option<IpAddr> {
+    Some(addr.parse().expect("valid ip"))
+}
+
+#[test]
+fn untrusted_client_headers_are_replaced() {
+    let mut req = req(&[
+        ("Host", "kibana.example.com"),
+        ("X-Forwarded-For", "6.6.6.6"),
+        ("X-Forwarded-Proto", "http"),
+        ("X-Forwarded-Host", "evil.example.com"),
+        ("Forwarded", "for=6.6.6.6"),
+    ]);
+
+    set_forwarding_headers(&mut req, ip("192.0.2.10"), true, false).expect("headers set");
+
+    assert_eq!(header(&req, "X-Forwarded-For").as_deref(), Some("192.0.2.10"));
+    assert_eq!(header(&req, "X-Forwarded-Proto").as_deref(), Some("https"));
+    assert_eq!(header(&req, "X-Forwarded-Host").as_deref(), Some("kibana.example.com"));
+    assert_eq!(
+        header(&req, "Forwarded").as_deref(),
+        Some("for=192.0.2.10;proto=https;host=kibana.example.com")
+    );
+}
+
+#[test]
+fn trusted_proxy_headers_are_appended_to() {
+    let mut req = req(&[
+        ("Host", "kibana.example.com:8443"),
+        ("X-Forwarded-For", "203.0.113.7"),
+        ("X-Forwarded-For", "198.51.100.2"),
+        ("X-Forwarded-Proto", "https"),
+        ("X-Forwarded-Host", "kibana.example.com"),
+        ("Forwarded", "for=203.0.113.7;proto=https"),
+    ]);
+
+    set_forwarding_headers(&mut req, ip("10.0.0.5"), false, true).expect("headers set");
+
+    assert_eq!(header(&req, "X-Forwarded-For").as_deref(), Some("203.0.113.7, 198.51.100.2, 10.0.0.5"));
+    assert_eq!(header(&req, "X-Forwarded-Proto").as_deref(), Some("https"));
+    assert_eq!(header(&req, "X-Forwarded-Host").as_deref(), Some("kibana.example.com"));
+    assert_eq!(
+        header(&req, "Forwarded").as_deref(),
+        Some("for=203.0.113.7;proto=https, for=10.0.0.5;proto=http;host=\"kibana.example.com:8443\"")
+    );
+}
+
+#[test]
+fn trusted_proxy_without_headers_gets_them_set() {
+    let mut req = req(&[("Host", "grafana.example.com")]);
+
+    set_forwarding_headers(&mut req, ip("10.0.0.5"), true, true).expect("headers set");
+
+    assert_eq!(header(&req, "X-Forwarded-For").as_deref(), Some("10.0.0.5"));
+    assert_eq!(header(&req, "X-Forwarded-Proto").as_deref(), Some("https"));
+    assert_eq!(header(&req, "X-Forwarded-Host").as_deref(), Some("grafana.example.com"));
+}
+
+#[test]
+fn forwarded_quotes_ipv6_and_unknown_clients() {
+    let mut v6 = req(&[("Host", "api.example.com")]);
+    set_forwarding_headers(&mut v6, ip("2001:db8::1"), true, false).expect("headers set");
+    assert_eq!(
+        header(&v6, "Forwarded").as_deref(),
+        Some("for=\"[2001:db8::1]\";proto=https;host=api.example.com")
+    );
+    assert_eq!(header(&v6, "X-Forwarded-For").as_deref(), Some("2001:db8::1"));
+
+    let mut unknown = req(&[]);
+    set_forwarding_headers(&mut unknown, None, false, false).expect("headers set");
+    assert_eq!(header(&unknown, "Forwarded").as_deref(), Some("for=unknown;proto=http"));
+    assert_eq!(header(&unknown, "X-Forwarded-Host"), None);
+}
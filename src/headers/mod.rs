@@ -0,0 +1,212 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::HeaderRules;
+use crate::log_warn;
+use crate::structs::{Context, HeaderActions, HeaderTemplate, TemplatePart, TemplateVar, TemplateValues};
+use anyhow::{anyhow, bail};
+use http::{HeaderName, HeaderValue};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::Session;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+impl HeaderActions {
+    pub fn compile(rules: &HeaderRules) -> anyhow::Result<Self> {
+        let templates = |values: &BTreeMap<String, String>| {
+            values
+                .iter()
+                .map(|(name, value)| {
+                    let template = HeaderTemplate::parse(value).map_err(|e| anyhow!("header '{}': {}", name, e))?;
+                    Ok((header_name(name)?, template))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            remove: rules.remove.iter().map(|name| header_name(name)).collect::<anyhow::Result<_>>()?,
+            set: templates(&rules.set)?,
+            add: templates(&rules.add)?,
+        })
+    }
+
+    pub fn apply_to_request(&self, req: &mut RequestHeader, values: &TemplateValues) -> pingora::Result<()> {
+        self.apply(req, values)
+    }
+
+    pub fn apply_to_response(&self, resp: &mut ResponseHeader, values: &TemplateValues) -> pingora::Result<()> {
+        self.apply(resp, values)
+    }
+
+    fn apply(&self, headers: &mut impl EditHeaders, values: &TemplateValues) -> pingora::Result<()> {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, template) in &self.set {
+            match template.render(name, values) {
+                Some(value) => headers.insert(name, value)?,
+                None => headers.remove(name),
+            }
+        }
+        for (name, template) in &self.add {
+            if let Some(value) = template.render(name, values) {
+                headers.append(name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The header edits `RequestHeader` and `ResponseHeader` both have.
+trait EditHeaders {
+    fn insert(&mut self, name: &HeaderName, value: HeaderValue) -> pingora::Result<()>;
+    fn append(&mut self, name: &HeaderName, value: HeaderValue) -> pingora::Result<()>;
+    fn remove(&mut self, name: &HeaderName);
+}
+
+impl EditHeaders for RequestHeader {
+    fn insert(&mut self, name: &HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: &HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+impl EditHeaders for ResponseHeader {
+    fn insert(&mut self, name: &HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: &HeaderName, value: HeaderValue) -> pingora::Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+impl HeaderTemplate {
+    /// Splits `value` into literals and `{variable}`s.
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unclosed '{{' in '{}'", value),
+                        }
+                    }
+                    let var = TemplateVar::from_str(&name)?;
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Var(var));
+                }
+                '}' => bail!("unmatched '}}' in '{}'", value),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        for part in &parts {
+            if let TemplatePart::Literal(literal) = part {
+                HeaderValue::from_str(literal).map_err(|_| anyhow!("invalid value '{}'", value))?;
+            }
+        }
+        Ok(Self(parts))
+    }
+
+    /// None when the value is empty, or not a valid header value after substitution.
+    fn render(&self, name: &HeaderName, values: &TemplateValues) -> Option<HeaderValue> {
+        let rendered: String = self
+            .0
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => literal.as_str(),
+                TemplatePart::Var(var) => values.get(*var).unwrap_or_default(),
+            })
+            .collect();
+        if rendered.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&rendered)
+            .inspect_err(|_| log_warn!("Header {} rendered to an invalid value, not sent", name))
+            .ok()
+    }
+}
+
+impl FromStr for TemplateVar {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "client_ip" => TemplateVar::ClientIp,
+            "host" => TemplateVar::Host,
+            "upstream" => TemplateVar::Upstream,
+            "request_id" => TemplateVar::RequestId,
+            "method" => TemplateVar::Method,
+            "path" => TemplateVar::Path,
+            "scheme" => TemplateVar::Scheme,
+            "sso_subject" => TemplateVar::SsoSubject,
+            "sso_tenant" => TemplateVar::SsoTenant,
+            _ => bail!("unknown variable '{{{}}}'", name),
+        })
+    }
+}
+
+impl TemplateValues {
+    pub fn new(session: &Session, ctx: &Context) -> Self {
+        let req = session.req_header();
+        let tls = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+        Self {
+            client_ip: session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip().to_string()),
+            host: ctx.hostname.clone(),
+            upstream: ctx.fully_qualified_upstream.clone(),
+            request_id: ctx.request_id.clone(),
+            method: req.method.to_string(),
+            path: req.uri.path().to_string(),
+            scheme: if tls { "https" } else { "http" },
+            sso_subject: ctx.sso_subject.clone(),
+            sso_tenant: ctx.sso_tenant.clone(),
+        }
+    }
+
+    fn get(&self, var: TemplateVar) -> Option<&str> {
+        match var {
+            TemplateVar::ClientIp => self.client_ip.as_deref(),
+            TemplateVar::Host => self.host.as_deref(),
+            TemplateVar::Upstream => self.upstream.as_deref(),
+            TemplateVar::RequestId => Some(&self.request_id),
+            TemplateVar::Method => Some(&self.method),
+            TemplateVar::Path => Some(&self.path),
+            TemplateVar::Scheme => Some(self.scheme),
+            TemplateVar::SsoSubject => self.sso_subject.as_deref(),
+            TemplateVar::SsoTenant => self.sso_tenant.as_deref(),
+        }
+    }
+}
+
+fn header_name(name: &str) -> anyhow::Result<HeaderName> {
+    HeaderName::from_str(name).map_err(|_| anyhow!("invalid header name '{}'", name))
+}
@@ -0,0 +1,109 @@
+use super::*;
+
+fn rules(remove: &[&str], set: &[(&str, &str)], add: &[(&str, &str)]) -> HeaderRules {
+    let map = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    HeaderRules {
+        remove: remove.iter().map(|r| r.to_string()).collect(),
+        set: map(set),
+        add: map(add),
+    }
+}
+
+fn values() -> TemplateValues {
+    TemplateValues {
+        client_ip: Some("192.0.2.10".to_string()),
+        host: Some("kibana.example.com".to_string()),
+        upstream: Some("pipeline-kibana".to_string()),
+        request_id: "req-1".to_string(),
+        method: "GET".to_string(),
+        path: "/app".to_string(),
+        scheme: "https",
+        sso_subject: Some("jane".to_string()),
+        sso_tenant: None,
+    }
+}
+
+fn values_of(req: &RequestHeader, name: &str) -> Vec<String> {
+    req.headers
+        .get_all(name)
+        .iter()
+        .map(|v| v.to_str().expect("ascii header").to_string())
+        .collect()
+}
+
+#[test]
+fn templates_split_literals_and_variables() {
+    let t = HeaderTemplate::parse("user={sso_subject}; ip={client_ip} {{raw}}").expect("valid template");
+    assert_eq!(
+        t.0,
+        vec![
+            TemplatePart::Literal("user=".to_string()),
+            TemplatePart::Var(TemplateVar::SsoSubject),
+            TemplatePart::Literal("; ip=".to_string()),
+            TemplatePart::Var(TemplateVar::ClientIp),
+            TemplatePart::Literal(" {raw}".to_string()),
+        ]
+    );
+
+    for (bad, error) in [
+        ("{nope}", "unknown variable '{nope}'"),
+        ("{host", "unclosed '{'"),
+        ("host}", "unmatched '}'"),
+        ("a\nb", "invalid value"),
+    ] {
+        let e = HeaderTemplate::parse(bad).expect_err(bad).to_string();
+        assert!(e.contains(error), "{bad}: {e}");
+    }
+}
+
+#[test]
+fn compile_rejects_invalid_header_names() {
+    let e = HeaderActions::compile(&rules(&["bad name"], &[], &[])).expect_err("space in name");
+    assert!(e.to_string().contains("invalid header name 'bad name'"));
+
+    let e = HeaderActions::compile(&rules(&[], &[("X-User", "{user}")], &[])).expect_err("unknown variable");
+    assert!(e.to_string().contains("header 'X-User'"));
+}
+
+#[test]
+fn request_headers_are_removed_then_set_then_added() {
+    let actions = HeaderActions::compile(&rules(
+        &["X-Debug", "X-Via"],
+        &[("X-Remote-User", "{sso_subject}"), ("X-Tenant", "{sso_tenant}"), ("X-Via", "rproxy {scheme}://{host}{path}")],
+        &[("X-Trace", "{request_id}")],
+    ))
+    .expect("valid rules");
+    let mut req = RequestHeader::build("GET", b"/app", None).expect("valid request");
+    req.append_header("X-Debug", "1").expect("valid header");
+    req.append_header("X-Remote-User", "admin").expect("valid header");
+    req.append_header("X-Tenant", "forged").expect("valid header");
+    req.append_header("X-Trace", "upstream-1").expect("valid header");
+
+    actions.apply_to_request(&mut req, &values()).expect("rules applied");
+
+    assert!(values_of(&req, "X-Debug").is_empty());
+    assert_eq!(values_of(&req, "X-Remote-User"), ["jane"]);
+    assert!(values_of(&req, "X-Tenant").is_empty(), "set with an empty value removes the client's header");
+    assert_eq!(values_of(&req, "X-Via"), ["rproxy https://kibana.example.com/app"]);
+    assert_eq!(values_of(&req, "X-Trace"), ["upstream-1", "req-1"]);
+}
+
+#[test]
+fn response_headers_skip_values_that_render_invalid() {
+    let actions = HeaderActions::compile(&rules(
+        &["Server"],
+        &[("X-Frame-Options", "DENY"), ("X-User", "{sso_subject}")],
+        &[],
+    ))
+    .expect("valid rules");
+    let mut resp = ResponseHeader::build(200, None).expect("valid response");
+    resp.append_header("Server", "nginx").expect("valid header");
+    resp.append_header("X-User", "stale").expect("valid header");
+    let values = TemplateValues { sso_subject: Some("jane\r\nSet-Cookie: x=1".to_string()), ..values() };
+
+    actions.apply_to_response(&mut resp, &values).expect("rules applied");
+
+    assert!(resp.headers.get("Server").is_none());
+    assert_eq!(resp.headers.get("X-Frame-Options").map(|v| v.as_bytes()), Some(b"DENY".as_slice()));
+    assert!(resp.headers.get("X-User").is_none());
+}
@@ -0,0 +1,215 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::ActiveHealthCheckConfig;
+use crate::structs::{HealthChecker, HealthStatus, NetIqLoadBalancer, UpstreamTls};
+use crate::{log_info, log_warn};
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+use pingora::http::RequestHeader;
+use pingora::lb::Backend;
+use pingora_core::connectors::http::Connector;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// How often upstreams are looked at for a due check round.
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
+
+/// Upper bound of the response body searched for `expected_body`.
+const MAX_BODY: usize = 64 * 1024;
+
+#[async_trait]
+impl BackgroundService for HealthChecker {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        log_info!("Starting health check background service");
+        let mut schedule = tokio::time::interval(SCHEDULE_TICK);
+        let mut rounds: HashMap<String, (Instant, JoinHandle<()>)> = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = schedule.tick() => {
+                    self.schedule(&mut rounds);
+                }
+                _ = shutdown.changed() => {
+                    log_info!("Shutting down (health check background service)...");
+                    rounds.values().for_each(|(_, handle)| handle.abort());
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl HealthChecker {
+    pub fn new(lb: NetIqLoadBalancer) -> Self {
+        Self {
+            lb,
+            connector: Arc::new(Connector::new(None)),
+        }
+    }
+
+    /// Starts a check round for every upstream whose interval is over and whose
+    /// previous round has finished. Upstreams that lost their check are forgotten.
+    fn schedule(&self, rounds: &mut HashMap<String, (Instant, JoinHandle<()>)>) {
+        let routes = self.lb.live_config.routes.load();
+        let checks = routes.health_checks();
+        rounds.retain(|upstream, (_, handle)| {
+            let keep = checks.contains_key(upstream);
+            if !keep {
+                handle.abort();
+            }
+            keep
+        });
+        self.lb.health.retain(|upstream, _| checks.contains_key(upstream));
+
+        for (upstream, check) in checks {
+            if let Some((started, handle)) = rounds.get(upstream)
+                && (!handle.is_finished() || started.elapsed() < Duration::from_secs(check.interval_secs))
+            {
+                continue;
+            }
+            let checker = self.clone();
+            let name = upstream.clone();
+            let check = check.clone();
+            let handle = tokio::spawn(async move { checker.run_round(&name, &check).await });
+            rounds.insert(upstream.clone(), (Instant::now(), handle));
+        }
+    }
+
+    /// Checks all backends of the upstream at once and applies the results.
+    pub async fn run_round(&self, upstream: &str, check: &ActiveHealthCheckConfig) {
+        let backends = match self.lb.balancers.get(upstream) {
+            Some(balancer) => balancer.backends(),
+            None => {
+                self.lb.health.remove(upstream);
+                return;
+            }
+        };
+
+        let mut probes = JoinSet::new();
+        for backend in backends.iter().cloned() {
+            let checker = self.clone();
+            let upstream = upstream.to_string();
+            let check = check.clone();
+            probes.spawn(async move {
+                let result = checker.probe(&upstream, &check, &backend).await;
+                (backend.addr.to_string(), result)
+            });
+        }
+        let results = probes.join_all().await;
+
+        let mut statuses = self.lb.health.get(upstream).map(|s| s.clone()).unwrap_or_default();
+        statuses.retain(|addr, _| results.iter().any(|(checked, _)| checked == addr));
+        for (addr, result) in results {
+            let status = statuses.entry(addr.clone()).or_default();
+            if status.observe(result, check.rise, check.fall) {
+                match &status.last_error {
+                    None => log_info!("Backend {} of {} is healthy again", addr, upstream),
+                    Some(e) => log_warn!("Backend {} of {} is unhealthy: {}", addr, upstream, e),
+                }
+            }
+        }
+        self.lb.health.insert(upstream.to_string(), statuses);
+    }
+
+    async fn probe(&self, upstream: &str, check: &ActiveHealthCheckConfig, backend: &Backend) -> Result<()> {
+        let timeout = Duration::from_millis(check.timeout_ms);
+        let probe = async {
+            if check.path.is_empty() {
+                self.probe_tcp(backend).await
+            } else {
+                self.probe_http(upstream, check, backend).await
+            }
+        };
+        tokio::time::timeout(timeout, probe)
+            .await
+            .map_err(|_| anyhow!("timed out after {:?}", timeout))?
+    }
+
+    async fn probe_tcp(&self, backend: &Backend) -> Result<()> {
+        let addr = backend.addr.to_string();
+        TcpStream::connect(&addr).await.map_err(|e| anyhow!("connect to {} failed: {}", addr, e))?;
+        Ok(())
+    }
+
+    /// GETs `path` over the same TLS settings requests to the upstream use.
+    async fn probe_http(&self, upstream: &str, check: &ActiveHealthCheckConfig, backend: &Backend) -> Result<()> {
+        let tls = self.lb.live_config.routes.load().upstream_tls(upstream);
+        let host = probe_host(upstream, check, tls.as_deref());
+        let peer = self
+            .lb
+            .build_peer(backend.clone(), host, tls.as_deref())
+            .map_err(|e| anyhow!("{}", e))?;
+
+        let (mut session, _) = self.connector.get_http_session(&peer).await.map_err(|e| anyhow!("{}", e))?;
+        let mut req = RequestHeader::build("GET", check.path.as_bytes(), None).map_err(|e| anyhow!("{}", e))?;
+        req.insert_header("Host", host).map_err(|e| anyhow!("{}", e))?;
+        req.insert_header("User-Agent", "rproxy-health-check").map_err(|e| anyhow!("{}", e))?;
+        session.write_request_header(Box::new(req)).await.map_err(|e| anyhow!("{}", e))?;
+        session.finish_request_body().await.map_err(|e| anyhow!("{}", e))?;
+        session.read_response_header().await.map_err(|e| anyhow!("{}", e))?;
+
+        let status = session.response_header().map(|h| h.status.as_u16()).unwrap_or_default();
+        if status != check.expected_status {
+            bail!("status {} instead of {}", status, check.expected_status);
+        }
+        if check.expected_body.is_empty() {
+            return Ok(());
+        }
+        let mut body = Vec::new();
+        while body.len() < MAX_BODY
+            && let Some(chunk) = session.read_response_body().await.map_err(|e| anyhow!("{}", e))?
+        {
+            body.extend_from_slice(&chunk);
+        }
+        if !String::from_utf8_lossy(&body).contains(&check.expected_body) {
+            bail!("body does not contain '{}'", check.expected_body);
+        }
+        Ok(())
+    }
+}
+
+/// Host header and SNI of the HTTP check: its own `host`, else the SNI the
+/// upstream's TLS settings send, else the upstream name.
+fn probe_host<'a>(upstream: &'a str, check: &'a ActiveHealthCheckConfig, tls: Option<&'a UpstreamTls>) -> &'a str {
+    if !check.host.is_empty() {
+        return &check.host;
+    }
+    tls.and_then(|tls| tls.sni.as_deref()).unwrap_or(upstream)
+}
+
+/// Backends are healthy until proven otherwise, so a restart or a new node
+/// does not take traffic away before the first checks ran.
+impl Default for HealthStatus {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            streak: 0,
+            last_error: None,
+        }
+    }
+}
+
+impl HealthStatus {
+    /// Counts a check result and flips the status after `rise` successes or
+    /// `fall` failures in a row; returns whether it flipped.
+    pub fn observe(&mut self, result: Result<()>, rise: u32, fall: u32) -> bool {
+        let ok = result.is_ok();
+        self.last_error = result.err().map(|e| e.to_string());
+        if ok == self.healthy {
+            self.streak = 0;
+            return false;
+        }
+        self.streak += 1;
+        if self.streak < if ok { rise } else { fall } {
+            return false;
+        }
+        self.healthy = ok;
+        self.streak = 0;
+        true
+    }
+}
@@ -0,0 +1,142 @@
+use super::*;
+use crate::config::{LoadBalancing, RPConfig, UpstreamTlsVerify};
+use crate::structs::{Balancer, ClientCert};
+use anyhow::anyhow;
+use axum::Router;
+use axum::http::StatusCode;
+use axum::routing::get;
+use std::collections::BTreeSet;
+use tokio::net::TcpListener;
+
+fn check(value: serde_json::Value) -> ActiveHealthCheckConfig {
+    serde_json::from_value(value).expect("valid health check")
+}
+
+async fn checker_for(upstream: &str, addrs: &[String]) -> HealthChecker {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let backends: BTreeSet<Backend> = addrs
+        .iter()
+        .map(|a| Backend::new(a).expect("valid backend address"))
+        .collect();
+    let balancer = Balancer::build(backends, &LoadBalancing::RoundRobin).await.expect("static discovery");
+    lb.balancers.insert(upstream.to_string(), balancer);
+    HealthChecker::new(lb)
+}
+
+fn status(checker: &HealthChecker, upstream: &str, addr: &str) -> HealthStatus {
+    checker
+        .lb
+        .health
+        .get(upstream)
+        .and_then(|s| s.get(addr).cloned())
+        .expect("backend was checked")
+}
+
+/// Serves `/health` answering "status: ok" and `/down` answering 500.
+async fn http_backend() -> String {
+    let app = Router::new()
+        .route("/health", get(|| async { "status: ok" }))
+        .route("/down", get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "down") }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind backend");
+    let addr = listener.local_addr().expect("local addr");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr.to_string()
+}
+
+async fn closed_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    listener.local_addr().expect("local addr").to_string()
+}
+
+#[test]
+fn status_flips_after_rise_and_fall_in_a_row() {
+    let mut status = HealthStatus::default();
+    let fail = || Err(anyhow!("refused"));
+
+    assert!(!status.observe(fail(), 2, 3));
+    assert!(!status.observe(fail(), 2, 3));
+    assert!(!status.observe(Ok(()), 2, 3));
+    assert!(!status.observe(fail(), 2, 3));
+    assert!(!status.observe(fail(), 2, 3));
+    assert!(status.healthy);
+    assert!(status.observe(fail(), 2, 3));
+    assert!(!status.healthy);
+    assert_eq!(status.last_error.as_deref(), Some("refused"));
+
+    assert!(!status.observe(Ok(()), 2, 3));
+    assert!(!status.healthy);
+    assert!(status.observe(Ok(()), 2, 3));
+    assert!(status.healthy);
+    assert_eq!(status.last_error, None);
+}
+
+#[tokio::test]
+async fn tcp_check_marks_unreachable_backend_unhealthy() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let up = listener.local_addr().expect("local addr").to_string();
+    let down = closed_port().await;
+    let checker = checker_for("svc", &[up.clone(), down.clone()]).await;
+    let check = check(serde_json::json!({"fall": 2}));
+
+    checker.run_round("svc", &check).await;
+    assert!(status(&checker, "svc", &down).healthy);
+
+    checker.run_round("svc", &check).await;
+    assert!(status(&checker, "svc", &up).healthy);
+    assert!(!status(&checker, "svc", &down).healthy);
+}
+
+#[tokio::test]
+async fn http_check_validates_status_and_body() {
+    let addr = http_backend().await;
+    let probe = |value: serde_json::Value| {
+        let addr = addr.clone();
+        async move {
+            let checker = checker_for("svc", std::slice::from_ref(&addr)).await;
+            checker.run_round("svc", &check(value)).await;
+            status(&checker, "svc", &addr).last_error
+        }
+    };
+
+    assert_eq!(probe(serde_json::json!({"path": "/health", "expected_body": "ok"})).await, None);
+    assert_eq!(probe(serde_json::json!({"path": "/down", "expected_status": 500})).await, None);
+    assert_eq!(
+        probe(serde_json::json!({"path": "/down"})).await.as_deref(),
+        Some("status 500 instead of 200")
+    );
+    assert_eq!(
+        probe(serde_json::json!({"path": "/health", "expected_body": "healthy"})).await.as_deref(),
+        Some("body does not contain 'healthy'")
+    );
+}
+
+#[test]
+fn http_check_host_defaults_to_the_upstream_sni() {
+    let tls = |sni: Option<&str>| UpstreamTls {
+        sni: sni.map(str::to_string),
+        verify: UpstreamTlsVerify::Full,
+        ca: None,
+        client_cert: ClientCert::None,
+    };
+    let with_host = check(serde_json::json!({"path": "/health", "host": "health.internal"}));
+    let without_host = check(serde_json::json!({"path": "/health"}));
+
+    assert_eq!(probe_host("svc", &with_host, Some(&tls(Some("api.internal")))), "health.internal");
+    assert_eq!(probe_host("svc", &without_host, Some(&tls(Some("api.internal")))), "api.internal");
+    assert_eq!(probe_host("svc", &without_host, Some(&tls(None))), "svc");
+    assert_eq!(probe_host("svc", &without_host, None), "svc");
+}
+
+#[tokio::test]
+async fn removed_backends_are_forgotten() {
+    let down = closed_port().await;
+    let checker = checker_for("svc", std::slice::from_ref(&down)).await;
+    let check = check(serde_json::json!({"fall": 1}));
+    checker.run_round("svc", &check).await;
+    assert!(!status(&checker, "svc", &down).healthy);
+
+    checker.lb.balancers.remove("svc");
+    checker.run_round("svc", &check).await;
+
+    assert!(!checker.lb.health.contains_key("svc"));
+}
@@ -0,0 +1,94 @@
+#[cfg(test)]
+mod tests;
+
+use crate::{log_info, log_warn};
+use crate::structs::Jwks;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Keys are fetched again after this long even when every `kid` is found, so
+/// keys the IdP retired stop being accepted.
+const JWKS_MAX_AGE: Duration = Duration::from_secs(3600);
+/// Unknown `kid`s fetch the keys at most this often.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+const JWKS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shorter than `JWKS_MIN_REFRESH`, so fetches never overlap.
+const JWKS_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Jwks {
+    pub fn try_new() -> anyhow::Result<Self> {
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(JWKS_CONNECT_TIMEOUT)
+            .timeout(JWKS_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            http_client,
+            keys: ArcSwap::from_pointee(JwkSet { keys: vec![] }),
+            fetched: ArcSwapOption::empty(),
+            attempted: Mutex::new(None),
+        })
+    }
+
+    /// The key named `kid`, the only key when the token names none. The keys are
+    /// fetched from `url` when they are stale or don't have it.
+    pub async fn key(&self, url: &str, kid: Option<&str>) -> anyhow::Result<Option<Jwk>> {
+        let fresh = self.fetched.load().as_deref().is_some_and(|at| at.elapsed() < JWKS_MAX_AGE);
+        if let Some(key) = self.cached(kid)
+            && fresh
+        {
+            return Ok(Some(key));
+        }
+        if !self.start_fetch() {
+            return Ok(self.cached(kid));
+        }
+        let keys = match self.fetch(url).await {
+            Ok(keys) => keys,
+            Err(e) => match self.cached(kid) {
+                Some(key) => {
+                    log_warn!("Unable to refresh keys from {}, keeping the cached ones: {}", url, e);
+                    return Ok(Some(key));
+                }
+                None => return Err(e),
+            },
+        };
+        log_info!("Fetched {} keys from {}", keys.keys.len(), url);
+        let _attempted = self.attempted.lock().unwrap_or_else(PoisonError::into_inner);
+        self.keys.store(keys.into());
+        self.fetched.store(Some(Arc::new(Instant::now())));
+        Ok(self.cached(kid))
+    }
+
+    /// Whether this caller fetches the keys; the others use the cached ones meanwhile.
+    fn start_fetch(&self) -> bool {
+        let mut attempted = self.attempted.lock().unwrap_or_else(PoisonError::into_inner);
+        if attempted.is_some_and(|at| at.elapsed() < JWKS_MIN_REFRESH) {
+            return false;
+        }
+        *attempted = Some(Instant::now());
+        true
+    }
+
+    fn cached(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.load();
+        match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    /// Makes the next `key` fetch the keys, for when the IdP moved them.
+    pub fn invalidate(&self) {
+        let mut attempted = self.attempted.lock().unwrap_or_else(PoisonError::into_inner);
+        self.fetched.store(None);
+        *attempted = None;
+    }
+
+    async fn fetch(&self, url: &str) -> anyhow::Result<JwkSet> {
+        let resp = self.http_client.get(url).send().await?.error_for_status()?;
+        let body = resp.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
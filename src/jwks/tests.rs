@@ -0,0 +1,163 @@
+use super::*;
+use axum::Router;
+use axum::extract::State;
+use axum::routing::get;
+use axum::Json;
+use jsonwebtoken::{Algorithm, EncodingKey};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Stand-in for the IdP's JWKS endpoint, serving whatever keys the test puts in.
+#[derive(Clone)]
+struct MockIdp {
+    keys: Arc<Mutex<JwkSet>>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl MockIdp {
+    fn new(kids: &[&str]) -> Self {
+        let idp = Self {
+            keys: Arc::new(Mutex::new(JwkSet { keys: vec![] })),
+            requests: Arc::new(AtomicUsize::new(0)),
+        };
+        idp.rotate(kids);
+        idp
+    }
+
+    fn rotate(&self, kids: &[&str]) {
+        let keys = kids.iter().map(|kid| jwk(kid)).collect();
+        *self.keys.lock().expect("mock keys lock") = JwkSet { keys };
+    }
+
+    fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    async fn serve(self) -> String {
+        let router = Router::new().route("/keys", get(keys)).with_state(self);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock idp");
+        let addr = listener.local_addr().expect("mock idp addr");
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}/keys")
+    }
+}
+
+async fn keys(State(idp): State<MockIdp>) -> Json<JwkSet> {
+    idp.requests.fetch_add(1, Ordering::SeqCst);
+    Json(idp.keys.lock().expect("mock keys lock").clone())
+}
+
+fn jwk(kid: &str) -> Jwk {
+    let mut jwk = Jwk::from_encoding_key(&EncodingKey::from_secret(kid.as_bytes()), Algorithm::HS256)
+        .expect("jwk from secret");
+    jwk.common.key_id = Some(kid.to_string());
+    jwk
+}
+
+fn kid(key: Option<Jwk>) -> Option<String> {
+    key.and_then(|k| k.common.key_id)
+}
+
+/// Pretends the keys were fetched `ago`.
+fn fetched_ago(jwks: &Jwks, ago: Duration) {
+    let at = Instant::now().checked_sub(ago);
+    jwks.fetched.store(at.map(Arc::new));
+    *jwks.attempted.lock().expect("attempted lock") = at;
+}
+
+/// Accepts connections and never answers, like a JWKS endpoint that hangs.
+async fn hung_idp() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind hung idp");
+    let addr = listener.local_addr().expect("hung idp addr");
+    tokio::spawn(async move {
+        let mut open = vec![];
+        while let Ok((conn, _)) = listener.accept().await {
+            open.push(conn);
+        }
+    });
+    format!("http://{addr}/keys")
+}
+
+#[tokio::test]
+async fn keys_are_fetched_once_and_served_from_the_cache() {
+    let idp = MockIdp::new(&["a", "b"]);
+    let url = idp.clone().serve().await;
+    let jwks = Jwks::try_new().expect("jwks client should build");
+
+    assert_eq!(kid(jwks.key(&url, Some("a")).await.expect("keys fetched")).as_deref(), Some("a"));
+    assert_eq!(kid(jwks.key(&url, Some("b")).await.expect("keys cached")).as_deref(), Some("b"));
+    assert_eq!(jwks.key(&url, None).await.expect("keys cached"), None, "no kid with several keys");
+    assert_eq!(idp.requests(), 1);
+}
+
+#[tokio::test]
+async fn unknown_kid_fetches_the_rotated_keys_at_most_every_min_refresh() {
+    let idp = MockIdp::new(&["old"]);
+    let url = idp.clone().serve().await;
+    let jwks = Jwks::try_new().expect("jwks client should build");
+    jwks.key(&url, Some("old")).await.expect("keys fetched");
+
+    idp.rotate(&["old", "new"]);
+    assert_eq!(jwks.key(&url, Some("new")).await.expect("too soon to refetch"), None);
+    assert_eq!(idp.requests(), 1);
+
+    fetched_ago(&jwks, JWKS_MIN_REFRESH);
+    assert_eq!(kid(jwks.key(&url, Some("new")).await.expect("keys refetched")).as_deref(), Some("new"));
+    assert_eq!(idp.requests(), 2);
+}
+
+#[tokio::test]
+async fn stale_keys_are_refetched_and_retired_keys_dropped() {
+    let idp = MockIdp::new(&["old"]);
+    let url = idp.clone().serve().await;
+    let jwks = Jwks::try_new().expect("jwks client should build");
+    assert_eq!(kid(jwks.key(&url, None).await.expect("keys fetched")).as_deref(), Some("old"), "the only key");
+
+    idp.rotate(&["new"]);
+    fetched_ago(&jwks, JWKS_MAX_AGE);
+    assert_eq!(jwks.key(&url, Some("old")).await.expect("keys refetched"), None);
+    assert_eq!(idp.requests(), 2);
+}
+
+#[tokio::test]
+async fn unreachable_jwks_is_an_error_unless_the_key_is_cached() {
+    let url = "http://127.0.0.1:9/keys".to_string();
+    let jwks = Jwks::try_new().expect("jwks client should build");
+    assert!(jwks.key(&url, Some("a")).await.is_err());
+
+    jwks.keys.store(Arc::new(JwkSet { keys: vec![jwk("a")] }));
+    fetched_ago(&jwks, JWKS_MAX_AGE);
+    assert_eq!(kid(jwks.key(&url, Some("a")).await.expect("cached key kept")).as_deref(), Some("a"));
+}
+
+#[tokio::test]
+async fn hung_jwks_times_out_without_holding_up_cached_keys() {
+    let url = hung_idp().await;
+    let jwks = Jwks {
+        http_client: reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .build()
+            .expect("client should build"),
+        ..Jwks::try_new().expect("jwks client should build")
+    };
+    jwks.keys.store(Arc::new(JwkSet { keys: vec![jwk("a")] }));
+    jwks.fetched.store(Some(Arc::new(Instant::now())));
+
+    let (miss, (cached, in_flight, took)) = tokio::join!(jwks.key(&url, Some("b")), async {
+        // let the miss start its fetch first
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = Instant::now();
+        let cached = jwks.key(&url, Some("a")).await;
+        let in_flight = jwks.key(&url, Some("c")).await;
+        (cached, in_flight, started.elapsed())
+    });
+
+    assert!(miss.is_err(), "the hung fetch times out");
+    assert_eq!(kid(cached.expect("cached key")).as_deref(), Some("a"));
+    assert_eq!(in_flight.expect("fetch in flight"), None);
+    assert!(took < Duration::from_millis(300), "waited {took:?} on the hung fetch");
+}
@@ -2,7 +2,7 @@
 mod tests;
 
 use crate::config::RPConfig;
-use crate::structs::{LeaderRoutine, RuntimeState};
+use crate::structs::{LeaderRoutine, Metrics, RuntimeState};
 use crate::utils::{get_consul_nodes, get_res_record_sets, update_res_record_sets};
 use crate::{log_info, log_warn};
 use async_trait::async_trait;
@@ -42,12 +42,13 @@ impl BackgroundService for LeaderRoutine {
 }
 
 impl LeaderRoutine {
-    pub fn new(rp_config: RPConfig, runtime_state: RuntimeState) -> Self {
+    pub fn new(rp_config: RPConfig, runtime_state: RuntimeState, metrics: Metrics) -> Self {
         Self {
             rp_config,
             session_id: Arc::new(Mutex::new(String::new())),
             http_client: reqwest::Client::new(),
             runtime_state,
+            metrics,
         }
     }
 
@@ -65,10 +66,12 @@ impl LeaderRoutine {
                 .unwrap();
             log_info!("Session id :{} + Leader : {}...", session_id, leader);
             //todo set leader to rp_config
+            self.runtime_state.is_leader.store(leader, Ordering::Relaxed);
+            self.metrics.leader.set(i64::from(leader));
             if leader {
-                self.runtime_state.is_leader.store(leader, Ordering::Relaxed);
                 if let Ok(rproxies) =
                     get_consul_nodes(self.rp_config.consul_url.as_str(), "rproxy", "passing", false, "" , "", 1,1).await
+                    && !rproxies.is_empty()
                 {
                     let rproxy_ips: Vec<ResourceRecord> = rproxies
                         .iter()
@@ -101,13 +104,14 @@ impl LeaderRoutine {
                                 existing_rr,
                                 rproxy_ips
                             );
-                            let _ = update_res_record_sets(
+                            let updated = update_res_record_sets(
                                 client.as_ref(),
                                 self.rp_config.r53_zone_id.clone(),
                                 fqdn.to_string(),
                                 rproxy_ips.clone(),
                             )
                             .await;
+                            self.metrics.record_r53_action("reconcile", updated.is_ok());
                         }
                     }
                 }
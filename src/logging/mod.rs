@@ -3,11 +3,41 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, fmt};
 use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::FormattedFields;
 use tracing_subscriber::fmt::format::Writer;
 use tracing_subscriber::registry::LookupSpan;
+#[cfg(not(debug_assertions))]
+use tracing_subscriber::Layer;
+#[cfg(not(debug_assertions))]
+use tracing_subscriber::filter::{Targets, filter_fn};
+
+/// Target of the access log events, see `log_access!`.
+#[cfg_attr(debug_assertions, allow(dead_code))]
+pub const ACCESS_TARGET: &str = "access";
 
 struct NiceFormat;
 
+/// The bare message, access log lines come formatted already.
+#[cfg(not(debug_assertions))]
+struct AccessFormat;
+
+#[cfg(not(debug_assertions))]
+impl<S, N> FormatEvent<S, N> for AccessFormat
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut w: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        ctx.field_format().format_fields(w.by_ref(), event)?;
+        writeln!(w)
+    }
+}
+
 impl<S, N> FormatEvent<S, N> for NiceFormat
 where
     S: tracing::Subscriber + for<'a> LookupSpan<'a>,
@@ -46,6 +76,11 @@ where
                 }
                 first = false;
                 write!(w, "{}", span.name())?;
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>()
+                    && !fields.is_empty()
+                {
+                    write!(w, "{{{}}}", fields)?;
+                }
             }
             write!(w, "⟧ ")?;
         }
@@ -85,7 +120,16 @@ macro_rules! log_trace {
     };
 }
 
-pub fn init_tracing(conf: RPConfig) -> Option<tracing_appender::non_blocking::WorkerGuard> {
+/// A line of the access log, the target has to match `ACCESS_TARGET`.
+#[macro_export]
+macro_rules! log_access {
+    ($($arg:tt)*) => {
+        tracing::info!(target: "access", "{}", format_args!($($arg)*))
+    };
+}
+
+/// In release builds the access log goes to its own daily file, `access.<date>`.
+pub fn init_tracing(conf: RPConfig) -> Vec<tracing_appender::non_blocking::WorkerGuard> {
     let filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(conf.log_level));
 
@@ -100,25 +144,34 @@ pub fn init_tracing(conf: RPConfig) -> Option<tracing_appender::non_blocking::Wo
             .with(stdout_layer)
             .init();
 
-        None
+        vec![]
     }
 
     // Prod
     #[cfg(not(debug_assertions))]
     {
-        let file_appender = tracing_appender::rolling::daily(conf.log_path, "rproxy");
+        let file_appender = tracing_appender::rolling::daily(&conf.log_path, "rproxy");
         let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+        let access_appender = tracing_appender::rolling::daily(&conf.log_path, ACCESS_TARGET);
+        let (access_non_blocking, access_guard) = tracing_appender::non_blocking(access_appender);
 
         let file_layer = fmt::layer()
             .with_ansi(false)
             .with_target(true)
-            .with_writer(non_blocking);
+            .with_writer(non_blocking)
+            .with_filter(filter)
+            .with_filter(filter_fn(|meta| meta.target() != ACCESS_TARGET));
+        let access_layer = fmt::layer()
+            .with_ansi(false)
+            .event_format(AccessFormat)
+            .with_writer(access_non_blocking)
+            .with_filter(Targets::new().with_target(ACCESS_TARGET, tracing::Level::INFO));
 
         tracing_subscriber::registry()
-            .with(filter)
             .with(file_layer)
+            .with(access_layer)
             .init();
 
-        Some(guard) // keep this alive for the lifetime of the program!
+        vec![guard, access_guard] // keep these alive for the lifetime of the program!
     }
 }
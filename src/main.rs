@@ -1,10 +1,23 @@
+mod access_log;
+mod affinity;
+mod balancer;
 mod config;
 mod consul;
+mod forwarding;
+mod headers;
+mod health;
+mod jwks;
 mod leader;
 mod logging;
+mod metrics;
 mod oauth2;
+mod oidc;
+mod outlier;
 mod proxy;
+mod reload;
+mod retry;
 mod route53;
+mod routing;
 mod structs;
 mod utils;
 mod vault;
@@ -12,7 +25,8 @@ mod web;
 
 use crate::config::parse;
 use crate::logging::init_tracing;
-use crate::structs::{LeaderRoutine, NetIqLoadBalancer, R53, Vault, Web, RuntimeState};
+use crate::routing::RouteTable;
+use crate::structs::{ConfigReloader, HealthChecker, LeaderRoutine, LiveConfig, Metrics, NetIqLoadBalancer, R53, Vault, Web, RuntimeState};
 use pingora::prelude::*;
 use std::path::PathBuf;
 
@@ -21,27 +35,46 @@ static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 fn main() {
     let args = parse();
-    let conf = match config::load(PathBuf::from(args.config_path)) {
+    let config_path = PathBuf::from(args.config_path);
+    let conf = match config::load(config_path.clone()) {
         Ok(c) => c,
         Err(e) => {
             panic!("Unable to load config : {}", e)
         }
     };
+    let routes = match RouteTable::compile(&conf) {
+        Ok(r) => r,
+        Err(e) => panic!("Invalid routing config : {}", e),
+    };
     let runtime_state = match RuntimeState::try_new(&conf) {
         Ok(x) => x,
         Err(e) => panic!("Unable to construct runtime state : {}", e),
     };
     
-    let _guard = init_tracing(conf.clone());
+    let _guards = init_tracing(conf.clone());
     log_info!("server starting");
     
-    let lb = NetIqLoadBalancer::new(conf.clone());
-    let r53 = R53::new(conf.clone() , runtime_state.clone());
-    let vault = Vault::new(conf.clone());
-    let leader = LeaderRoutine::new(conf.clone(), runtime_state.clone());
-    let web = Web::new(conf.clone(), lb.nodes.clone(), runtime_state.clone());
+    let metrics = match Metrics::try_new() {
+        Ok(m) => m,
+        Err(e) => panic!("Unable to register metrics : {}", e),
+    };
+    let live_config = LiveConfig::new(config_path, conf.clone(), routes);
+    let lb = match NetIqLoadBalancer::try_new(conf.clone(), live_config.clone(), metrics.clone()) {
+        Ok(lb) => lb,
+        Err(e) => panic!("Unable to construct load balancer : {}", e),
+    };
+    let r53 = R53::new(conf.clone() , runtime_state.clone(), metrics.clone());
+    let vault = match Vault::try_new(conf.clone(), live_config.clone(), lb.client_certs.clone(), metrics.clone()) {
+        Ok(v) => v,
+        Err(e) => panic!("Invalid TLS certificate config : {}", e),
+    };
+    let leader = LeaderRoutine::new(conf.clone(), runtime_state.clone(), metrics.clone());
+    let web = Web::new(conf.clone(), &lb, runtime_state.clone());
+    let reloader = ConfigReloader::new(live_config);
+    let health_checker = HealthChecker::new(lb.clone());
 
     r53.non_async_r53_register();
+    lb.auth_verifier.non_async_discover_oidc();
 
     let mut my_server = Server::new(Some(Opt::parse_args())).unwrap();
     my_server.bootstrap();
@@ -50,25 +83,35 @@ fn main() {
     let r53_bg = background_service("r53-background", r53);
     let leader_bg = background_service("leader-background", leader);
     let web_bg = background_service("web-background", web);
+    let reloader_bg = background_service("reloader-background", reloader);
+    let health_bg = background_service("health-background", health_checker);
+    let oidc_bg = background_service("oidc-background", lb.auth_verifier.clone());
 
     let mut lb = http_proxy_service(&my_server.configuration, lb);
-    //lb.add_tcp(&format!("0.0.0.0:{}", conf.port));
+    if conf.http_port > 0 {
+        lb.add_tcp(&format!("0.0.0.0:{}", conf.http_port));
+    }
 
     if conf.tls_enabled {
         vault.non_async_fetch_ssl_certs();
-        let cert_path = conf.tls_chain_cert.clone();
-        let key_path = conf.tls_private_cert.clone();
-        let mut tls_settings =
-            pingora_core::listeners::tls::TlsSettings::intermediate(&cert_path, &key_path).unwrap();
+        let mut tls_settings = match pingora_core::listeners::tls::TlsSettings::with_callbacks(Box::new(vault.cert_resolver())) {
+            Ok(s) => s,
+            Err(e) => panic!("Unable to set up TLS listener : {}", e),
+        };
         if conf.tls_enable_h2{
             tls_settings.enable_h2();
         }
         lb.add_tls_with_settings(&format!("0.0.0.0:{}", conf.tls_port), None, tls_settings);
     }
+    let vault_bg = background_service("vault-background", vault);
     my_server.add_service(consul_bg);
     my_server.add_service(r53_bg);
     my_server.add_service(leader_bg);
     my_server.add_service(web_bg);
+    my_server.add_service(reloader_bg);
+    my_server.add_service(health_bg);
+    my_server.add_service(oidc_bg);
+    my_server.add_service(vault_bg);
     my_server.add_service(lb);
     log_info!("Server ready");
     my_server.run_forever();
@@ -0,0 +1,129 @@
+#[cfg(test)]
+mod tests;
+
+use crate::structs::Metrics;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::X509Ref;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Blocking queries take up to `consul_pool_secs`, the default buckets stop at 10s.
+const CONSUL_POLL_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+impl Metrics {
+    pub fn try_new() -> anyhow::Result<Self> {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("rproxy_requests_total", "Requests handled, by route host, upstream and status class"),
+            &["host", "upstream", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("rproxy_request_duration_seconds", "Time from request to the end of the response"),
+            &["host", "upstream"],
+        )?;
+        let connect_errors = IntCounterVec::new(
+            Opts::new("rproxy_upstream_connect_errors_total", "Failed connections to backends"),
+            &["upstream"],
+        )?;
+        let active_connections = IntGaugeVec::new(
+            Opts::new("rproxy_upstream_active_connections", "Requests being proxied to the backends"),
+            &["upstream"],
+        )?;
+        let consul_poll_duration = HistogramVec::new(
+            HistogramOpts::new("rproxy_consul_poll_duration_seconds", "Duration of the Consul blocking queries")
+                .buckets(CONSUL_POLL_BUCKETS.to_vec()),
+            &["upstream"],
+        )?;
+        let consul_poll_failures = IntCounterVec::new(
+            Opts::new("rproxy_consul_poll_failures_total", "Failed Consul blocking queries"),
+            &["upstream"],
+        )?;
+        let leader = IntGauge::new("rproxy_leader", "1 when this instance holds the leader lock")?;
+        let r53_actions = IntCounterVec::new(
+            Opts::new("rproxy_r53_actions_total", "Route53 record changes, by action and result"),
+            &["action", "result"],
+        )?;
+        let cert_expiry = GaugeVec::new(
+            Opts::new("rproxy_certificate_expiry_timestamp_seconds", "Unix time the certificate expires at"),
+            &["kind", "name"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(connect_errors.clone()))?;
+        registry.register(Box::new(active_connections.clone()))?;
+        registry.register(Box::new(consul_poll_duration.clone()))?;
+        registry.register(Box::new(consul_poll_failures.clone()))?;
+        registry.register(Box::new(leader.clone()))?;
+        registry.register(Box::new(r53_actions.clone()))?;
+        registry.register(Box::new(cert_expiry.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            connect_errors,
+            active_connections,
+            consul_poll_duration,
+            consul_poll_failures,
+            leader,
+            r53_actions,
+            cert_expiry,
+        })
+    }
+
+    /// Prometheus text exposition of every metric.
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// `status` is the one sent downstream, none when the request failed before a response.
+    pub fn record_request(&self, host: &str, upstream: &str, status: Option<u16>, elapsed: Duration) {
+        self.requests
+            .with_label_values(&[host, upstream, status_class(status)])
+            .inc();
+        self.request_duration
+            .with_label_values(&[host, upstream])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_consul_poll(&self, upstream: &str, elapsed: Duration, ok: bool) {
+        self.consul_poll_duration
+            .with_label_values(&[upstream])
+            .observe(elapsed.as_secs_f64());
+        if !ok {
+            self.consul_poll_failures.with_label_values(&[upstream]).inc();
+        }
+    }
+
+    pub fn record_r53_action(&self, action: &str, ok: bool) {
+        let result = if ok { "ok" } else { "error" };
+        self.r53_actions.with_label_values(&[action, result]).inc();
+    }
+
+    pub fn record_cert_expiry(&self, kind: &str, name: &str, cert: &X509Ref) {
+        if let Ok(expiry) = unix_time(cert.not_after()) {
+            self.cert_expiry.with_label_values(&[kind, name]).set(expiry as f64);
+        }
+    }
+}
+
+fn status_class(status: Option<u16>) -> &'static str {
+    match status {
+        Some(100..=199) => "1xx",
+        Some(200..=299) => "2xx",
+        Some(300..=399) => "3xx",
+        Some(400..=499) => "4xx",
+        Some(500..=599) => "5xx",
+        _ => "none",
+    }
+}
+
+fn unix_time(time: &Asn1TimeRef) -> anyhow::Result<i64> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    Ok(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+}
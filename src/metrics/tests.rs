@@ -0,0 +1,53 @@
+use super::*;
+
+fn metrics() -> Metrics {
+    Metrics::try_new().expect("metrics should register")
+}
+
+#[test]
+fn requests_are_counted_by_status_class() {
+    let metrics = metrics();
+
+    metrics.record_request("kibana.example.com", "pipeline-kibana", Some(200), Duration::from_millis(20));
+    metrics.record_request("kibana.example.com", "pipeline-kibana", Some(204), Duration::from_millis(30));
+    metrics.record_request("kibana.example.com", "pipeline-kibana", Some(503), Duration::from_secs(2));
+    metrics.record_request("", "", None, Duration::ZERO);
+
+    let count = |host: &str, upstream: &str, status: &str| {
+        metrics.requests.with_label_values(&[host, upstream, status]).get()
+    };
+    assert_eq!(count("kibana.example.com", "pipeline-kibana", "2xx"), 2);
+    assert_eq!(count("kibana.example.com", "pipeline-kibana", "5xx"), 1);
+    assert_eq!(count("", "", "none"), 1);
+    let duration = metrics
+        .request_duration
+        .with_label_values(&["kibana.example.com", "pipeline-kibana"]);
+    assert_eq!(duration.get_sample_count(), 3);
+    assert!((duration.get_sample_sum() - 2.05).abs() < 1e-9);
+}
+
+#[test]
+fn status_classes() {
+    assert_eq!(status_class(Some(101)), "1xx");
+    assert_eq!(status_class(Some(302)), "3xx");
+    assert_eq!(status_class(Some(404)), "4xx");
+    assert_eq!(status_class(Some(999)), "none");
+    assert_eq!(status_class(None), "none");
+}
+
+#[test]
+fn render_uses_the_prometheus_text_format() {
+    let metrics = metrics();
+    metrics.record_consul_poll("svc", Duration::from_millis(200), true);
+    metrics.record_consul_poll("svc", Duration::from_millis(5), false);
+    metrics.record_r53_action("reconcile", true);
+    metrics.leader.set(1);
+
+    let rendered = metrics.render().expect("metrics render");
+
+    assert!(rendered.contains("# TYPE rproxy_consul_poll_duration_seconds histogram"));
+    assert!(rendered.contains("rproxy_consul_poll_duration_seconds_count{upstream=\"svc\"} 2"));
+    assert!(rendered.contains("rproxy_consul_poll_failures_total{upstream=\"svc\"} 1"));
+    assert!(rendered.contains("rproxy_r53_actions_total{action=\"reconcile\",result=\"ok\"} 1"));
+    assert!(rendered.contains("rproxy_leader 1"));
+}
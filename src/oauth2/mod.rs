@@ -2,30 +2,55 @@
 mod tests;
 
 use crate::config::RPConfig;
-use crate::structs::{AuthClaims, AuthDecision, AuthVerifier};
-use crate::{log_error, log_trace};
+use crate::proxy::respond;
+use crate::structs::{AuthClaims, AuthDecision, AuthVerifier, Context, IdTokenClaims, Jwks, OAuthState, OidcEndpoints};
+use crate::{log_error, log_trace, log_warn};
+use anyhow::{anyhow, bail};
+use arc_swap::ArcSwapOption;
 use base64::Engine;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
-use oauth2::basic::BasicClient;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
 use oauth2::http::Uri;
 use oauth2::url::Url;
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl, Scope,
-    TokenResponse, TokenUrl,
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl,
+    Scope,
 };
+use bytes::Bytes;
 use pingora::ErrorType;
 use pingora::http::{ResponseHeader, StatusCode};
 use pingora::prelude::Session;
-use serde_json::Value;
 use std::fs;
+use std::sync::Arc;
 
 const COOKIE_NAME: &str = "rproxy_auth";
 const ISSUER: &str = "rproxy";
 const COOKIE_HEADER_NAME: &str = "Cookie";
+/// Followed by a hash of the state, so logins started in several tabs don't
+/// overwrite each other's cookie.
+const STATE_COOKIE_PREFIX: &str = "rproxy_oauth_state_";
+/// Time the user has to log in at the IdP.
+const STATE_TTL_SECS: u64 = 600;
+/// Longer paths are not kept through the login, the state cookie has to stay small.
+const RETURN_TO_MAX_LEN: usize = 2048;
+/// Signatures accepted on ID tokens; never HMAC, which a JWKS key could be abused for.
+const ID_TOKEN_ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
 
 impl AuthVerifier {
-    pub fn new(rp_config: RPConfig) -> Self {
+    pub fn try_new(rp_config: RPConfig) -> anyhow::Result<Self> {
         let jwt_pub_pem = fs::read(&rp_config.jwt_cert).unwrap_or_else(|e| {
             panic!(
                 "Failed to read jwt_cert PEM file '{}': {e}",
@@ -49,48 +74,95 @@ impl AuthVerifier {
             Err(err) => panic!("Failed to create EncodingKey: {}", err),
         };
 
+        let hmac_key = openssl::sha::sha256(&jwt_priv_pem).to_vec();
+
         let mut validation = Validation::new(Algorithm::RS256);
         validation.set_issuer(&["rproxy"]);
         validation.set_audience(&["rproxy"]);
 
-        let client = BasicClient::new(ClientId::new(rp_config.client_id.clone()))
-            .set_client_secret(ClientSecret::new(rp_config.client_secret.clone()))
-            .set_auth_uri(AuthUrl::new(rp_config.auth_url.clone()).expect("Invalid auth url"))
-            .set_token_uri(TokenUrl::new(rp_config.token_url.clone()).expect("Invalid token url"));
-            //redirect_url set for each upstream individually
+        // discovered by non_async_discover_oidc when there is an issuer
+        let endpoints = if rp_config.oidc_issuer.is_empty() {
+            let endpoints = OidcEndpoints::from_config(&rp_config).map_err(|e| anyhow!("Invalid SSO endpoints: {}", e))?;
+            Some(Arc::new(endpoints))
+        } else {
+            None
+        };
+
+        let client = oauth2::Client::new(ClientId::new(rp_config.client_id.clone()))
+            .set_client_secret(ClientSecret::new(rp_config.client_secret.clone()));
+            //endpoints and redirect_url set for each login
 
         let http_client = oauth2::reqwest::ClientBuilder::new()
             .redirect(oauth2::reqwest::redirect::Policy::none())
             .build()
             .expect("Client should build");
         
-        Self {
+        Ok(Self {
             rp_config,
             decoding_key,
             encoding_key,
+            hmac_key,
             validation,
             client,
             http_client,
-        }
+            endpoints: Arc::new(ArcSwapOption::new(endpoints)),
+            jwks: Arc::new(Jwks::try_new()?),
+        })
     }
 
-    pub async fn verify_auth_cookie(&self, session: &mut Session, redirect_url: String) -> pingora::Result<bool> {
+    fn current_endpoints(&self) -> anyhow::Result<Arc<OidcEndpoints>> {
+        self.endpoints
+            .load_full()
+            .ok_or_else(|| anyhow!("OIDC endpoints of {} not discovered", self.rp_config.oidc_issuer))
+    }
+
+    pub async fn verify_auth_cookie(
+        &self,
+        session: &mut Session,
+        ctx: &mut Context,
+        redirect_url: String,
+    ) -> pingora::Result<bool> {
         log_trace!("Uri host{}", session.req_header().uri);
 
         let cookie_header = session
             .get_header(COOKIE_HEADER_NAME)
             .and_then(|h| h.to_str().ok());
 
-        match self.decide_auth(&session.req_header().uri, cookie_header) {
-            AuthDecision::Exchange { code } => self.exchange(&code, session, redirect_url).await,
-            AuthDecision::RedirectToSso => self.redirect_to_sso(session, redirect_url).await,
-            AuthDecision::Proceed => Ok(false),
+        match self.decide_auth(&session.req_header().uri, cookie_header, &redirect_url) {
+            AuthDecision::Exchange { code, signed } => self.exchange(&code, signed, session, ctx, redirect_url).await,
+            AuthDecision::RedirectToSso => self.redirect_to_sso(session, ctx, redirect_url).await,
+            AuthDecision::StateMismatch => {
+                log_warn!("OAuth2 callback with a missing, expired or foreign state: {}", session.request_summary());
+                const BODY: &[u8] = b"Login failed, please try again\n";
+                let mut resp = ResponseHeader::build(StatusCode::FORBIDDEN, Some(2))?;
+                if let Some(state) = self.query_param(&session.req_header().uri, "state") {
+                    resp.insert_header("Set-Cookie", expired_state_cookie(&state))?;
+                }
+                respond(session, ctx, resp, Bytes::from_static(BODY)).await?;
+                Ok(true)
+            }
+            AuthDecision::Proceed { subject, tenant } => {
+                ctx.sso_subject = Some(subject);
+                ctx.sso_tenant = Some(tenant);
+                Ok(false)
+            }
         }
     }
 
-    fn decide_auth(&self, uri: &Uri, cookie_header: Option<&str>) -> AuthDecision {
-        if let Some(code) = self.is_oauth_redirect_with_code(uri) {
-            return AuthDecision::Exchange { code };
+    /// Only requests to the path of `redirect_url` are OAuth2 callbacks, and only
+    /// those carrying the `state` of the signed cookie set by `redirect_to_sso` are
+    /// exchanged; anything else would let a third party log the user in.
+    fn decide_auth(&self, uri: &Uri, cookie_header: Option<&str>, redirect_url: &str) -> AuthDecision {
+        let is_callback = Url::parse(redirect_url).is_ok_and(|url| url.path() == uri.path());
+        if is_callback && let Some(code) = self.query_param(uri, "code") {
+            let Some(state) = self.query_param(uri, "state") else {
+                return AuthDecision::StateMismatch;
+            };
+            let state_cookie = cookie_header.and_then(|h| self.is_have_cookie_value_by_name(h, &state_cookie_name(&state)));
+            return match state_cookie.and_then(|cookie| self.signed_state(&cookie, &state, unix_now())) {
+                Some(signed) => AuthDecision::Exchange { code, signed },
+                None => AuthDecision::StateMismatch,
+            };
         }
 
         let Some(cookie_header) = cookie_header else {
@@ -101,34 +173,42 @@ impl AuthVerifier {
             return AuthDecision::RedirectToSso;
         };
 
-        if self.decode_jwt(&jwt).is_err() {
-            return AuthDecision::RedirectToSso;
+        match self.decode_jwt(&jwt) {
+            Ok(claims) => AuthDecision::Proceed { subject: claims.sub, tenant: claims.tid },
+            Err(_) => AuthDecision::RedirectToSso,
         }
-
-        AuthDecision::Proceed
     }
 
-    async fn redirect_to_sso(&self, session: &mut Session, redirect_url: String) -> pingora::Result<bool> {
+    async fn redirect_to_sso(&self, session: &mut Session, ctx: &Context, redirect_url: String) -> pingora::Result<bool> {
         log_trace!(
             "Redirecting to SSO + req summary {}",
             session.request_summary()
         );
 
-        let location = match self.get_redirect_url(redirect_url) {
+        let return_to = return_to(&session.req_header().uri);
+        let (location, signed) = match self.get_redirect_url(redirect_url, return_to, unix_now()) {
             Ok(url) => url,
             Err(e) => {
                 log_error!("Got error during constructing redirect url {}", e);
                 return Ok(true);
             }
         };
+        let state_cookie = match self.state_cookie(&signed) {
+            Ok(cookie) => cookie,
+            Err(e) => {
+                log_error!("Unable to sign OAuth2 state {}", e);
+                return Err(pingora::Error::new(ErrorType::HTTPStatus(500)));
+            }
+        };
 
         let mut resp = ResponseHeader::build(StatusCode::FOUND, Some(0))?;
         resp.insert_header("Location", location)?;
-        session.write_response_header(Box::new(resp), true).await?;
+        resp.insert_header("Set-Cookie", state_cookie)?;
+        respond(session, ctx, resp, Bytes::new()).await?;
         Ok(true)
     }
 
-    fn is_oauth_redirect_with_code(&self, uri: &Uri) -> Option<String> {
+    fn query_param(&self, uri: &Uri, name: &str) -> Option<String> {
         const HOST_PREFIX: &str = "http://localhost/";
         let url = &(HOST_PREFIX.to_string() + &uri.to_string());
         let Ok(parsed_url) = Url::parse(url) else {
@@ -137,17 +217,35 @@ impl AuthVerifier {
         };
         parsed_url
             .query_pairs()
-            .find(|(key, _)| key == "code")
-            .map(|(_, code)| AuthorizationCode::new(code.into_owned()))
-            .map(|code| code.secret().to_owned())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
     }
 
-    async fn exchange(&self, code: &str, session: &mut Session, redirect_url: String) -> pingora::Result<bool> {
-        let token = match self
+    async fn exchange(
+        &self,
+        code: &str,
+        signed: OAuthState,
+        session: &mut Session,
+        ctx: &Context,
+        redirect_url: String,
+    ) -> pingora::Result<bool> {
+        let endpoints = match self.current_endpoints() {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                log_error!("{}", e);
+                return Err(pingora::Error::new(ErrorType::HTTPStatus(503)));
+            }
+        };
+        let client = self
             .client
             .clone()
-            .set_redirect_uri(RedirectUrl::new(redirect_url).expect("Invalid redirect url"))
-            .exchange_code(AuthorizationCode::new(code.to_string())).request_async(&self.http_client).await
+            .set_token_uri(endpoints.token_url.clone())
+            .set_redirect_uri(RedirectUrl::new(redirect_url).expect("Invalid redirect url"));
+        let mut request = client.exchange_code(AuthorizationCode::new(code.to_string()));
+        if let Some(verifier) = signed.pkce_verifier.clone() {
+            request = request.set_pkce_verifier(PkceCodeVerifier::new(verifier));
+        }
+        let token = match request.request_async(&self.http_client).await
         {
             Ok(t) => {t}
             Err(x) => {
@@ -156,16 +254,19 @@ impl AuthVerifier {
             }
         };
 
-        let jwt = token.access_token().secret();
-        let claims = self.decode_jwt_unverified(jwt).await?;
-        let name = claims
-            .get("name")
-            .and_then(Value::as_str)
-            .unwrap_or("name_unknown");
-        let tid = claims
-            .get("tid")
-            .and_then(Value::as_str)
-            .unwrap_or("tid_unknown");
+        let Some(id_token) = token.extra_fields().id_token.as_deref() else {
+            log_error!("Token response without an id_token, is the openid scope granted?");
+            return Err(pingora::Error::new(ErrorType::HTTPStatus(401)));
+        };
+        let claims = match self.verify_id_token(id_token, &signed.nonce).await {
+            Ok(claims) => claims,
+            Err(e) => {
+                log_error!("Rejected ID token: {}", e);
+                return Err(pingora::Error::new(ErrorType::HTTPStatus(401)));
+            }
+        };
+        let name = claims.name.as_deref().unwrap_or("name_unknown");
+        let tid = claims.tid.as_deref().unwrap_or("tid_unknown");
 
         let jwt = self.encode_jwt(name, tid).unwrap();
 
@@ -180,8 +281,10 @@ impl AuthVerifier {
             age = max_age
         );
         resp.insert_header("Set-Cookie", cookie_value)?;
-        resp.insert_header("Location", "/")?;
-        session.write_response_header(Box::new(resp), true).await?;
+        resp.append_header("Set-Cookie", expired_state_cookie(&signed.state))?;
+        let location = signed.return_to.as_deref().filter(|path| is_local_path(path)).unwrap_or("/");
+        resp.insert_header("Location", location)?;
+        respond(session, ctx, resp, Bytes::new()).await?;
 
         Ok(true)
     }
@@ -196,6 +299,82 @@ impl AuthVerifier {
         None
     }
 
+    /// HMAC-SHA256 of `payload`, for values rproxy hands out and reads back.
+    pub fn sign(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let key = PKey::hmac(&self.hmac_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(payload)?;
+        Ok(signer.sign_to_vec()?)
+    }
+
+    pub fn verify_signature(&self, payload: &[u8], signature: &[u8]) -> bool {
+        self.sign(payload)
+            .is_ok_and(|expected| expected.len() == signature.len() && memcmp::eq(&expected, signature))
+    }
+
+    /// `Set-Cookie` binding the login state to the browser that started it.
+    fn state_cookie(&self, signed: &OAuthState) -> anyhow::Result<String> {
+        let payload = serde_json::to_vec(signed)?;
+        let signature = self.sign(&payload)?;
+        Ok(format!(
+            "{}={}.{}; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age={}",
+            state_cookie_name(&signed.state),
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature),
+            STATE_TTL_SECS
+        ))
+    }
+
+    /// The state cookie, when its signature holds, it hasn't expired and it is for `state`.
+    fn signed_state(&self, cookie: &str, state: &str, now: u64) -> Option<OAuthState> {
+        let (payload, signature) = cookie.split_once('.')?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        if !self.verify_signature(&payload, &signature) {
+            return None;
+        }
+        serde_json::from_slice::<OAuthState>(&payload).ok().filter(|signed| {
+            signed.exp > now
+                && signed.state.len() == state.len()
+                && memcmp::eq(signed.state.as_bytes(), state.as_bytes())
+        })
+    }
+
+    /// Claims of an ID token signed by a key of the IdP's JWKS, issued by the IdP
+    /// to `client_id` for the login that sent `nonce`.
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> anyhow::Result<IdTokenClaims> {
+        let header = decode_header(id_token)?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            bail!("unsupported algorithm {:?}", header.alg);
+        }
+        let endpoints = self.current_endpoints()?;
+        let jwk = self
+            .jwks
+            .key(&endpoints.jwks_url, header.kid.as_deref())
+            .await?
+            .ok_or_else(|| anyhow!("no key {:?} in {}", header.kid, endpoints.jwks_url))?;
+        if let Some(alg) = jwk.common.key_algorithm
+            && alg.to_string() != format!("{:?}", header.alg)
+        {
+            bail!("key {:?} is for {}, token is signed with {:?}", header.kid, alg, header.alg);
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&endpoints.issuer]);
+        validation.set_audience(&[&self.rp_config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let claims = decode::<IdTokenClaims>(id_token, &DecodingKey::from_jwk(&jwk)?, &validation)?.claims;
+
+        let nonce_matches = claims
+            .nonce
+            .as_deref()
+            .is_some_and(|n| n.len() == nonce.len() && memcmp::eq(n.as_bytes(), nonce.as_bytes()));
+        if !nonce_matches {
+            bail!("nonce doesn't match the login");
+        }
+        Ok(claims)
+    }
+
     fn decode_jwt(&self, cookie_value: &str) -> anyhow::Result<AuthClaims> {
         Ok(decode::<AuthClaims>(cookie_value, &self.decoding_key, &self.validation)?.claims)
     }
@@ -221,11 +400,21 @@ impl AuthVerifier {
         )?)
     }
 
-    fn get_redirect_url(&self, redirect_url : String) -> anyhow::Result<String> {
-        let (auth_url, _) = self
+    /// Authorization URL and the login state to sign into the state cookie.
+    fn get_redirect_url(
+        &self,
+        redirect_url: String,
+        return_to: Option<String>,
+        now: u64,
+    ) -> anyhow::Result<(String, OAuthState)> {
+        let endpoints = self.current_endpoints()?;
+        let client = self
             .client
             .clone()
-            .set_redirect_uri(RedirectUrl::new(redirect_url).expect("Invalid redirect url"))
+            .set_auth_uri(endpoints.auth_url.clone())
+            .set_redirect_uri(RedirectUrl::new(redirect_url).expect("Invalid redirect url"));
+        let nonce = CsrfToken::new_random().secret().to_string();
+        let mut request = client
             .authorize_url(CsrfToken::new_random)
             .add_scopes(
                 self.rp_config
@@ -233,20 +422,58 @@ impl AuthVerifier {
                     .iter()
                     .map(|s| Scope::new(s.to_string())),
             )
-            .url();
-
-        Ok(auth_url.to_string())
-    }
-
-    async fn decode_jwt_unverified(&self, jwt: &str) -> Result<Value, pingora::Error> {
-        let parts: Vec<&str> = jwt.split('.').collect();
-        if parts.len() != 3 {
-            return Err(*pingora::Error::new(ErrorType::HTTPStatus(401)));
+            .add_extra_param("nonce", &nonce);
+        if !self.rp_config.scopes.iter().any(|s| s == "openid") {
+            request = request.add_scope(Scope::new("openid".to_string()));
+        }
+        let mut pkce_verifier = None;
+        if self.rp_config.pkce {
+            let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+            request = request.set_pkce_challenge(challenge);
+            pkce_verifier = Some(verifier.secret().to_string());
         }
-        let claims = URL_SAFE_NO_PAD
-            .decode(parts[1])
-            .map_err(|_| *pingora::Error::new(ErrorType::HTTPStatus(401)))?;
-        serde_json::from_slice::<Value>(&claims)
-            .map_err(|_| *pingora::Error::new(ErrorType::HTTPStatus(401)))
+        let (auth_url, state) = request.url();
+
+        let signed = OAuthState {
+            state: state.secret().to_string(),
+            exp: now + STATE_TTL_SECS,
+            nonce,
+            pkce_verifier,
+            return_to,
+        };
+        Ok((auth_url.to_string(), signed))
     }
 }
+
+/// Name of the state cookie of the login sent to the IdP with `state`.
+fn state_cookie_name(state: &str) -> String {
+    let hash = openssl::sha::sha256(state.as_bytes());
+    format!("{STATE_COOKIE_PREFIX}{}", URL_SAFE_NO_PAD.encode(&hash[..12]))
+}
+
+/// Removes the state cookie of `state` once its login is over, it is good for one callback.
+fn expired_state_cookie(state: &str) -> String {
+    format!("{}=; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age=0", state_cookie_name(state))
+}
+
+/// Path and query of `uri` to come back to after the login, None when it is too
+/// long for the state cookie or could lead off this host.
+fn return_to(uri: &Uri) -> Option<String> {
+    let path = uri.path_and_query()?.as_str();
+    (path.len() <= RETURN_TO_MAX_LEN && is_local_path(path)).then(|| path.to_string())
+}
+
+/// A path on this host: `//host` and `/\host` are taken as other hosts by
+/// browsers, and control characters could split the `Location` header.
+fn is_local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.starts_with("/\\")
+        && !path.chars().any(char::is_control)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -1,4 +1,6 @@
 use super::*;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use oauth2::{AuthUrl, TokenUrl};
 
 impl AuthVerifier {
     pub fn new_for_tests(rp_config: RPConfig) -> Self {
@@ -20,16 +22,17 @@ impl AuthVerifier {
         validation.set_issuer(&["rproxy"]);
         validation.set_audience(&["rproxy"]);
 
-        let client = BasicClient::new(ClientId::new("".to_string()))
-            .set_client_secret(ClientSecret::new("".to_string()))
-            .set_auth_uri(AuthUrl::new("http://localhost".to_string()).expect("Invalid auth url"))
-            .set_token_uri(
-                TokenUrl::new("http://localhost".to_string()).expect("Invalid token url"),
-            )
-            .set_redirect_uri(
-                RedirectUrl::new("http://localhost".to_string()).expect("Invalid redirect url"),
-            );
+        let client = oauth2::Client::new(ClientId::new("".to_string()))
+            .set_client_secret(ClientSecret::new("".to_string()));
 
+        let endpoints = OidcEndpoints {
+            issuer: rp_config.id_token_issuer.clone(),
+            auth_url: AuthUrl::new("http://localhost".to_string()).expect("Invalid auth url"),
+            token_url: TokenUrl::new("http://localhost".to_string()).expect("Invalid token url"),
+            jwks_url: rp_config.jwks_url.clone(),
+            userinfo_url: None,
+            end_session_url: None,
+        };
         let http_client = oauth2::reqwest::ClientBuilder::new()
             .redirect(oauth2::reqwest::redirect::Policy::none())
             .build()
@@ -39,23 +42,49 @@ impl AuthVerifier {
             rp_config,
             decoding_key,
             encoding_key,
+            hmac_key: openssl::sha::sha256(&jwt_priv_pem).to_vec(),
             validation,
             client,
             http_client,
+            endpoints: Arc::new(ArcSwapOption::from_pointee(endpoints)),
+            jwks: Arc::new(Jwks::try_new().expect("jwks client should build")),
         }
     }
 }
 
+const CALLBACK: &str = "https://example.local/oauth/callback";
+
 fn mock_verifier() -> AuthVerifier {
     AuthVerifier::new_for_tests(RPConfig::default())
 }
 
+/// `Cookie` header with the state cookie `redirect_to_sso` would set at `now`.
+fn state_cookie_header(v: &AuthVerifier, state: &str, now: u64) -> String {
+    signed_state_cookie_header(v, &login(state, now, None))
+}
+
+fn login(state: &str, now: u64, pkce_verifier: Option<&str>) -> OAuthState {
+    OAuthState {
+        state: state.to_string(),
+        exp: now + STATE_TTL_SECS,
+        nonce: "n0nce".to_string(),
+        pkce_verifier: pkce_verifier.map(str::to_string),
+        return_to: None,
+    }
+}
+
+fn signed_state_cookie_header(v: &AuthVerifier, signed: &OAuthState) -> String {
+    let set_cookie = v.state_cookie(signed).expect("state should sign");
+    let pair = set_cookie.split(';').next().expect("cookie pair");
+    format!("other=1; {pair}")
+}
+
 #[test]
 fn decide_auth_redirects_when_no_cookie_header() {
     let v = mock_verifier();
     let uri: Uri = "http://example.local/".parse().unwrap();
 
-    let d = v.decide_auth(&uri, None);
+    let d = v.decide_auth(&uri, None, CALLBACK);
     assert_eq!(d, AuthDecision::RedirectToSso);
 }
 
@@ -64,26 +93,82 @@ fn decide_auth_redirects_when_cookie_missing() {
     let v = mock_verifier();
     let uri: Uri = "http://example.local/".parse().unwrap();
 
-    let d = v.decide_auth(&uri, Some("other=1; something=2"));
+    let d = v.decide_auth(&uri, Some("other=1; something=2"), CALLBACK);
     assert_eq!(d, AuthDecision::RedirectToSso);
 }
 
 #[test]
-fn decide_auth_exchanges_when_code_query_param_is_present() {
+fn decide_auth_exchanges_when_code_comes_with_the_signed_state() {
     let v = mock_verifier();
-    let uri: Uri = "http://example.local/?code=abababbsdkajsdlkasl"
+    let uri: Uri = "http://example.local/oauth/callback?code=abababbsdkajsdlkasl&state=st4te"
         .parse()
         .unwrap();
 
-    let d = v.decide_auth(&uri, None);
+    let now = unix_now();
+    let d = v.decide_auth(&uri, Some(&state_cookie_header(&v, "st4te", now)), CALLBACK);
     assert_eq!(
         d,
         AuthDecision::Exchange {
-            code: "abababbsdkajsdlkasl".to_string()
+            code: "abababbsdkajsdlkasl".to_string(),
+            signed: login("st4te", now, None),
         }
     );
 }
 
+#[test]
+fn decide_auth_rejects_callbacks_without_a_matching_state() {
+    let v = mock_verifier();
+    let now = unix_now();
+    let callback = |state: &str| -> Uri {
+        format!("http://example.local/oauth/callback?code=abc&state={state}").parse().unwrap()
+    };
+    let valid = state_cookie_header(&v, "st4te", now);
+    let tampered = valid.replacen('.', ".AAAA", 1);
+    let expired = state_cookie_header(&v, "st4te", now - STATE_TTL_SECS - 1);
+
+    for (uri, cookies) in [
+        (callback("st4te"), None),
+        (callback("other"), Some(valid.as_str())),
+        (callback("st4te"), Some(tampered.as_str())),
+        (callback("st4te"), Some(expired.as_str())),
+        ("http://example.local/oauth/callback?code=abc".parse().unwrap(), Some(valid.as_str())),
+    ] {
+        assert_eq!(v.decide_auth(&uri, cookies, CALLBACK), AuthDecision::StateMismatch, "{uri} {cookies:?}");
+    }
+}
+
+#[test]
+fn concurrent_logins_keep_their_own_state_cookie() {
+    let v = mock_verifier();
+    let now = unix_now();
+    let first = state_cookie_header(&v, "first", now);
+    let second = state_cookie_header(&v, "second", now);
+    let cookies = format!("{first}; {second}");
+    assert_ne!(state_cookie_name("first"), state_cookie_name("second"));
+
+    for state in ["first", "second"] {
+        let uri: Uri = format!("http://example.local/oauth/callback?code=abc&state={state}").parse().unwrap();
+        assert_eq!(
+            v.decide_auth(&uri, Some(&cookies), CALLBACK),
+            AuthDecision::Exchange { code: "abc".to_string(), signed: login(state, now, None) }
+        );
+    }
+    assert!(expired_state_cookie("first").starts_with(&format!("{}=;", state_cookie_name("first"))));
+}
+
+#[test]
+fn decide_auth_ignores_code_outside_the_callback_path() {
+    let v = mock_verifier();
+    let uri: Uri = "http://example.local/app?code=abc&state=st4te".parse().unwrap();
+    let jwt = v.encode_jwt("xxx", "yyy").unwrap();
+
+    assert_eq!(v.decide_auth(&uri, None, CALLBACK), AuthDecision::RedirectToSso);
+    assert_eq!(
+        v.decide_auth(&uri, Some(&format!("rproxy_auth={jwt}")), CALLBACK),
+        AuthDecision::Proceed { subject: "xxx".to_string(), tenant: "yyy".to_string() }
+    );
+}
+
 #[test]
 fn decide_auth_proceeds_when_cookie_present_and_decodable() {
     let v = mock_verifier();
@@ -91,8 +176,8 @@ fn decide_auth_proceeds_when_cookie_present_and_decodable() {
 
     let jwt = v.encode_jwt("xxx", "yyy").unwrap();
 
-    let d = v.decide_auth(&uri, Some(&format!("rproxy_auth={}; other=1", jwt)));
-    assert_eq!(d, AuthDecision::Proceed);
+    let d = v.decide_auth(&uri, Some(&format!("rproxy_auth={}; other=1", jwt)), CALLBACK);
+    assert_eq!(d, AuthDecision::Proceed { subject: "xxx".to_string(), tenant: "yyy".to_string() });
 }
 
 #[test]
@@ -103,6 +188,148 @@ fn decide_auth_redirects_when_cookie_present_jwt_malformed() {
     let d = v.decide_auth(
         &uri,
         Some(&format!("rproxy_auth=asdasdasdasdasdasd; other=1")),
+        CALLBACK,
     );
     assert_eq!(d, AuthDecision::RedirectToSso);
 }
+
+#[test]
+fn redirect_url_carries_an_s256_challenge_of_the_verifier_when_pkce_is_on() {
+    let query = |url: &str, name: &str| {
+        Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+
+    let (url, signed) = mock_verifier().get_redirect_url(CALLBACK.to_string(), None, 1000).unwrap();
+    assert!(signed.pkce_verifier.is_none());
+    assert_eq!(query(&url, "code_challenge"), None);
+    assert_eq!(query(&url, "state"), Some(signed.state));
+    assert_eq!(query(&url, "nonce"), Some(signed.nonce));
+    assert_eq!(query(&url, "scope").as_deref(), Some("openid"));
+    assert_eq!(signed.exp, 1000 + STATE_TTL_SECS);
+
+    let v = AuthVerifier::new_for_tests(RPConfig { pkce: true, ..RPConfig::default() });
+    let (url, signed) = v.get_redirect_url(CALLBACK.to_string(), None, 1000).unwrap();
+    let verifier = signed.pkce_verifier.expect("pkce verifier");
+    let expected = URL_SAFE_NO_PAD.encode(openssl::sha::sha256(verifier.as_bytes()));
+    assert_eq!(query(&url, "code_challenge_method").as_deref(), Some("S256"));
+    assert_eq!(query(&url, "code_challenge"), Some(expected));
+}
+
+#[test]
+fn decide_auth_returns_the_signed_pkce_verifier_with_the_code() {
+    let v = mock_verifier();
+    let uri: Uri = "http://example.local/oauth/callback?code=abc&state=st4te".parse().unwrap();
+    let signed = login("st4te", unix_now(), Some("v3rifier"));
+
+    let d = v.decide_auth(&uri, Some(&signed_state_cookie_header(&v, &signed)), CALLBACK);
+    assert_eq!(d, AuthDecision::Exchange { code: "abc".to_string(), signed });
+}
+
+#[test]
+fn return_to_keeps_local_paths_only() {
+    let return_to_of = |uri: &str| return_to(&uri.parse::<Uri>().unwrap());
+    assert_eq!(return_to_of("/d/abc/board?orgId=1&from=now-6h").as_deref(), Some("/d/abc/board?orgId=1&from=now-6h"));
+    assert_eq!(return_to_of("https://grafana.example.com/explore?left=x").as_deref(), Some("/explore?left=x"));
+    assert_eq!(return_to_of("http://example.local").as_deref(), Some("/"));
+    assert_eq!(return_to_of(&format!("/{}", "a".repeat(RETURN_TO_MAX_LEN))), None);
+
+    for path in ["/app", "/app/%2F%2Fevil.example", "/search?q=//evil.example"] {
+        assert!(is_local_path(path), "{path}");
+    }
+    for path in ["", "app", "//evil.example/", "/\\evil.example", "https://evil.example/", "/a\r\nSet-Cookie: x=1"] {
+        assert!(!is_local_path(path), "{path:?}");
+    }
+}
+
+#[test]
+fn decide_auth_returns_the_signed_return_to_with_the_code() {
+    let v = mock_verifier();
+    let (_, signed) = v
+        .get_redirect_url(CALLBACK.to_string(), Some("/app/discover?_g=(time:now-1h)".to_string()), unix_now())
+        .unwrap();
+    let uri: Uri = format!("http://example.local/oauth/callback?code=abc&state={}", signed.state).parse().unwrap();
+
+    let d = v.decide_auth(&uri, Some(&signed_state_cookie_header(&v, &signed)), CALLBACK);
+    assert_eq!(d, AuthDecision::Exchange { code: "abc".to_string(), signed });
+}
+
+const IDP_KID: &str = "idp-1";
+const IDP_ISSUER: &str = "https://idp.example.com/tenant";
+const CLIENT_ID: &str = "rproxy-client";
+
+/// Verifier whose JWKS already holds the IdP key; the test key pair stands in for it.
+async fn idp_verifier() -> AuthVerifier {
+    let v = AuthVerifier::new_for_tests(RPConfig {
+        client_id: CLIENT_ID.to_string(),
+        id_token_issuer: IDP_ISSUER.to_string(),
+        ..RPConfig::default()
+    });
+    let mut jwk = Jwk::from_encoding_key(&idp_key(), Algorithm::RS256).expect("jwk from the test key");
+    jwk.common.key_id = Some(IDP_KID.to_string());
+    v.jwks.keys.store(Arc::new(JwkSet { keys: vec![jwk] }));
+    v.jwks.fetched.store(Some(Arc::new(std::time::Instant::now())));
+    v
+}
+
+fn idp_key() -> EncodingKey {
+    let pem = fs::read(format!("{}/config/jwt_private.pem", env!("CARGO_MANIFEST_DIR"))).expect("test private key");
+    EncodingKey::from_rsa_pem(&pem).expect("valid RSA private key")
+}
+
+fn id_token(kid: &str, claims: serde_json::Value) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    encode(&header, &claims, &idp_key()).expect("id token should sign")
+}
+
+fn id_claims() -> serde_json::Value {
+    serde_json::json!({
+        "iss": IDP_ISSUER,
+        "aud": CLIENT_ID,
+        "exp": unix_now() + 300,
+        "sub": "jane-id",
+        "name": "Jane",
+        "tid": "tenant-1",
+        "nonce": "n0nce",
+    })
+}
+
+#[tokio::test]
+async fn verify_id_token_accepts_a_token_signed_by_the_idp_for_this_login() {
+    let v = idp_verifier().await;
+
+    let claims = v.verify_id_token(&id_token(IDP_KID, id_claims()), "n0nce").await.expect("valid ID token");
+    assert_eq!(claims.name.as_deref(), Some("Jane"));
+    assert_eq!(claims.tid.as_deref(), Some("tenant-1"));
+}
+
+#[tokio::test]
+async fn verify_id_token_rejects_foreign_expired_or_replayed_tokens() {
+    let v = idp_verifier().await;
+    let with = |key: &str, value: serde_json::Value| {
+        let mut claims = id_claims();
+        claims[key] = value;
+        id_token(IDP_KID, claims)
+    };
+    let hmac = encode(
+        &Header { kid: Some(IDP_KID.to_string()), ..Header::new(Algorithm::HS256) },
+        &id_claims(),
+        &EncodingKey::from_secret(b"secret"),
+    )
+    .unwrap();
+
+    for (case, token) in [
+        ("other audience", with("aud", serde_json::json!("someone-else"))),
+        ("other issuer", with("iss", serde_json::json!("https://evil.example.com"))),
+        ("expired", with("exp", serde_json::json!(unix_now() - 3600))),
+        ("other login", with("nonce", serde_json::json!("replayed"))),
+        ("unknown key", id_token("idp-2", id_claims())),
+        ("hmac", hmac),
+    ] {
+        assert!(v.verify_id_token(&token, "n0nce").await.is_err(), "{case}");
+    }
+}
@@ -0,0 +1,129 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::RPConfig;
+use crate::structs::{AuthVerifier, OidcEndpoints, OidcProviderMetadata};
+use crate::{log_error, log_info, log_trace};
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use oauth2::{AuthUrl, TokenUrl};
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+/// An issuer that accepts the connection but never answers fails discovery after this long.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl OidcEndpoints {
+    /// The hand-configured endpoints, used when there is no `oidc_issuer`.
+    pub fn from_config(rp_config: &RPConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            issuer: rp_config.id_token_issuer.clone(),
+            auth_url: AuthUrl::new(rp_config.auth_url.clone()).map_err(|e| anyhow!("invalid auth_url: {}", e))?,
+            token_url: TokenUrl::new(rp_config.token_url.clone()).map_err(|e| anyhow!("invalid token_url: {}", e))?,
+            jwks_url: rp_config.jwks_url.clone(),
+            userinfo_url: None,
+            end_session_url: None,
+        })
+    }
+
+    /// Reads the discovery document of `issuer`, which has to name `issuer` itself.
+    pub async fn discover(http_client: &reqwest::Client, issuer: &str, timeout: Duration) -> anyhow::Result<Self> {
+        let url = format!("{}{}", issuer.trim_end_matches('/'), DISCOVERY_PATH);
+        let body = tokio::time::timeout(timeout, async {
+            let resp = http_client.get(&url).send().await?.error_for_status()?;
+            resp.bytes().await
+        })
+        .await
+        .map_err(|_| anyhow!("no answer from {} within {:?}", url, timeout))??;
+        let metadata: OidcProviderMetadata = serde_json::from_slice(&body)
+            .map_err(|e| anyhow!("invalid discovery document at {}: {}", url, e))?;
+        if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            bail!("discovery document at {} is for issuer '{}'", url, metadata.issuer);
+        }
+        Ok(Self {
+            auth_url: AuthUrl::new(metadata.authorization_endpoint)
+                .map_err(|e| anyhow!("invalid authorization_endpoint: {}", e))?,
+            token_url: TokenUrl::new(metadata.token_endpoint).map_err(|e| anyhow!("invalid token_endpoint: {}", e))?,
+            jwks_url: metadata.jwks_uri,
+            userinfo_url: metadata.userinfo_endpoint,
+            end_session_url: metadata.end_session_endpoint,
+            issuer: metadata.issuer,
+        })
+    }
+}
+
+impl AuthVerifier {
+    /// Discovers the IdP before the proxy starts, exits when that fails as no one
+    /// could log in.
+    pub fn non_async_discover_oidc(&self) {
+        if self.rp_config.oidc_issuer.is_empty() {
+            return;
+        }
+        log_info!("Discovering OIDC endpoints of {}...", self.rp_config.oidc_issuer);
+        let rt = match Runtime::new() {
+            Ok(rt) => rt,
+            Err(err) => {
+                log_error!("Unable to start a runtime for OIDC discovery: {:?}", err);
+                std::process::exit(1);
+            }
+        };
+        rt.block_on(async {
+            if let Err(err) = self.refresh_oidc().await {
+                log_error!("OIDC discovery from {} failed: {:?}", self.rp_config.oidc_issuer, err);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    /// Fetches the discovery document again, the current endpoints stay when that fails.
+    pub async fn refresh_oidc(&self) -> anyhow::Result<()> {
+        let discovered = OidcEndpoints::discover(&self.jwks.http_client, &self.rp_config.oidc_issuer, DISCOVERY_TIMEOUT).await?;
+        let current = self.endpoints.load_full();
+        if current.as_deref() == Some(&discovered) {
+            log_trace!("OIDC endpoints unchanged");
+            return Ok(());
+        }
+        log_info!(
+            "OIDC endpoints of {}: authorization {}, token {}, jwks {}, userinfo {}, end session {}",
+            discovered.issuer,
+            discovered.auth_url.as_str(),
+            discovered.token_url.as_str(),
+            discovered.jwks_url,
+            discovered.userinfo_url.as_deref().unwrap_or("-"),
+            discovered.end_session_url.as_deref().unwrap_or("-"),
+        );
+        if current.is_some_and(|c| c.jwks_url != discovered.jwks_url) {
+            self.jwks.invalidate();
+        }
+        self.endpoints.store(Some(Arc::new(discovered)));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AuthVerifier {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let secs = self.rp_config.oidc_discovery_secs;
+        let enabled = !self.rp_config.oidc_issuer.is_empty() && secs > 0;
+        let mut refresh = tokio::time::interval(Duration::from_secs(secs.max(1)));
+        // the first tick fires immediately, the endpoints were just discovered at startup
+        refresh.tick().await;
+        loop {
+            tokio::select! {
+                _ = refresh.tick(), if enabled => {
+                    if let Err(e) = self.refresh_oidc().await {
+                        log_error!("OIDC discovery failed, keeping current endpoints: {}", e);
+                    }
+                }
+                _ = shutdown.changed() => {
+                    log_info!("Shutting down (oidc background service)...");
+                    break;
+                }
+            }
+        }
+    }
+}
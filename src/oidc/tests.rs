@@ -0,0 +1,156 @@
+use super::*;
+use axum::Router;
+use axum::extract::State;
+use axum::routing::get;
+use axum::Json;
+use std::sync::Mutex;
+
+/// Stand-in for the IdP's discovery endpoint, serving whatever document the test puts in.
+#[derive(Clone, Default)]
+struct MockIdp {
+    document: Arc<Mutex<serde_json::Value>>,
+}
+
+impl MockIdp {
+    /// Serves the document of an IdP at the returned issuer URL, with its keys at `jwks_path`.
+    async fn serve(&self, jwks_path: &str) -> String {
+        let router = Router::new()
+            .route(DISCOVERY_PATH, get(document))
+            .with_state(self.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock idp");
+        let issuer = format!("http://{}", listener.local_addr().expect("mock idp addr"));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        self.publish(&issuer, jwks_path);
+        issuer
+    }
+
+    fn publish(&self, issuer: &str, jwks_path: &str) {
+        *self.document.lock().expect("mock document lock") = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "jwks_uri": format!("{issuer}{jwks_path}"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+            "response_types_supported": ["code"],
+        });
+    }
+}
+
+async fn document(State(idp): State<MockIdp>) -> Json<serde_json::Value> {
+    Json(idp.document.lock().expect("mock document lock").clone())
+}
+
+/// Accepts connections and never answers, like an issuer that hangs.
+async fn hung_issuer() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind hung issuer");
+    let issuer = format!("http://{}", listener.local_addr().expect("hung issuer addr"));
+    tokio::spawn(async move {
+        let mut open = vec![];
+        while let Ok((conn, _)) = listener.accept().await {
+            open.push(conn);
+        }
+    });
+    issuer
+}
+
+fn discovering_verifier(issuer: &str) -> AuthVerifier {
+    let v = AuthVerifier::new_for_tests(RPConfig {
+        oidc_issuer: issuer.to_string(),
+        ..RPConfig::default()
+    });
+    v.endpoints.store(None);
+    v
+}
+
+#[tokio::test]
+async fn discovery_reads_every_endpoint() {
+    let idp = MockIdp::default();
+    let issuer = idp.serve("/keys").await;
+
+    let endpoints = OidcEndpoints::discover(&reqwest::Client::new(), &format!("{issuer}/"), DISCOVERY_TIMEOUT)
+        .await
+        .expect("endpoints discovered");
+
+    assert_eq!(endpoints.issuer, issuer);
+    assert_eq!(endpoints.auth_url.as_str(), format!("{issuer}/authorize"));
+    assert_eq!(endpoints.token_url.as_str(), format!("{issuer}/token"));
+    assert_eq!(endpoints.jwks_url, format!("{issuer}/keys"));
+    assert_eq!(endpoints.userinfo_url, Some(format!("{issuer}/userinfo")));
+    assert_eq!(endpoints.end_session_url, None);
+}
+
+#[tokio::test]
+async fn discovery_rejects_a_document_of_another_issuer() {
+    let idp = MockIdp::default();
+    let issuer = idp.serve("/keys").await;
+    idp.publish("https://login.example.com", "/keys");
+
+    let e = OidcEndpoints::discover(&reqwest::Client::new(), &issuer, DISCOVERY_TIMEOUT)
+        .await
+        .expect_err("issuer mismatch");
+    assert!(e.to_string().contains("is for issuer 'https://login.example.com'"), "{e}");
+
+    let e = OidcEndpoints::discover(&reqwest::Client::new(), "http://127.0.0.1:9", DISCOVERY_TIMEOUT)
+        .await
+        .expect_err("unreachable issuer");
+    assert!(!e.to_string().is_empty());
+}
+
+#[tokio::test]
+async fn discovery_gives_up_on_an_issuer_that_never_answers() {
+    let issuer = hung_issuer().await;
+
+    let e = OidcEndpoints::discover(&reqwest::Client::new(), &issuer, Duration::from_millis(200))
+        .await
+        .expect_err("discovery timed out");
+    assert_eq!(e.to_string(), format!("no answer from {issuer}{DISCOVERY_PATH} within 200ms"));
+}
+
+#[tokio::test]
+async fn refresh_swaps_the_endpoints_and_refetches_moved_keys() {
+    let idp = MockIdp::default();
+    let issuer = idp.serve("/keys").await;
+    let v = discovering_verifier(&issuer);
+
+    v.refresh_oidc().await.expect("endpoints discovered");
+    assert_eq!(v.endpoints.load_full().expect("endpoints").jwks_url, format!("{issuer}/keys"));
+    v.jwks.fetched.store(Some(Arc::new(std::time::Instant::now())));
+
+    v.refresh_oidc().await.expect("endpoints unchanged");
+    assert!(v.jwks.fetched.load().is_some(), "same keys url keeps the cached keys");
+
+    idp.publish(&issuer, "/keys/v2");
+    v.refresh_oidc().await.expect("endpoints rediscovered");
+    assert_eq!(v.endpoints.load_full().expect("endpoints").jwks_url, format!("{issuer}/keys/v2"));
+    assert!(v.jwks.fetched.load().is_none(), "moved keys are fetched again");
+}
+
+#[tokio::test]
+async fn failed_refresh_keeps_the_current_endpoints() {
+    let v = discovering_verifier("http://127.0.0.1:9");
+    let current = OidcEndpoints::from_config(&RPConfig {
+        auth_url: "https://idp.example.com/authorize".to_string(),
+        token_url: "https://idp.example.com/token".to_string(),
+        ..RPConfig::default()
+    })
+    .expect("valid endpoints");
+    v.endpoints.store(Some(Arc::new(current.clone())));
+
+    assert!(v.refresh_oidc().await.is_err());
+    assert_eq!(v.endpoints.load_full().as_deref(), Some(&current));
+}
+
+#[test]
+fn static_endpoints_must_be_urls() {
+    let e = OidcEndpoints::from_config(&RPConfig {
+        auth_url: "not a url".to_string(),
+        token_url: "https://idp.example.com/token".to_string(),
+        ..RPConfig::default()
+    })
+    .expect_err("invalid auth_url");
+    assert!(e.to_string().contains("invalid auth_url"), "{e}");
+}
@@ -0,0 +1,96 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::OutlierDetectionConfig;
+use crate::structs::{Context, NetIqLoadBalancer, OutlierState};
+use crate::log_warn;
+use std::time::{Duration, Instant};
+
+impl NetIqLoadBalancer {
+    /// Feeds the outcome of a proxied request into the outlier detection of its
+    /// upstream, once per attempt.
+    pub fn record_outcome(&self, ctx: &mut Context, success: bool) {
+        if ctx.outcome_recorded {
+            return;
+        }
+        ctx.outcome_recorded = true;
+        if let (Some(upstream), Some(backend)) = (&ctx.fully_qualified_upstream, &ctx.backend) {
+            self.record_backend_outcome(upstream, backend, success, Instant::now());
+        }
+    }
+
+    /// Ejects the backend once it failed `consecutive_failures` times in a row, unless
+    /// that would take more than `max_ejection_percent` of the pool out.
+    pub fn record_backend_outcome(&self, upstream: &str, backend: &str, success: bool, now: Instant) {
+        let routes = self.live_config.routes.load();
+        let Some(conf) = routes.outlier_detection(upstream) else {
+            return;
+        };
+        if success {
+            if let Some(mut outliers) = self.outliers.get_mut(upstream)
+                && let Some(state) = outliers.get_mut(backend)
+            {
+                state.consecutive_failures = 0;
+            }
+            return;
+        }
+
+        let pool = self.balancers.get(upstream).map_or(0, |b| b.backends().len());
+        let mut outliers = self.outliers.entry(upstream.to_string()).or_default();
+        let ejected = outliers.values().filter(|s| s.is_ejected(now)).count();
+        let state = outliers.entry(backend.to_string()).or_default();
+        // requests still in flight when the backend got ejected don't count
+        if state.is_ejected(now) {
+            return;
+        }
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < conf.consecutive_failures {
+            return;
+        }
+        if (ejected + 1) * 100 > pool * usize::from(conf.max_ejection_percent) {
+            log_warn!(
+                "Backend {} of {} keeps failing but {} of {} backends are already ejected",
+                backend, upstream, ejected, pool
+            );
+            return;
+        }
+        let duration = state.eject(conf, now);
+        log_warn!(
+            "Backend {} of {} ejected for {:?} after {} failures in a row",
+            backend, upstream, duration, conf.consecutive_failures
+        );
+    }
+
+    /// Forgets backends of the upstream that are no longer in its pool.
+    pub fn prune_outliers(&self, upstream: &str, pool: &[String]) {
+        if let Some(mut outliers) = self.outliers.get_mut(upstream) {
+            outliers.retain(|backend, _| pool.contains(backend));
+        }
+    }
+}
+
+impl OutlierState {
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+
+    /// Ejects for the base time doubled per previous ejection, capped at the max.
+    /// The count starts over once the backend stayed in the pool for the max time.
+    fn eject(&mut self, conf: &OutlierDetectionConfig, now: Instant) -> Duration {
+        let max = Duration::from_secs(conf.max_ejection_secs);
+        if self.ejected_until.is_some_and(|until| now >= until + max) {
+            self.ejections = 0;
+        }
+        let duration = Duration::from_secs(conf.base_ejection_secs)
+            .saturating_mul(2u32.saturating_pow(self.ejections))
+            .min(max);
+        self.ejections = self.ejections.saturating_add(1);
+        self.consecutive_failures = 0;
+        self.ejected_until = Some(now + duration);
+        duration
+    }
+
+    pub fn ejected_for(&self, now: Instant) -> Duration {
+        self.ejected_until.map_or(Duration::ZERO, |until| until.saturating_duration_since(now))
+    }
+}
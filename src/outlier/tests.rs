@@ -0,0 +1,111 @@
+use super::*;
+use crate::config::{LoadBalancing, RPConfig, UpstreamDetails};
+use crate::structs::Balancer;
+use crate::routing::RouteTable;
+use pingora::lb::Backend;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+const BACKENDS: [&str; 4] = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.0.0.4:80"];
+
+async fn lb_with(outlier_detection: serde_json::Value, pool: &[&str]) -> NetIqLoadBalancer {
+    let details: UpstreamDetails =
+        serde_json::from_value(serde_json::json!({ "upstream": "svc", "outlier_detection": outlier_detection }))
+            .expect("valid upstream details");
+    let rp_config = RPConfig {
+        host_to_upstream: HashMap::from([("svc".to_string(), details)]),
+        ..RPConfig::default()
+    };
+    let lb = NetIqLoadBalancer::new_for_tests(rp_config.clone());
+    lb.live_config
+        .routes
+        .store(Arc::new(RouteTable::compile(&rp_config).expect("routes should compile")));
+
+    let backends: BTreeSet<Backend> = pool.iter().map(|a| Backend::new(a).expect("valid address")).collect();
+    let balancer = Balancer::build(backends, &LoadBalancing::RoundRobin).await.expect("static discovery");
+    lb.balancers.insert("svc".to_string(), balancer);
+    lb
+}
+
+fn state(lb: &NetIqLoadBalancer, backend: &str) -> OutlierState {
+    lb.outliers
+        .get("svc")
+        .and_then(|o| o.get(backend).cloned())
+        .unwrap_or_default()
+}
+
+fn fail(lb: &NetIqLoadBalancer, backend: &str, times: usize, now: Instant) {
+    for _ in 0..times {
+        lb.record_backend_outcome("svc", backend, false, now);
+    }
+}
+
+#[tokio::test]
+async fn backend_is_ejected_after_consecutive_failures_only() {
+    let lb = lb_with(serde_json::json!({"consecutive_failures": 3}), &BACKENDS).await;
+    let now = Instant::now();
+
+    fail(&lb, BACKENDS[0], 2, now);
+    lb.record_backend_outcome("svc", BACKENDS[0], true, now);
+    fail(&lb, BACKENDS[0], 2, now);
+    assert!(!state(&lb, BACKENDS[0]).is_ejected(now));
+
+    fail(&lb, BACKENDS[0], 1, now);
+    let ejected = state(&lb, BACKENDS[0]);
+    assert!(ejected.is_ejected(now));
+    assert_eq!(ejected.ejected_for(now), Duration::from_secs(30));
+    assert!(!ejected.is_ejected(now + Duration::from_secs(30)));
+}
+
+#[tokio::test]
+async fn ejection_time_doubles_up_to_the_max_and_is_forgiven_later() {
+    let lb = lb_with(
+        serde_json::json!({"consecutive_failures": 1, "base_ejection_secs": 10, "max_ejection_secs": 35}),
+        &BACKENDS,
+    )
+    .await;
+    let mut now = Instant::now();
+    let mut ejections = vec![];
+    for _ in 0..4 {
+        fail(&lb, BACKENDS[0], 1, now);
+        let ejected_for = state(&lb, BACKENDS[0]).ejected_for(now);
+        ejections.push(ejected_for.as_secs());
+        now += ejected_for;
+    }
+    assert_eq!(ejections, vec![10, 20, 35, 35]);
+
+    fail(&lb, BACKENDS[0], 1, now + Duration::from_secs(35));
+    assert_eq!(state(&lb, BACKENDS[0]).ejections, 1);
+}
+
+#[tokio::test]
+async fn ejections_are_capped_by_max_ejection_percent() {
+    let lb = lb_with(serde_json::json!({"consecutive_failures": 1, "max_ejection_percent": 50}), &BACKENDS).await;
+    let now = Instant::now();
+
+    for backend in BACKENDS {
+        fail(&lb, backend, 1, now);
+    }
+
+    let ejected = BACKENDS.iter().filter(|b| state(&lb, b).is_ejected(now)).count();
+    assert_eq!(ejected, 2);
+}
+
+#[tokio::test]
+async fn single_backend_pool_is_never_ejected_below_full_percent() {
+    let lb = lb_with(serde_json::json!({"consecutive_failures": 1}), &BACKENDS[..1]).await;
+    let now = Instant::now();
+
+    fail(&lb, BACKENDS[0], 3, now);
+
+    assert!(!state(&lb, BACKENDS[0]).is_ejected(now));
+}
+
+#[tokio::test]
+async fn upstream_without_outlier_detection_is_not_tracked() {
+    let lb = lb_with(serde_json::json!(null), &BACKENDS).await;
+
+    fail(&lb, BACKENDS[0], 10, Instant::now());
+
+    assert!(lb.outliers.is_empty());
+}
@@ -1,21 +1,37 @@
 #[cfg(test)]
 mod tests;
 
-use crate::config::{RPConfig, UpstreamDetails};
+use crate::config::{HstsConfig, LoadBalancing, PlainHttp, RPConfig, UpstreamTlsVerify};
 use crate::consul::ConsulDiscovery;
-use crate::structs::{AuthVerifier, ConsulNode, ConsulNodes, Context, LoadBalancers, NetIqLoadBalancer};
-use crate::{log_error, log_info, log_trace};
+use crate::forwarding::set_forwarding_headers;
+use crate::structs::{
+    AuthVerifier, BackendHealth, Balancer, ClientCert, ClientCerts, ConsulNode, ConsulNodes, Context, DrainingUpstreams,
+    InFlight, LiveConfig, LoadBalancers, Metrics, NetIqLoadBalancer, Outliers, RetryBudgets, TemplateValues, UpstreamTls,
+};
+use crate::{log_error, log_info, log_trace, log_warn};
 use async_trait::async_trait;
 use bytes::Bytes;
 use pingora::ErrorSource::Upstream;
-use pingora::lb::LoadBalancer;
-use pingora::prelude::{ProxyHttp, RoundRobin, Session};
-use pingora::{Error, HTTPStatus, ImmutStr, RetryType};
+use pingora::proxy::FailToProxy;
+use pingora::http::{RequestHeader, ResponseHeader, StatusCode};
+use pingora::lb::Backend;
+use pingora::prelude::{ProxyHttp, Session};
+use pingora::{Error, ErrorSource, ErrorType, HTTPStatus, ImmutStr, RetryType};
 use pingora_core::prelude::HttpPeer;
+use pingora_core::protocols::http::ServerSession;
 use pingora_core::server::ShutdownWatch;
 use pingora_core::services::background::BackgroundService;
+use std::collections::{BTreeSet, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Host label of the metrics of requests no route matched.
+const UNMATCHED_ROUTE: &str = "unmatched";
+const MAX_REQUEST_ID_LEN: usize = 128;
 
 #[async_trait]
 impl ProxyHttp for NetIqLoadBalancer {
@@ -23,7 +39,21 @@ impl ProxyHttp for NetIqLoadBalancer {
     fn new_ctx(&self) -> Self::CTX {
         Context {
             hostname: None,
+            route: None,
             fully_qualified_upstream: None,
+            hsts: None,
+            backend: None,
+            outcome_recorded: false,
+            tried: vec![],
+            in_flight: None,
+            affinity_cookie: None,
+            started: Instant::now(),
+            upstream_started: None,
+            upstream_latency: None,
+            sso_subject: None,
+            sso_tenant: None,
+            request_id: new_request_id(),
+            span: Span::none(),
         }
     }
 
@@ -32,35 +62,67 @@ impl ProxyHttp for NetIqLoadBalancer {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
-        let hostname = self.get_host(session).ok_or_else(|| {
-            Box::new(Error {
-                etype: HTTPStatus(503),
-                esource: Upstream,
-                retry: RetryType::Decided(false),
-                cause: None,
-                context: Some(ImmutStr::Static("Hostname not resolved")),
-            })
-        })?;
-
-        log_trace!("request summary {}", session.request_summary());
-        let upstream = match self.resolve_upstream(&hostname) {
-            Some(u) => {u}
-            None => {
-                    let _ = session
-                        .respond_error_with_body(404, bytes::Bytes::from("Not found\n"))
-                        .await;
-                    return Ok(true)
+        ctx.request_id = accepted_request_id(session.req_header()).unwrap_or_else(|| ctx.request_id.clone());
+        // error level, so the request id is on every log line whatever the log_level
+        ctx.span = tracing::error_span!(target: "rproxy", "request", request_id = %ctx.request_id);
+        let span = ctx.span.clone();
+        async {
+            let hostname = self.get_host(session).ok_or_else(|| {
+                Box::new(Error {
+                    etype: HTTPStatus(503),
+                    esource: Upstream,
+                    retry: RetryType::Decided(false),
+                    cause: None,
+                    context: Some(ImmutStr::Static("Hostname not resolved")),
+                })
+            })?;
+
+            log_trace!("request summary {}", session.request_summary());
+            ctx.hostname = Some(hostname.to_string());
+            let upstream = match self.live_config.routes.load().resolve(&hostname, session.req_header()) {
+                Some((route, u)) => {
+                    ctx.route = Some(route.to_string());
+                    u.clone()
+                }
+                None => {
+                        let _ = respond(session, ctx, ServerSession::generate_error(404), Bytes::from("Not found\n")).await;
+                        return Ok(true)
+                }
+            };
+            ctx.fully_qualified_upstream = Some(upstream.upstream.clone());
+            let is_tls = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+            if !is_tls && upstream.plain_http != PlainHttp::Proxy {
+                let tls_port = self.live_config.rp_config.load().tls_port;
+                let path = session.req_header().uri.path_and_query().map_or("/", |p| p.as_str());
+                let location = https_location(&hostname, tls_port, path);
+                let status = match upstream.plain_http {
+                    PlainHttp::Redirect308 => StatusCode::PERMANENT_REDIRECT,
+                    _ => StatusCode::MOVED_PERMANENTLY,
+                };
+                let mut resp = ResponseHeader::build(status, Some(0))?;
+                resp.insert_header("Location", location)?;
+                respond(session, ctx, resp, Bytes::new()).await?;
+                return Ok(true);
+            }
+            if is_tls {
+                ctx.hsts = upstream.hsts.as_ref().map(hsts_value);
+            }
+            if !self.balancers.contains_key(&upstream.upstream) {
+                log_warn!("No healthy backends for upstream {}", upstream.upstream);
+                let body = format!("503 Service Unavailable: no healthy backends for {}\n", upstream.upstream);
+                let _ = respond(session, ctx, ServerSession::generate_error(503), Bytes::from(body)).await;
+                return Ok(true);
             }
-        };
-        ctx.hostname = Some(hostname.to_string());
-        ctx.fully_qualified_upstream = Some(upstream.upstream);
 
-        //OAUTH2 challenge
-        if  upstream.sso_req {
-            return self.auth_verifier.verify_auth_cookie(session, upstream.redirect_url).await;
-        };
+            //OAUTH2 challenge
+            if  upstream.sso_req {
+                return self.auth_verifier.verify_auth_cookie(session, ctx, upstream.redirect_url).await;
+            };
 
-        Ok(false)
+            Ok(false)
+        }
+        .instrument(span)
+        .await
     }
 
     async fn upstream_peer(
@@ -68,76 +130,252 @@ impl ProxyHttp for NetIqLoadBalancer {
         _session: &mut Session,
         _ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
-        let upstream_name = match _ctx.fully_qualified_upstream.as_ref() {
-            Some(x) => x,
-            None => {
-                if let Err(e) = _session
-                    .respond_error_with_body(502, Bytes::from("502 Bad Gateway\n"))
-                    .await
-                {
+        let span = _ctx.span.clone();
+        async {
+            let upstream_name = match _ctx.fully_qualified_upstream.clone() {
+                Some(x) => x,
+                None => {
+                    let resp = ServerSession::generate_error(502);
+                    if let Err(e) = respond(_session, _ctx, resp, Bytes::from("502 Bad Gateway\n")).await {
+                        log_error!("Failed to send error response: {:?}", e);
+                    }
+                    return Err(Box::new(Error {
+                        etype: HTTPStatus(502),
+                        esource: Upstream,
+                        retry: RetryType::Decided(false),
+                        cause: None,
+                        context: Option::from(ImmutStr::Static("Upstream not found")),
+                    }));
+                }
+            };
+            let upstream_name = upstream_name.as_str();
+            let balancer = match self.balancers.get(upstream_name) {
+                Some(x) => x,
+                None => {
+                    log_error!("Balancer not found for upstream: {}", upstream_name);
+                    return Err(Box::new(Error {
+                        etype: HTTPStatus(502),
+                        esource: Upstream,
+                        retry: RetryType::Decided(true),
+                        cause: None,
+                        context: Option::from(ImmutStr::Owned(format!("Balancer {} not found", upstream_name).into_boxed_str())),
+                    }));
+                },
+            };
+            let now = Instant::now();
+            if _ctx.tried.is_empty() {
+                self.count_request(upstream_name, now);
+            }
+            _ctx.upstream_started = Some(now);
+            let routes = self.live_config.routes.load();
+            let affinity = routes.affinity(upstream_name);
+            let pinned = affinity.and_then(|conf| self.auth_verifier.pinned_backend(upstream_name, _session.req_header(), conf));
+            let client_ip = _session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip().to_string());
+            let key = balancer.hash_key(_session.req_header(), client_ip.as_deref());
+            let backend = match self.select_backend(upstream_name, &balancer, &key, &_ctx.tried, pinned.as_deref()) {
+                Some(x) => x,
+                None => {
+                    let reason = if _ctx.tried.is_empty() { "No healthy backend" } else { "No backend left to retry" };
+                    log_warn!("{} for upstream: {}", reason, upstream_name);
+                    return Err(Box::new(Error {
+                        etype: HTTPStatus(503),
+                        esource: Upstream,
+                        retry: RetryType::Decided(false),
+                        cause: None,
+                        context: Option::from(ImmutStr::Owned(format!("{} for {}", reason, upstream_name).into_boxed_str())),
+                    }));
+                }
+            };
+            let addr = backend.addr.to_string();
+            self.acquire_backend(_ctx, &addr);
+            _ctx.tried.push(addr.clone());
+            _ctx.affinity_cookie = match affinity {
+                Some(conf) if pinned.as_deref() != Some(addr.as_str()) => {
+                    let secure = _session.digest().is_some_and(|d| d.ssl_digest.is_some());
+                    self.auth_verifier
+                        .affinity_cookie(upstream_name, &addr, conf, secure)
+                        .inspect_err(|e| log_error!("Unable to sign affinity cookie for {}: {}", upstream_name, e))
+                        .ok()
+                }
+                _ => None,
+            };
+            _ctx.backend = Some(addr);
+            _ctx.outcome_recorded = false;
+            let tls = routes.upstream_tls(upstream_name);
+            let hostname = _ctx.hostname.as_deref().unwrap_or_default();
+            let peer = self.build_peer(backend, hostname, tls.as_deref())?;
+            Ok(Box::new(peer))
+        }
+        .instrument(span)
+        .await
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let _span = ctx.span.clone().entered();
+        if let Some(upstream) = &ctx.fully_qualified_upstream {
+            self.metrics.connect_errors.with_label_values(&[upstream]).inc();
+        }
+        self.record_outcome(ctx, false);
+        e.set_retry(self.may_retry(ctx, Instant::now()));
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let _span = ctx.span.clone().entered();
+        if e.esource == Upstream {
+            self.record_outcome(ctx, false);
+        }
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        e
+    }
+
+    async fn logging(&self, _session: &mut Session, _e: Option<&Error>, ctx: &mut Self::CTX) {
+        let _span = ctx.span.clone().entered();
+        self.release_backend(ctx);
+        let status = _session.response_written().map(|r| r.status.as_u16());
+        self.metrics.record_request(
+            ctx.route.as_deref().unwrap_or(UNMATCHED_ROUTE),
+            ctx.fully_qualified_upstream.as_deref().unwrap_or_default(),
+            status,
+            ctx.started.elapsed(),
+        );
+        self.write_access_log(_session, ctx);
+    }
+
+    /// Pingora's error response for `e`, with the request id.
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> FailToProxy {
+        let span = ctx.span.clone();
+        async {
+            let code = match e.etype() {
+                HTTPStatus(code) => *code,
+                _ => match e.esource() {
+                    ErrorSource::Upstream => 502,
+                    ErrorSource::Downstream => match e.etype() {
+                        // the client is gone
+                        ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                        _ => 400,
+                    },
+                    ErrorSource::Internal | ErrorSource::Unset => 500,
+                },
+            };
+            if code > 0 && session.response_written().is_none() {
+                session.set_keepalive(None);
+                if let Err(e) = respond(session, ctx, ServerSession::generate_error(code), Bytes::new()).await {
                     log_error!("Failed to send error response: {:?}", e);
                 }
-                return Err(Box::new(Error {
-                    etype: HTTPStatus(502),
-                    esource: Upstream,
-                    retry: RetryType::Decided(false),
-                    cause: None,
-                    context: Option::from(ImmutStr::Static("Upstream not found")),
-                }));
             }
-        };
-        let upstream = match self.balancers.get(upstream_name) {
-            Some(x) => x,
-            None => {
-                log_error!("Balancer not found for upstream: {}", upstream_name);
-                return Err(Box::new(Error {
-                    etype: HTTPStatus(502),
-                    esource: Upstream,
-                    retry: RetryType::Decided(true),
-                    cause: None,
-                    context: Option::from(ImmutStr::Owned(format!("Balancer {} not found", upstream_name).into_boxed_str())),
-                }));
-            },
+            FailToProxy {
+                error_code: code,
+                can_reuse_downstream: false,
+            }
         }
-            .select(b"", 256)
-            .unwrap();
-        let peer = Box::new(HttpPeer::new(
-            upstream,
-            false,
-            "one.one.one.one".to_string(),
-        ));
-        Ok(peer)
+        .instrument(span)
+        .await
     }
 
-    // async fn upstream_request_filter(
-    //     &self,
-    //     _session: &mut Session,
-    //     upstream_request: &mut RequestHeader,
-    //     _ctx: &mut Self::CTX,
-    // ) -> pingora::Result<()> {
-    //     upstream_request
-    //         .insert_header("Host", "one.one.one.one")
-    //         .unwrap();
-    //     Ok(())
-    // }
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        let _span = ctx.span.clone().entered();
+        ctx.upstream_latency = ctx.upstream_started.map(|started| started.elapsed());
+        self.record_outcome(ctx, !upstream_response.status.is_server_error());
+        let status = upstream_response.status.as_u16();
+        if !_session.as_ref().retry_buffer_truncated()
+            && self.may_retry_status(ctx, status, &_session.req_header().method, Instant::now())
+        {
+            return Err(Box::new(Error {
+                etype: HTTPStatus(status),
+                esource: Upstream,
+                retry: RetryType::Decided(true),
+                cause: None,
+                context: Some(ImmutStr::Owned(
+                    format!("Backend {} answered {}", ctx.backend.as_deref().unwrap_or_default(), status).into_boxed_str(),
+                )),
+            }));
+        }
+        if let Some(hsts) = &ctx.hsts {
+            upstream_response.insert_header("Strict-Transport-Security", hsts)?;
+        }
+        if let Some(cookie) = &ctx.affinity_cookie {
+            upstream_response.append_header("Set-Cookie", cookie)?;
+        }
+        upstream_response.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        let routes = self.live_config.routes.load();
+        if let Some(actions) = ctx.fully_qualified_upstream.as_deref().and_then(|u| routes.response_headers(u)) {
+            actions.apply_to_response(upstream_response, &TemplateValues::new(_session, ctx))?;
+        }
+        Ok(())
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        let _span = ctx.span.clone().entered();
+        let routes = self.live_config.routes.load();
+        let client_ip = session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip());
+        let trusted = client_ip.is_some_and(|ip| routes.is_trusted_proxy(ip));
+        let tls = session.digest().is_some_and(|d| d.ssl_digest.is_some());
+        set_forwarding_headers(upstream_request, client_ip, tls, trusted)?;
+        if let Some(host) = ctx.fully_qualified_upstream.as_deref().and_then(|u| routes.host_rewrite(u)) {
+            upstream_request.insert_header("Host", host)?;
+        }
+        upstream_request.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        if let Some(actions) = ctx.fully_qualified_upstream.as_deref().and_then(|u| routes.request_headers(u)) {
+            actions.apply_to_request(upstream_request, &TemplateValues::new(session, ctx))?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl BackgroundService for NetIqLoadBalancer {
     async fn start(&self, mut shutdown: ShutdownWatch) {
         log_info!("Starting Consul background service");
-        let rp_config = self.rp_config.clone();
         let (tx, mut rx) = mpsc::channel::<ConsulNodes>(1);
-        let handle = tokio::spawn(async move { ConsulDiscovery::new(rp_config).fetch_nodes(tx).await });
+        let mut handle = self.spawn_discovery(tx.clone());
+        let mut drain_sweep = tokio::time::interval(Duration::from_secs(1));
         loop {
             tokio::select! {
                 val = rx.recv() => {
                     if let Some(new_node) = val {
                             log_info!("New nodes: {new_node:?}");
+                            self.mark_draining(&new_node);
                             self.repopulate_nodes(&new_node);
-                            self.repopulate_balancers(&new_node)
+                            self.repopulate_balancers(&new_node).await
                     }
                 }
+                _ = drain_sweep.tick() => {
+                    self.expire_drained();
+                }
+                _ = self.live_config.discovery_changed.notified() => {
+                    log_info!("Discovery set changed, restarting consul discovery");
+                    handle.abort();
+                    let _ = (&mut handle).await;
+                    while rx.try_recv().is_ok() {}
+                    self.prune_unconfigured();
+                    handle = self.spawn_discovery(tx.clone());
+                }
                 _ = shutdown.changed() => {
                     log_info!("Shutting down (consul background service)...");
                     handle.abort();
@@ -149,41 +387,201 @@ impl BackgroundService for NetIqLoadBalancer {
 }
 
 impl NetIqLoadBalancer {
-    pub fn new(rp_config: RPConfig) -> Self {
-        let auth_verifier = AuthVerifier::new(rp_config.clone());
-        Self {
+    pub fn try_new(rp_config: RPConfig, live_config: LiveConfig, metrics: Metrics) -> anyhow::Result<Self> {
+        let auth_verifier = AuthVerifier::try_new(rp_config)?;
+        Ok(Self {
             nodes: Arc::new(ConsulNodes::new()),
             balancers: Arc::new(LoadBalancers::new()),
+            draining: Arc::new(DrainingUpstreams::new()),
+            client_certs: Arc::new(ClientCerts::new()),
+            health: Arc::new(BackendHealth::new()),
+            outliers: Arc::new(Outliers::new()),
+            retry_budgets: Arc::new(RetryBudgets::new()),
+            in_flight: Arc::new(InFlight::new()),
             auth_verifier,
-            rp_config,
+            live_config,
+            metrics,
+        })
+    }
+
+    /// Picks the pinned backend while it is in the pool, otherwise lets the balancer
+    /// pick. Skips the backends already tried by this request, the ones failing the
+    /// active health check and the ones ejected by outlier detection.
+    fn select_backend(
+        &self,
+        upstream: &str,
+        balancer: &Balancer,
+        key: &[u8],
+        tried: &[String],
+        pinned: Option<&str>,
+    ) -> Option<Backend> {
+        let health = self.health.get(upstream);
+        let outliers = self.outliers.get(upstream);
+        let now = Instant::now();
+        let accept = |backend: &Backend| {
+            let addr = backend.addr.to_string();
+            !tried.contains(&addr)
+                && health.as_ref().and_then(|s| s.get(&addr)).is_none_or(|status| status.healthy)
+                && outliers.as_ref().and_then(|s| s.get(&addr)).is_none_or(|state| !state.is_ejected(now))
+        };
+        if let Some(pinned) = pinned
+            && let Some(backend) = balancer.backends().iter().find(|b| b.addr.to_string() == pinned && accept(b))
+        {
+            return Some(backend.clone());
+        }
+        balancer.select_with(key, &self.in_flight, accept)
+    }
+
+    /// Builds the peer for a selected backend, over TLS when the upstream has TLS
+    /// settings. Without an SNI override the request hostname is sent as SNI.
+    pub fn build_peer(&self, backend: Backend, hostname: &str, tls: Option<&UpstreamTls>) -> pingora::Result<HttpPeer> {
+        let Some(tls) = tls else {
+            return Ok(HttpPeer::new(backend, false, String::new()));
+        };
+        let sni = tls.sni.clone().unwrap_or_else(|| hostname.to_string());
+        let mut peer = HttpPeer::new(backend, true, sni);
+        peer.options.verify_cert = tls.verify != UpstreamTlsVerify::None;
+        peer.options.verify_hostname = tls.verify == UpstreamTlsVerify::Full;
+        peer.options.ca = tls.ca.clone();
+        peer.client_cert_key = match &tls.client_cert {
+            ClientCert::None => None,
+            ClientCert::Static(cert_key) => Some(cert_key.clone()),
+            ClientCert::VaultPki(pki) => match self.client_certs.get(pki) {
+                Some(issued) => Some(issued.cert_key.clone()),
+                None => {
+                    return Err(Box::new(Error {
+                        etype: HTTPStatus(502),
+                        esource: Upstream,
+                        retry: RetryType::Decided(false),
+                        cause: None,
+                        context: Some(ImmutStr::Owned(
+                            format!("Client certificate {} not issued yet", pki.common_name).into_boxed_str(),
+                        )),
+                    }));
+                }
+            },
+        };
+        Ok(peer)
+    }
+
+    fn spawn_discovery(&self, tx: mpsc::Sender<ConsulNodes>) -> JoinHandle<()> {
+        let rp_config = RPConfig::clone(&self.live_config.rp_config.load());
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move { ConsulDiscovery::new(rp_config, metrics).fetch_nodes(tx).await })
+    }
+
+    /// Forgets upstreams that are no longer referenced by the config.
+    fn prune_unconfigured(&self) {
+        let rp_config = self.live_config.rp_config.load();
+        let configured: HashSet<&str> = rp_config.all_upstreams().map(|u| u.upstream.as_str()).collect();
+        self.nodes.retain(|upstream, _| configured.contains(upstream.as_str()));
+        self.balancers.retain(|upstream, _| configured.contains(upstream.as_str()));
+        self.draining.retain(|upstream, _| configured.contains(upstream.as_str()));
+        self.health.retain(|upstream, _| configured.contains(upstream.as_str()));
+        self.outliers.retain(|upstream, _| configured.contains(upstream.as_str()));
+        self.retry_budgets.retain(|upstream, _| configured.contains(upstream.as_str()));
+        // keyed by backend; a request finishing on a pruned backend releases nothing
+        let pooled: HashSet<String> = self
+            .balancers
+            .iter()
+            .flat_map(|balancer| balancer.backends().iter().map(|b| b.addr.to_string()).collect::<Vec<_>>())
+            .collect();
+        self.in_flight.retain(|backend, _| pooled.contains(backend));
+    }
+
+    fn drain_grace(&self) -> Duration {
+        Duration::from_secs(self.live_config.rp_config.load().consul_drain_grace_secs)
+    }
+
+    /// Starts the grace period for upstreams that came back without any node and
+    /// cancels it for the ones that recovered. Until the grace period expires the
+    /// last known nodes keep serving, which rides out short Consul check flaps.
+    fn mark_draining(&self, src: &ConsulNodes) {
+        for entry in src.iter() {
+            let upstream = entry.key();
+            if !entry.value().is_empty() {
+                if self.draining.remove(upstream).is_some() {
+                    log_info!("Upstream {} recovered, draining cancelled", upstream);
+                }
+                continue;
+            }
+            if self.nodes.contains_key(upstream) {
+                if !self.draining.contains_key(upstream) {
+                    log_warn!(
+                        "Upstream {} has no healthy nodes, draining for {:?}",
+                        upstream,
+                        self.drain_grace()
+                    );
+                    self.draining.insert(upstream.clone(), Instant::now());
+                }
+            } else {
+                self.balancers.remove(upstream);
+            }
+        }
+    }
+
+    /// Drops nodes and balancers of upstreams whose grace period is over.
+    fn expire_drained(&self) {
+        let grace = self.drain_grace();
+        let expired: Vec<String> = self
+            .draining
+            .iter()
+            .filter(|entry| entry.value().elapsed() >= grace)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for upstream in expired {
+            log_warn!("Upstream {} drained, removing its nodes and balancer", upstream);
+            self.draining.remove(&upstream);
+            self.nodes.remove(&upstream);
+            self.balancers.remove(&upstream);
+            self.health.remove(&upstream);
+            self.outliers.remove(&upstream);
         }
     }
 
     fn repopulate_nodes(&self, src: &ConsulNodes) {
-        for host in src.iter() {
+        for host in src.iter().filter(|host| !host.value().is_empty()) {
             let host_name = host.key();
             let nodes = host.value().clone();
             self.nodes.insert(host_name.clone(), nodes);
         }
     }
 
-    fn repopulate_balancers(&self, src: &ConsulNodes) {
+    async fn repopulate_balancers(&self, src: &ConsulNodes) {
         for entry in src.iter() {
-            if let Some(balancer) = self.create_balancer(entry.value()) {
+            let algorithm = self.live_config.routes.load().load_balancing(entry.key());
+            if let Some(balancer) = Self::create_balancer(entry.value(), &algorithm).await {
+                let pool: Vec<String> = balancer.backends().iter().map(|b| b.addr.to_string()).collect();
+                self.prune_outliers(entry.key(), &pool);
                 self.balancers.insert(entry.key().clone(), balancer);
             }
         }
     }
 
-    fn create_balancer(&self, nodes: &[ConsulNode]) -> Option<LoadBalancer<RoundRobin>> {
-        let endpoints: Vec<String> = nodes
-            .iter()
-            .map(|cn| format!("{}:{}", cn.address, cn.service_port))
-            .collect();
-        LoadBalancer::<RoundRobin>::try_from_iter(endpoints).ok()
+    /// Builds a balancer whose backends carry the Consul-derived node weights.
+    /// A node with weight 0 is only picked as a fallback, unless every node has
+    /// weight 0, in which case all of them are treated equally.
+    async fn create_balancer(nodes: &[ConsulNode], algorithm: &LoadBalancing) -> Option<Balancer> {
+        let all_zero = nodes.iter().all(|cn| cn.weight == 0);
+        let mut backends = BTreeSet::new();
+        for cn in nodes {
+            let weight = if all_zero { 1 } else { usize::from(cn.weight) };
+            match Backend::new_with_weight(&format!("{}:{}", cn.address, cn.service_port), weight) {
+                Ok(backend) => {
+                    backends.insert(backend);
+                }
+                Err(e) => {
+                    log_error!("Skipping node {}:{} : {}", cn.address, cn.service_port, e);
+                }
+            }
+        }
+        if backends.is_empty() {
+            return None;
+        }
+        Balancer::build(backends, algorithm).await
     }
 
-    fn get_host(&self, session: &mut Session) -> Option<String> {
+    pub fn get_host(&self, session: &mut Session) -> Option<String> {
         session
             .get_header("Host")
             .and_then(|h| h.to_str().ok())
@@ -191,12 +589,50 @@ impl NetIqLoadBalancer {
             .map(|s| s.to_string())
             .or_else(|| session.req_header().uri.host().map(|s| s.to_string()))
     }
+}
 
-    fn resolve_upstream(&self, hostname: &str) -> Option<UpstreamDetails> {
-        self.rp_config
-            .host_to_upstream
-            .iter()
-            .find(|(k, _)| hostname.contains(k.as_str()))
-            .map(|(_, v)| v.clone())
+/// Sends a response rproxy makes itself, carrying the request id like proxied ones.
+pub async fn respond(session: &mut Session, ctx: &Context, mut resp: ResponseHeader, body: Bytes) -> pingora::Result<()> {
+    resp.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+    if body.is_empty() {
+        return session.write_response_header(Box::new(resp), true).await;
+    }
+    resp.set_content_length(body.len())?;
+    session.write_response_header(Box::new(resp), false).await?;
+    session.write_response_body(Some(body), true).await
+}
+
+/// Request id sent by the client, unless it could break a log line or header.
+fn accepted_request_id(req: &RequestHeader) -> Option<String> {
+    let id = req.headers.get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic());
+    valid.then(|| id.to_string())
+}
+
+/// Random UUID (version 4).
+fn new_request_id() -> String {
+    // version nibble 4, variant bits 10
+    let bits = rand::random::<u128>() & !(0xf_u128 << 76 | 0x3_u128 << 62) | 0x4_u128 << 76 | 0x2_u128 << 62;
+    let hex = format!("{bits:032x}");
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Same host and path on the TLS listener, the port is left out when it is 443.
+fn https_location(hostname: &str, tls_port: u64, path: &str) -> String {
+    if tls_port == 443 {
+        format!("https://{hostname}{path}")
+    } else {
+        format!("https://{hostname}:{tls_port}{path}")
+    }
+}
+
+fn hsts_value(hsts: &HstsConfig) -> String {
+    let mut value = format!("max-age={}", hsts.max_age);
+    if hsts.include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+    if hsts.preload {
+        value.push_str("; preload");
     }
+    value
 }
@@ -1,7 +1,39 @@
+use super::{accepted_request_id, hsts_value, https_location, new_request_id};
+use pingora::http::ResponseHeader;
+use pingora::prelude::{ProxyHttp, Session};
 use pingora::lb::{Backend, Backends, LoadBalancer};
 use pingora::prelude::RoundRobin;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use pingora::lb::discovery::Static;
+use crate::config::{LoadBalancing, PlainHttp, RPConfig, UpstreamDetails, UpstreamTlsVerify, VaultPkiConfig};
+use crate::routing::RouteTable;
+use crate::structs::{
+    AuthVerifier, Context, BackendHealth, ClientCert, ClientCerts, ConsulEntryRaw, ConsulNode, ConsulNodes, DrainingUpstreams,
+    HealthStatus, InFlight, IssuedClientCert, LiveConfig, LoadBalancers, Metrics, NetIqLoadBalancer, OutlierState, Outliers, RetryBudgets, UpstreamTls,
+};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{NameType, Ssl, SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509, X509Builder, X509Name, X509NameBuilder};
+use pingora_core::connectors::TransportConnector;
+use pingora_core::prelude::HttpPeer;
+use pingora_core::protocols::Stream;
+use pingora_core::tls::tokio_ssl::SslStream;
+use pingora_core::utils::tls::CertKey;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
 
 #[tokio::test]
 async fn weighted_backends_are_selected_proportionally() {
@@ -32,4 +64,779 @@ async fn weighted_backends_are_selected_proportionally() {
     assert!(counts.entry("127.0.0.1:10002".to_string()).or_default() > &mut 6500);
     println!("{counts:#?}");
 
-}
\ No newline at end of file
+}
+const HEALTH_RESPONSE: &str = r#"[
+  {
+    "Node": {"Node": "node-a", "Address": "10.0.0.1"},
+    "Service": {"Service": "pipeline-config-service", "Address": "", "Port": 8080},
+    "Checks": [
+      {"CheckID": "serfHealth", "Status": "passing", "Output": "Agent alive and reachable"},
+      {"CheckID": "is leader", "Status": "passing", "Output": "{\"data\":true}\n"}
+    ]
+  },
+  {
+    "Node": {"Node": "node-b", "Address": "10.0.0.2"},
+    "Service": {"Service": "pipeline-config-service", "Address": "", "Port": 8080},
+    "Checks": [
+      {"CheckID": "serfHealth", "Status": "passing", "Output": "Agent alive and reachable"},
+      {"CheckID": "is leader", "Status": "passing", "Output": "{\"data\":false}\n"}
+    ]
+  }
+]"#;
+
+fn nodes_from_health_response(weighted: bool, weight_on_true: u16, weight_on_false: u16) -> Vec<ConsulNode> {
+    let raws: Vec<ConsulEntryRaw> = serde_json::from_str(HEALTH_RESPONSE).expect("valid health response");
+    raws.into_iter()
+        .map(|raw| ConsulNode::from_raw(raw, weighted, "is leader", "{\"data\":true}\n", weight_on_true, weight_on_false))
+        .collect()
+}
+
+async fn select_counts(nodes: &[ConsulNode], rounds: usize) -> BTreeMap<String, usize> {
+    let lb = NetIqLoadBalancer::create_balancer(nodes, &LoadBalancing::RoundRobin).await.expect("balancer should be built");
+    let mut counts = BTreeMap::new();
+    for _ in 0..rounds {
+        let backend = lb.select_with(b"", &InFlight::new(), |_| true).expect("backend should be selected");
+        *counts.entry(backend.to_string()).or_insert(0usize) += 1;
+    }
+    counts
+}
+
+#[tokio::test]
+async fn create_balancer_honors_weights_from_consul_health_response() {
+    let nodes = nodes_from_health_response(true, 9, 1);
+
+    let counts = select_counts(&nodes, 10_000).await;
+
+    assert_eq!(counts.get("10.0.0.1:8080"), Some(&9_000));
+    assert_eq!(counts.get("10.0.0.2:8080"), Some(&1_000));
+}
+
+#[tokio::test]
+async fn create_balancer_spreads_evenly_when_not_weighted() {
+    let nodes = nodes_from_health_response(false, 9, 1);
+
+    let counts = select_counts(&nodes, 10_000).await;
+
+    assert_eq!(counts.get("10.0.0.1:8080"), Some(&5_000));
+    assert_eq!(counts.get("10.0.0.2:8080"), Some(&5_000));
+}
+
+#[tokio::test]
+async fn create_balancer_sends_everything_to_leader_when_follower_weight_is_zero() {
+    let nodes = nodes_from_health_response(true, 1, 0);
+
+    let counts = select_counts(&nodes, 1_000).await;
+
+    assert_eq!(counts.get("10.0.0.1:8080"), Some(&1_000));
+    assert_eq!(counts.get("10.0.0.2:8080"), None);
+}
+
+#[tokio::test]
+async fn create_balancer_treats_all_zero_weights_equally() {
+    let nodes = nodes_from_health_response(true, 0, 0);
+
+    let counts = select_counts(&nodes, 1_000).await;
+
+    assert_eq!(counts.get("10.0.0.1:8080"), Some(&500));
+    assert_eq!(counts.get("10.0.0.2:8080"), Some(&500));
+}
+
+#[tokio::test]
+async fn create_balancer_returns_none_for_empty_node_list() {
+    assert!(NetIqLoadBalancer::create_balancer(&[], &LoadBalancing::RoundRobin).await.is_none());
+}
+
+impl NetIqLoadBalancer {
+    pub fn new_for_tests(rp_config: RPConfig) -> Self {
+        Self {
+            nodes: Arc::new(ConsulNodes::new()),
+            balancers: Arc::new(LoadBalancers::new()),
+            draining: Arc::new(DrainingUpstreams::new()),
+            client_certs: Arc::new(ClientCerts::new()),
+            health: Arc::new(BackendHealth::new()),
+            outliers: Arc::new(Outliers::new()),
+            retry_budgets: Arc::new(RetryBudgets::new()),
+            in_flight: Arc::new(InFlight::new()),
+            auth_verifier: AuthVerifier::new_for_tests(rp_config.clone()),
+            live_config: LiveConfig::new(PathBuf::new(), rp_config, RouteTable::default()),
+            metrics: Metrics::try_new().expect("metrics should register"),
+        }
+    }
+}
+
+fn update(upstream: &str, nodes: Vec<ConsulNode>) -> ConsulNodes {
+    let dash = ConsulNodes::new();
+    dash.insert(upstream.to_string(), nodes);
+    dash
+}
+
+async fn apply(lb: &NetIqLoadBalancer, src: &ConsulNodes) {
+    lb.mark_draining(src);
+    lb.repopulate_nodes(src);
+    lb.repopulate_balancers(src).await;
+}
+
+fn lb_with_grace(secs: u64) -> NetIqLoadBalancer {
+    NetIqLoadBalancer::new_for_tests(RPConfig {
+        consul_drain_grace_secs: secs,
+        ..RPConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn empty_update_starts_draining_and_keeps_last_known_nodes() {
+    let lb = lb_with_grace(60);
+    apply(&lb, &update("svc", nodes_from_health_response(false, 1, 1))).await;
+
+    apply(&lb, &update("svc", vec![])).await;
+    lb.expire_drained();
+
+    assert!(lb.draining.contains_key("svc"));
+    assert_eq!(lb.nodes.get("svc").map(|n| n.len()), Some(2));
+    assert!(lb.balancers.contains_key("svc"));
+}
+
+#[tokio::test]
+async fn drained_upstream_is_removed_after_grace_period() {
+    let lb = lb_with_grace(0);
+    apply(&lb, &update("svc", nodes_from_health_response(false, 1, 1))).await;
+
+    apply(&lb, &update("svc", vec![])).await;
+    lb.expire_drained();
+
+    assert!(!lb.draining.contains_key("svc"));
+    assert!(!lb.nodes.contains_key("svc"));
+    assert!(!lb.balancers.contains_key("svc"));
+}
+
+#[tokio::test]
+async fn recovered_upstream_cancels_draining() {
+    let lb = lb_with_grace(60);
+    apply(&lb, &update("svc", nodes_from_health_response(false, 1, 1))).await;
+    apply(&lb, &update("svc", vec![])).await;
+
+    let mut recovered = nodes_from_health_response(false, 1, 1);
+    recovered.truncate(1);
+    apply(&lb, &update("svc", recovered)).await;
+
+    assert!(!lb.draining.contains_key("svc"));
+    assert_eq!(lb.nodes.get("svc").map(|n| n.len()), Some(1));
+    assert!(lb.balancers.contains_key("svc"));
+}
+
+#[tokio::test]
+async fn empty_update_for_unknown_upstream_is_not_drained() {
+    let lb = lb_with_grace(60);
+
+    apply(&lb, &update("svc", vec![])).await;
+
+    assert!(!lb.draining.contains_key("svc"));
+    assert!(!lb.nodes.contains_key("svc"));
+    assert!(!lb.balancers.contains_key("svc"));
+}
+
+#[tokio::test]
+async fn prune_unconfigured_forgets_upstreams_removed_from_config() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig {
+        host_to_upstream: [("kibana".to_string(), serde_json::from_value(serde_json::json!({"upstream": "svc"})).expect("details"))]
+            .into_iter()
+            .collect(),
+        ..RPConfig::default()
+    });
+    apply(&lb, &update("svc", nodes_from_health_response(false, 1, 1))).await;
+    apply(&lb, &update("gone", nodes_from_health_response(false, 1, 1))).await;
+    lb.draining.insert("gone".to_string(), std::time::Instant::now());
+    let kept = lb.balancers.get("svc").expect("balancer").backends().iter().next().expect("backend").addr.to_string();
+    let gone = "192.0.2.1:8080".to_string();
+    lb.in_flight.insert(kept.clone(), 1);
+    lb.in_flight.insert(gone.clone(), 1);
+    lb.count_request("svc", std::time::Instant::now());
+    lb.count_request("gone", std::time::Instant::now());
+
+    lb.prune_unconfigured();
+
+    assert!(lb.nodes.contains_key("svc"));
+    assert!(lb.balancers.contains_key("svc"));
+    assert!(!lb.nodes.contains_key("gone"));
+    assert!(!lb.balancers.contains_key("gone"));
+    assert!(!lb.draining.contains_key("gone"));
+    assert!(lb.retry_budgets.contains_key("svc"));
+    assert!(!lb.retry_budgets.contains_key("gone"));
+    assert!(lb.in_flight.contains_key(&kept));
+    assert!(!lb.in_flight.contains_key(&gone), "not a backend of a configured upstream");
+}
+
+struct TestCa {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+fn test_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("p256 group");
+    PKey::from_ec_key(EcKey::generate(&group).expect("ec key")).expect("pkey")
+}
+
+fn x509_name(cn: &str) -> X509Name {
+    let mut name = X509NameBuilder::new().expect("name builder");
+    name.append_entry_by_nid(Nid::COMMONNAME, cn).expect("common name");
+    name.build()
+}
+
+fn x509_builder(cn: &str, key: &PKey<Private>) -> X509Builder {
+    let mut builder = X509::builder().expect("x509 builder");
+    builder.set_version(2).expect("version");
+    builder.set_subject_name(&x509_name(cn)).expect("subject");
+    builder.set_pubkey(key).expect("pubkey");
+    builder.set_not_before(&Asn1Time::days_from_now(0).expect("now")).expect("not before");
+    builder.set_not_after(&Asn1Time::days_from_now(1).expect("tomorrow")).expect("not after");
+    builder
+}
+
+impl TestCa {
+    fn new(cn: &str) -> Self {
+        let key = test_key();
+        let mut builder = x509_builder(cn, &key);
+        builder.set_issuer_name(&x509_name(cn)).expect("issuer");
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().expect("ca"))
+            .expect("ca extension");
+        builder.sign(&key, MessageDigest::sha256()).expect("sign");
+        Self { cert: builder.build(), key }
+    }
+
+    fn issue(&self, cn: &str) -> (X509, PKey<Private>) {
+        let key = test_key();
+        let mut builder = x509_builder(cn, &key);
+        builder.set_issuer_name(self.cert.subject_name()).expect("issuer");
+        let san = SubjectAlternativeName::new()
+            .dns(cn)
+            .build(&builder.x509v3_context(Some(&self.cert), None))
+            .expect("san");
+        builder.append_extension(san).expect("san extension");
+        builder.sign(&self.key, MessageDigest::sha256()).expect("sign");
+        (builder.build(), key)
+    }
+}
+
+/// SNI and client certificate CN the backend saw, or why the handshake failed.
+type Handshake = Result<(Option<String>, Option<String>), String>;
+
+/// Local TLS backend serving a `backend.internal` certificate issued by `ca`,
+/// requiring a client certificate issued by `client_ca` when one is given.
+async fn tls_backend(ca: &TestCa, client_ca: Option<&X509>) -> (SocketAddr, mpsc::Receiver<Handshake>) {
+    let (cert, key) = ca.issue("backend.internal");
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("acceptor");
+    acceptor.set_certificate(&cert).expect("server cert");
+    acceptor.set_private_key(&key).expect("server key");
+    if let Some(client_ca) = client_ca {
+        let mut store = X509StoreBuilder::new().expect("store");
+        store.add_cert(client_ca.clone()).expect("client ca");
+        acceptor.set_verify_cert_store(store.build()).expect("verify store");
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = acceptor.build();
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind backend");
+    let addr = listener.local_addr().expect("backend addr");
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            let ssl = Ssl::new(acceptor.context()).expect("ssl");
+            let mut stream = SslStream::new(ssl, tcp).expect("ssl stream");
+            let handshake = match Pin::new(&mut stream).accept().await {
+                Ok(()) => {
+                    let ssl = stream.ssl();
+                    let sni = ssl.servername(NameType::HOST_NAME).map(str::to_string);
+                    let client_cn = ssl.peer_certificate().and_then(|c| {
+                        c.subject_name()
+                            .entries_by_nid(Nid::COMMONNAME)
+                            .next()
+                            .and_then(|e| e.data().as_utf8().ok())
+                            .map(|s| s.to_string())
+                    });
+                    Ok((sni, client_cn))
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            let _ = tx.send(handshake).await;
+        }
+    });
+    (addr, rx)
+}
+
+fn upstream_tls(verify: UpstreamTlsVerify, ca: Option<&TestCa>, client_cert: ClientCert) -> UpstreamTls {
+    UpstreamTls {
+        sni: None,
+        verify,
+        ca: ca.map(|ca| Arc::new(vec![ca.cert.clone()].into_boxed_slice())),
+        client_cert,
+    }
+}
+
+async fn connect(peer: &HttpPeer) -> pingora::Result<()> {
+    TransportConnector::new(None).new_stream(peer).await.map(|_| ())
+}
+
+async fn next_handshake(rx: &mut mpsc::Receiver<Handshake>) -> Handshake {
+    timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("backend saw a handshake")
+        .expect("backend is running")
+}
+
+fn backend(addr: SocketAddr) -> Backend {
+    Backend::new(&addr.to_string()).expect("backend address")
+}
+
+#[test]
+fn plaintext_upstream_gets_plaintext_peer() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+
+    let peer = lb.build_peer(backend("127.0.0.1:8080".parse().expect("addr")), "portal.example.com", None).expect("peer");
+
+    assert!(!peer.is_tls());
+}
+
+#[tokio::test]
+async fn tls_upstream_is_verified_against_ca_bundle_and_request_hostname() {
+    let ca = TestCa::new("upstream-ca");
+    let (addr, mut handshakes) = tls_backend(&ca, None).await;
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let tls = upstream_tls(UpstreamTlsVerify::Full, Some(&ca), ClientCert::None);
+
+    let peer = lb.build_peer(backend(addr), "backend.internal", Some(&tls)).expect("peer");
+    connect(&peer).await.expect("verified handshake");
+
+    assert_eq!(next_handshake(&mut handshakes).await, Ok((Some("backend.internal".into()), None)));
+}
+
+#[tokio::test]
+async fn verify_modes_control_hostname_and_chain_checks() {
+    let ca = TestCa::new("upstream-ca");
+    let (addr, _handshakes) = tls_backend(&ca, None).await;
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let peer = |tls: &UpstreamTls| lb.build_peer(backend(addr), "portal.example.com", Some(tls)).expect("peer");
+
+    let full = upstream_tls(UpstreamTlsVerify::Full, Some(&ca), ClientCert::None);
+    assert!(connect(&peer(&full)).await.is_err(), "hostname does not match the certificate");
+
+    let chain = upstream_tls(UpstreamTlsVerify::Chain, Some(&ca), ClientCert::None);
+    assert!(connect(&peer(&chain)).await.is_ok());
+
+    let untrusted = upstream_tls(UpstreamTlsVerify::Chain, None, ClientCert::None);
+    assert!(connect(&peer(&untrusted)).await.is_err(), "test CA is not a system root");
+
+    let none = upstream_tls(UpstreamTlsVerify::None, None, ClientCert::None);
+    assert!(connect(&peer(&none)).await.is_ok());
+}
+
+#[tokio::test]
+async fn sni_override_replaces_request_hostname() {
+    let ca = TestCa::new("upstream-ca");
+    let (addr, mut handshakes) = tls_backend(&ca, None).await;
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let tls = UpstreamTls {
+        sni: Some("backend.internal".into()),
+        ..upstream_tls(UpstreamTlsVerify::Full, Some(&ca), ClientCert::None)
+    };
+
+    let peer = lb.build_peer(backend(addr), "portal.example.com", Some(&tls)).expect("peer");
+    connect(&peer).await.expect("verified handshake");
+
+    assert_eq!(next_handshake(&mut handshakes).await, Ok((Some("backend.internal".into()), None)));
+}
+
+#[tokio::test]
+async fn client_certificate_is_presented_for_mtls() {
+    let ca = TestCa::new("upstream-ca");
+    let client_ca = TestCa::new("client-ca");
+    let (addr, mut handshakes) = tls_backend(&ca, Some(&client_ca.cert)).await;
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let (cert, key) = client_ca.issue("rproxy-client");
+    let with_cert = upstream_tls(
+        UpstreamTlsVerify::Full,
+        Some(&ca),
+        ClientCert::Static(Arc::new(CertKey::new(vec![cert], key))),
+    );
+    let without_cert = upstream_tls(UpstreamTlsVerify::Full, Some(&ca), ClientCert::None);
+
+    let peer = lb.build_peer(backend(addr), "backend.internal", Some(&with_cert)).expect("peer");
+    connect(&peer).await.expect("mtls handshake");
+    assert_eq!(
+        next_handshake(&mut handshakes).await,
+        Ok((Some("backend.internal".into()), Some("rproxy-client".into())))
+    );
+
+    let peer = lb.build_peer(backend(addr), "backend.internal", Some(&without_cert)).expect("peer");
+    let _ = connect(&peer).await;
+    assert!(next_handshake(&mut handshakes).await.is_err());
+}
+
+#[tokio::test]
+async fn vault_pki_client_certificate_is_used_once_issued() {
+    let ca = TestCa::new("upstream-ca");
+    let client_ca = TestCa::new("client-ca");
+    let (addr, mut handshakes) = tls_backend(&ca, Some(&client_ca.cert)).await;
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let pki = VaultPkiConfig {
+        mount: "pki".into(),
+        role: "rproxy".into(),
+        common_name: "rproxy-pki".into(),
+        ttl: String::new(),
+    };
+    let tls = upstream_tls(UpstreamTlsVerify::Full, Some(&ca), ClientCert::VaultPki(pki.clone()));
+
+    assert!(lb.build_peer(backend(addr), "backend.internal", Some(&tls)).is_err());
+
+    let (cert, key) = client_ca.issue("rproxy-pki");
+    lb.client_certs.insert(
+        pki,
+        IssuedClientCert {
+            cert_key: Arc::new(CertKey::new(vec![cert], key)),
+            renew_at: std::time::Instant::now(),
+        },
+    );
+    let peer = lb.build_peer(backend(addr), "backend.internal", Some(&tls)).expect("peer");
+    connect(&peer).await.expect("mtls handshake");
+
+    assert_eq!(
+        next_handshake(&mut handshakes).await,
+        Ok((Some("backend.internal".into()), Some("rproxy-pki".into())))
+    );
+}
+
+#[test]
+fn https_location_keeps_host_and_path_and_drops_default_port() {
+    assert_eq!(https_location("kibana.example.com", 443, "/app/home?x=1"), "https://kibana.example.com/app/home?x=1");
+    assert_eq!(https_location("kibana.example.com", 8443, "/"), "https://kibana.example.com:8443/");
+}
+
+#[test]
+fn hsts_value_lists_enabled_directives() {
+    let hsts = |value: serde_json::Value| hsts_value(&serde_json::from_value(value).expect("valid hsts"));
+
+    assert_eq!(hsts(serde_json::json!({})), "max-age=31536000");
+    assert_eq!(
+        hsts(serde_json::json!({"max_age": 600, "include_subdomains": true, "preload": true})),
+        "max-age=600; includeSubDomains; preload"
+    );
+}
+
+#[test]
+fn plain_http_defaults_to_301_redirect() {
+    let details = |value: serde_json::Value| -> UpstreamDetails { serde_json::from_value(value).expect("valid upstream") };
+
+    assert_eq!(details(serde_json::json!({"upstream": "a"})).plain_http, PlainHttp::Redirect301);
+    assert_eq!(details(serde_json::json!({"upstream": "a", "plain_http": "redirect_308"})).plain_http, PlainHttp::Redirect308);
+    assert_eq!(details(serde_json::json!({"upstream": "a", "plain_http": "proxy"})).plain_http, PlainHttp::Proxy);
+}
+
+const KIBANA_GET: &[u8] = b"GET /app/home?x=1 HTTP/1.1\r\nHost: kibana.example.com\r\n\r\n";
+
+/// Proxy routing `kibana.example.com` to the upstream `svc` configured with `details`,
+/// its backends already discovered.
+async fn kibana_lb(mut details: serde_json::Value) -> NetIqLoadBalancer {
+    details["upstream"] = "svc".into();
+    let rp_config = RPConfig {
+        tls_port: 8443,
+        jwks_url: "https://idp.example.com/keys".to_string(),
+        id_token_issuer: "https://idp.example.com".to_string(),
+        host_to_upstream: HashMap::from([(
+            "kibana.example.com".to_string(),
+            serde_json::from_value(details).expect("valid upstream details"),
+        )]),
+        ..RPConfig::default()
+    };
+    let lb = NetIqLoadBalancer::new_for_tests(rp_config.clone());
+    lb.live_config.routes.store(Arc::new(RouteTable::compile(&rp_config).expect("routes should compile")));
+    apply(&lb, &update("svc", nodes_from_health_response(false, 1, 1))).await;
+    lb
+}
+
+/// Client and rproxy ends of a plain connection, the client having sent `request`.
+async fn plain_connection(request: &[u8]) -> (DuplexStream, Stream) {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    client.write_all(request).await.expect("request sent");
+    (client, Box::new(server))
+}
+
+/// Client and rproxy ends of a TLS connection to `kibana.example.com`, the client
+/// having sent `request`.
+async fn tls_connection(request: &[u8]) -> (SslStream<DuplexStream>, Stream) {
+    let (cert, key) = TestCa::new("test-ca").issue("kibana.example.com");
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("acceptor");
+    acceptor.set_certificate(&cert).expect("server cert");
+    acceptor.set_private_key(&key).expect("server key");
+    let acceptor = acceptor.build();
+    let mut connector = SslConnector::builder(SslMethod::tls()).expect("connector");
+    connector.set_verify(SslVerifyMode::NONE);
+    let ssl = connector
+        .build()
+        .configure()
+        .expect("client config")
+        .into_ssl("kibana.example.com")
+        .expect("client ssl");
+
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let mut client = SslStream::new(ssl, client).expect("client stream");
+    let (connected, accepted) = tokio::join!(
+        Pin::new(&mut client).connect(),
+        pingora_core::protocols::tls::server::handshake(&acceptor, server)
+    );
+    connected.expect("client handshake");
+    client.write_all(request).await.expect("request sent");
+    (client, Box::new(accepted.expect("server handshake")))
+}
+
+/// Reads the request on rproxy's end of `stream` and runs it through `request_filter`;
+/// true when rproxy answered it itself.
+async fn request_filter(lb: &NetIqLoadBalancer, stream: Stream) -> (Session, Context, bool) {
+    let mut session = Session::new_h1(stream);
+    assert!(session.read_request().await.expect("request read"));
+    let mut ctx = lb.new_ctx();
+    let answered = lb.request_filter(&mut session, &mut ctx).await.expect("request filtered");
+    (session, ctx, answered)
+}
+
+/// Status line and headers rproxy answered with, lowercased.
+async fn answer(session: Session, client: &mut (impl AsyncRead + Unpin)) -> String {
+    drop(session);
+    let mut head = vec![];
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match timeout(Duration::from_secs(5), client.read(&mut buf)).await.expect("answer in time") {
+            Ok(0) | Err(_) => break,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+    String::from_utf8_lossy(&head).to_lowercase()
+}
+
+/// Headers `response_filter` puts on a backend's 200.
+async fn filtered_response(lb: &NetIqLoadBalancer, session: &mut Session, ctx: &mut Context) -> ResponseHeader {
+    let mut resp = ResponseHeader::build(200, None).expect("valid response");
+    lb.response_filter(session, &mut resp, ctx).await.expect("response filtered");
+    resp
+}
+
+#[tokio::test]
+async fn plain_requests_are_redirected_to_the_tls_port() {
+    for (plain_http, status) in [("redirect_301", "301"), ("redirect_308", "308")] {
+        let lb = kibana_lb(serde_json::json!({"plain_http": plain_http})).await;
+        let (mut client, stream) = plain_connection(KIBANA_GET).await;
+
+        let (session, ctx, answered) = request_filter(&lb, stream).await;
+
+        assert!(answered, "{plain_http}");
+        let answer = answer(session, &mut client).await;
+        assert!(answer.starts_with(&format!("http/1.1 {status} ")), "{answer}");
+        assert!(answer.contains("\r\nlocation: https://kibana.example.com:8443/app/home?x=1\r\n"), "{answer}");
+        assert!(answer.contains(&format!("\r\nx-request-id: {}\r\n", ctx.request_id)), "{answer}");
+    }
+}
+
+#[tokio::test]
+async fn plain_requests_are_proxied_without_hsts_when_configured() {
+    let lb = kibana_lb(serde_json::json!({"plain_http": "proxy", "hsts": {"max_age": 600}})).await;
+    let (_client, stream) = plain_connection(KIBANA_GET).await;
+
+    let (mut session, mut ctx, answered) = request_filter(&lb, stream).await;
+
+    assert!(!answered, "passed on to the upstream");
+    assert_eq!(ctx.fully_qualified_upstream.as_deref(), Some("svc"));
+    let resp = filtered_response(&lb, &mut session, &mut ctx).await;
+    assert!(resp.headers.get("Strict-Transport-Security").is_none());
+}
+
+#[tokio::test]
+async fn tls_responses_carry_hsts() {
+    let lb = kibana_lb(serde_json::json!({"hsts": {"max_age": 600}})).await;
+    let (_client, stream) = tls_connection(KIBANA_GET).await;
+
+    let (mut session, mut ctx, answered) = request_filter(&lb, stream).await;
+
+    assert!(!answered, "tls requests are not redirected");
+    let resp = filtered_response(&lb, &mut session, &mut ctx).await;
+    assert_eq!(
+        resp.headers.get("Strict-Transport-Security").map(|v| v.as_bytes()),
+        Some(b"max-age=600".as_slice())
+    );
+}
+
+#[tokio::test]
+async fn unrouted_hosts_get_a_404_with_the_request_id() {
+    let lb = kibana_lb(serde_json::json!({"plain_http": "proxy"})).await;
+    let request = b"GET / HTTP/1.1\r\nHost: grafana.example.com\r\nX-Request-Id: client-id-1\r\n\r\n";
+    let (mut client, stream) = plain_connection(request).await;
+
+    let (session, _, answered) = request_filter(&lb, stream).await;
+
+    assert!(answered);
+    let answer = answer(session, &mut client).await;
+    assert!(answer.starts_with("http/1.1 404 "), "{answer}");
+    assert!(answer.contains("\r\nx-request-id: client-id-1\r\n"), "{answer}");
+}
+
+#[tokio::test]
+async fn no_backend_answer_keeps_the_routed_host_and_upstream() {
+    let lb = kibana_lb(serde_json::json!({})).await;
+    lb.balancers.remove("svc");
+    let (_client, stream) = tls_connection(KIBANA_GET).await;
+
+    let (session, ctx, answered) = request_filter(&lb, stream).await;
+
+    assert!(answered);
+    assert_eq!(session.response_written().map(|r| r.status.as_u16()), Some(503));
+    assert_eq!(ctx.hostname.as_deref(), Some("kibana.example.com"));
+    assert_eq!(ctx.fully_qualified_upstream.as_deref(), Some("svc"));
+}
+
+#[tokio::test]
+async fn answers_of_request_filter_are_counted_by_route() {
+    let lb = kibana_lb(serde_json::json!({"plain_http": "redirect_301"})).await;
+    let unrouted = b"GET / HTTP/1.1\r\nHost: grafana.example.com\r\n\r\n";
+    let count = |route: &str, upstream: &str, status: &str| lb.metrics.requests.with_label_values(&[route, upstream, status]).get();
+
+    for request in [unrouted.as_slice(), KIBANA_GET] {
+        let (_client, stream) = plain_connection(request).await;
+        let (mut session, mut ctx, answered) = request_filter(&lb, stream).await;
+        assert!(answered);
+        lb.logging(&mut session, None, &mut ctx).await;
+    }
+    lb.balancers.remove("svc");
+    let (_client, stream) = tls_connection(KIBANA_GET).await;
+    let (mut session, mut ctx, answered) = request_filter(&lb, stream).await;
+    assert!(answered);
+    lb.logging(&mut session, None, &mut ctx).await;
+
+    assert_eq!(count("unmatched", "", "4xx"), 1, "client hosts are not labels");
+    assert_eq!(count("kibana.example.com", "svc", "3xx"), 1);
+    assert_eq!(count("kibana.example.com", "svc", "5xx"), 1);
+}
+
+#[tokio::test]
+async fn sso_responses_carry_the_request_id() {
+    let lb = kibana_lb(serde_json::json!({
+        "plain_http": "proxy",
+        "sso_req": true,
+        "redirect_url": "https://kibana.example.com/oauth/callback"
+    }))
+    .await;
+    let callback = b"GET /oauth/callback?code=abc&state=forged HTTP/1.1\r\nHost: kibana.example.com\r\n\r\n";
+
+    for (request, status) in [(KIBANA_GET, "302"), (callback.as_slice(), "403")] {
+        let (mut client, stream) = plain_connection(request).await;
+        let (session, ctx, answered) = request_filter(&lb, stream).await;
+
+        assert!(answered, "{status}");
+        let answer = answer(session, &mut client).await;
+        assert!(answer.starts_with(&format!("http/1.1 {status} ")), "{answer}");
+        assert!(answer.contains(&format!("\r\nx-request-id: {}\r\n", ctx.request_id)), "{answer}");
+    }
+}
+
+#[tokio::test]
+async fn select_backend_skips_backends_failing_active_checks() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let balancer = NetIqLoadBalancer::create_balancer(&nodes_from_health_response(false, 1, 1), &LoadBalancing::RoundRobin)
+        .await
+        .expect("balancer should be built");
+    let unhealthy = HealthStatus { healthy: false, ..HealthStatus::default() };
+    lb.health.insert("svc".to_string(), [("10.0.0.1:8080".to_string(), unhealthy.clone())].into());
+
+    for _ in 0..10 {
+        let backend = lb.select_backend("svc", &balancer, b"", &[], None).expect("healthy backend left");
+        assert_eq!(backend.to_string(), "10.0.0.2:8080");
+    }
+
+    lb.health.alter("svc", |_, mut statuses| {
+        statuses.insert("10.0.0.2:8080".to_string(), unhealthy.clone());
+        statuses
+    });
+    assert!(lb.select_backend("svc", &balancer, b"", &[], None).is_none());
+}
+
+#[tokio::test]
+async fn select_backend_skips_ejected_backends() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let balancer = NetIqLoadBalancer::create_balancer(&nodes_from_health_response(false, 1, 1), &LoadBalancing::RoundRobin)
+        .await
+        .expect("balancer should be built");
+    let ejected = OutlierState {
+        ejected_until: Some(std::time::Instant::now() + Duration::from_secs(60)),
+        ..OutlierState::default()
+    };
+    lb.outliers.insert("svc".to_string(), [("10.0.0.2:8080".to_string(), ejected)].into());
+
+    for _ in 0..10 {
+        let backend = lb.select_backend("svc", &balancer, b"", &[], None).expect("backend left");
+        assert_eq!(backend.to_string(), "10.0.0.1:8080");
+    }
+}
+
+#[tokio::test]
+async fn select_backend_does_not_pick_a_tried_backend_again() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let balancer = NetIqLoadBalancer::create_balancer(&nodes_from_health_response(false, 1, 1), &LoadBalancing::RoundRobin)
+        .await
+        .expect("balancer should be built");
+
+    let tried = vec!["10.0.0.1:8080".to_string()];
+    for _ in 0..10 {
+        let backend = lb.select_backend("svc", &balancer, b"", &tried, None).expect("untried backend left");
+        assert_eq!(backend.to_string(), "10.0.0.2:8080");
+    }
+
+    let tried = vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()];
+    assert!(lb.select_backend("svc", &balancer, b"", &tried, None).is_none());
+}
+
+#[tokio::test]
+async fn select_backend_honors_the_pinned_backend_while_it_is_usable() {
+    let lb = NetIqLoadBalancer::new_for_tests(RPConfig::default());
+    let balancer = NetIqLoadBalancer::create_balancer(&nodes_from_health_response(false, 1, 1), &LoadBalancing::RoundRobin)
+        .await
+        .expect("balancer should be built");
+
+    for _ in 0..10 {
+        let backend = lb.select_backend("svc", &balancer, b"", &[], Some("10.0.0.2:8080")).expect("pinned backend");
+        assert_eq!(backend.to_string(), "10.0.0.2:8080");
+    }
+
+    let gone = lb.select_backend("svc", &balancer, b"", &[], Some("10.0.0.9:8080"));
+    assert!(gone.is_some());
+
+    let tried = vec!["10.0.0.2:8080".to_string()];
+    let retried = lb
+        .select_backend("svc", &balancer, b"", &tried, Some("10.0.0.2:8080"))
+        .expect("untried backend left");
+    assert_eq!(retried.to_string(), "10.0.0.1:8080");
+}
+
+#[test]
+fn client_request_id_is_kept_when_safe_to_log() {
+    let req = |id: &str| {
+        let mut req = pingora::http::RequestHeader::build("GET", b"/", None).expect("valid request");
+        req.insert_header("X-Request-Id", id).expect("valid header");
+        req
+    };
+
+    assert_eq!(accepted_request_id(&req("abc-123_x.y")).as_deref(), Some("abc-123_x.y"));
+    assert_eq!(accepted_request_id(&req("two words")), None);
+    assert_eq!(accepted_request_id(&req("")), None);
+    assert_eq!(accepted_request_id(&req(&"a".repeat(129))), None);
+    let no_header = pingora::http::RequestHeader::build("GET", b"/", None).expect("valid request");
+    assert_eq!(accepted_request_id(&no_header), None);
+}
+
+#[test]
+fn generated_request_ids_are_v4_uuids() {
+    let ids: BTreeSet<String> = (0..100).map(|_| new_request_id()).collect();
+
+    assert_eq!(ids.len(), 100);
+    for id in ids {
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), [8, 4, 4, 4, 12]);
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+    }
+}
@@ -0,0 +1,163 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::{self, RPConfig};
+use crate::routing::RouteTable;
+use crate::structs::{ConfigReloader, LiveConfig, ReloadReport};
+use crate::{log_error, log_info, log_warn};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Notify;
+
+/// Compares the listed fields of two configs and collects the names of the ones that differ.
+macro_rules! changed_fields {
+    ($old:expr, $new:expr, $($field:ident),+ $(,)?) => {{
+        let mut changed: Vec<&'static str> = Vec::new();
+        $(
+            if $old.$field != $new.$field {
+                changed.push(stringify!($field));
+            }
+        )+
+        changed
+    }};
+}
+
+impl LiveConfig {
+    pub fn new(config_path: PathBuf, rp_config: RPConfig, routes: RouteTable) -> Self {
+        Self {
+            config_path,
+            rp_config: Arc::new(ArcSwap::from_pointee(rp_config)),
+            routes: Arc::new(ArcSwap::from_pointee(routes)),
+            discovery_changed: Arc::new(Notify::new()),
+            reload_lock: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Re-reads the config file and swaps in the sections that can change at runtime.
+    /// Nothing is applied if the new file does not load or its routes do not compile.
+    pub async fn reload(&self) -> anyhow::Result<ReloadReport> {
+        let _guard = self.reload_lock.lock().await;
+
+        let new = config::load(self.config_path.clone())
+            .map_err(|e| anyhow::anyhow!("Unable to load config : {}", e))?;
+        let routes = RouteTable::compile(&new)
+            .map_err(|e| anyhow::anyhow!("Invalid routing config : {}", e))?;
+
+        let current = self.rp_config.load_full();
+        let discovery = changed_fields!(current, new, routes, host_to_upstream, consul_pool_secs);
+        let applied = [discovery.as_slice(), &changed_fields!(current, new, consul_drain_grace_secs, access_log, trusted_proxies)].concat();
+        let report = ReloadReport {
+            requires_restart: unapplied_fields(&current, &new, &applied)?,
+            applied,
+        };
+
+        if !report.applied.is_empty() {
+            let mut effective = (*current).clone();
+            effective.routes = new.routes;
+            effective.host_to_upstream = new.host_to_upstream;
+            effective.consul_pool_secs = new.consul_pool_secs;
+            effective.consul_drain_grace_secs = new.consul_drain_grace_secs;
+            effective.access_log = new.access_log;
+            effective.trusted_proxies = new.trusted_proxies;
+
+            self.routes.store(Arc::new(routes));
+            self.rp_config.store(Arc::new(effective));
+            if !discovery.is_empty() {
+                self.discovery_changed.notify_one();
+            }
+        }
+
+        log_info!(
+            "Config reloaded, applied: {:?}, requires restart: {:?}",
+            report.applied,
+            report.requires_restart
+        );
+        Ok(report)
+    }
+
+    async fn reload_and_log(&self, trigger: &str) {
+        match self.reload().await {
+            Ok(report) if !report.requires_restart.is_empty() => {
+                log_warn!(
+                    "Config reload ({}) left sections unapplied until restart: {:?}",
+                    trigger,
+                    report.requires_restart
+                );
+            }
+            Ok(_) => {}
+            Err(e) => log_error!("Config reload ({}) rejected, keeping current config: {}", trigger, e),
+        }
+    }
+
+    fn modified_at(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.config_path)
+            .and_then(|m| m.modified())
+            .ok()
+    }
+}
+
+impl ConfigReloader {
+    pub fn new(live_config: LiveConfig) -> Self {
+        Self { live_config }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log_error!("Unable to listen for SIGHUP, config reload on signal disabled: {}", e);
+                return;
+            }
+        };
+        let watch_secs = self.live_config.rp_config.load().config_watch_secs;
+        let mut watch = tokio::time::interval(Duration::from_secs(watch_secs.max(1)));
+        let mut last_modified = self.live_config.modified_at();
+
+        loop {
+            tokio::select! {
+                _ = sighup.recv() => {
+                    log_info!("SIGHUP received, reloading config...");
+                    self.live_config.reload_and_log("SIGHUP").await;
+                    last_modified = self.live_config.modified_at();
+                }
+                _ = watch.tick(), if watch_secs > 0 => {
+                    let modified = self.live_config.modified_at();
+                    if modified != last_modified {
+                        last_modified = modified;
+                        log_info!("Config file changed, reloading config...");
+                        self.live_config.reload_and_log("file watch").await;
+                    }
+                }
+                _ = shutdown.changed() => {
+                    log_info!("Shutting down (config reloader)...");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Every other field of `new` that differs from `old`, so fields added to the
+/// config are reported without being listed here.
+fn unapplied_fields(old: &RPConfig, new: &RPConfig, applied: &[&str]) -> anyhow::Result<Vec<String>> {
+    let serde_json::Value::Object(old) = serde_json::to_value(old)? else {
+        anyhow::bail!("config does not serialize to an object");
+    };
+    let serde_json::Value::Object(new) = serde_json::to_value(new)? else {
+        anyhow::bail!("config does not serialize to an object");
+    };
+    Ok(new
+        .into_iter()
+        .filter(|(field, value)| !applied.contains(&field.as_str()) && old.get(field) != Some(value))
+        .map(|(field, _)| field)
+        .collect())
+}
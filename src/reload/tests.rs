@@ -0,0 +1,170 @@
+use super::*;
+use pingora::http::RequestHeader;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::time::timeout;
+
+static CONFIG_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+const BASE_CONFIG: &str = r#"
+tls_port = 443
+consul_url = "http://127.0.0.1:8500/"
+consul_leader_pool_secs = 600
+log_path = "/tmp"
+log_level = "INFO"
+vault_address = ""
+role_id = ""
+secret_id = ""
+path_to_cert_secret = ""
+tls_enabled = false
+tls_private_cert = ""
+tls_chain_cert = ""
+tls_enable_h2 = false
+jwt_cert = ""
+jwt_private_cert = ""
+client_id = ""
+client_secret = ""
+auth_url = "http://localhost/auth"
+token_url = "http://localhost/token"
+scopes = []
+sso_cookie_expire_dayz = 1
+aws_access_key = ""
+aws_secret_key = ""
+r53_zone_id = ""
+r53_fqdns = []
+"#;
+
+struct TempConfig(PathBuf);
+
+impl TempConfig {
+    fn new() -> Self {
+        let n = CONFIG_SEQ.fetch_add(1, Ordering::SeqCst);
+        Self(std::env::temp_dir().join(format!("rproxy-reload-{}-{n}.toml", std::process::id())))
+    }
+
+    fn write(&self, port: u64, grace: u64, hosts: &str) {
+        let content = format!(
+            "port = {port}\nconsul_pool_secs = 60\nconsul_drain_grace_secs = {grace}\n{BASE_CONFIG}\n[host_to_upstream]\n{hosts}\n"
+        );
+        std::fs::write(&self.0, content).expect("write temp config");
+    }
+
+    fn live_config(&self) -> LiveConfig {
+        let rp_config = config::load(self.0.clone()).expect("initial config loads");
+        let routes = RouteTable::compile(&rp_config).expect("initial routes compile");
+        LiveConfig::new(self.0.clone(), rp_config, routes)
+    }
+}
+
+impl Drop for TempConfig {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn resolve(live_config: &LiveConfig, host: &str) -> Option<String> {
+    let req = RequestHeader::build("GET", b"/", None).expect("valid request");
+    live_config.routes.load().resolve(host, &req).map(|(_, u)| u.upstream.clone())
+}
+
+async fn discovery_notified(live_config: &LiveConfig) -> bool {
+    timeout(Duration::from_millis(50), live_config.discovery_changed.notified())
+        .await
+        .is_ok()
+}
+
+#[tokio::test]
+async fn reload_swaps_routes_and_restarts_discovery() {
+    let file = TempConfig::new();
+    file.write(7777, 30, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let live_config = file.live_config();
+
+    file.write(
+        7777,
+        30,
+        "kibana = { upstream = \"pipeline-kibana\" }\ngrafana = { upstream = \"pipeline-grafana\" }",
+    );
+    let report = live_config.reload().await.expect("reload succeeds");
+
+    assert_eq!(report.applied, ["host_to_upstream"]);
+    assert!(report.requires_restart.is_empty());
+    assert_eq!(resolve(&live_config, "grafana.example.com"), Some("pipeline-grafana".into()));
+    assert_eq!(live_config.rp_config.load().host_to_upstream.len(), 2);
+    assert!(discovery_notified(&live_config).await);
+}
+
+#[tokio::test]
+async fn reload_reports_sections_that_need_a_restart() {
+    let file = TempConfig::new();
+    file.write(7777, 30, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let live_config = file.live_config();
+
+    file.write(8888, 30, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let report = live_config.reload().await.expect("reload succeeds");
+
+    assert!(report.applied.is_empty());
+    assert_eq!(report.requires_restart, ["port"]);
+    assert_eq!(live_config.rp_config.load().port, 7777);
+    assert!(!discovery_notified(&live_config).await);
+}
+
+#[tokio::test]
+async fn reload_applies_drain_grace_without_restarting_discovery() {
+    let file = TempConfig::new();
+    file.write(7777, 30, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let live_config = file.live_config();
+
+    file.write(7777, 5, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let report = live_config.reload().await.expect("reload succeeds");
+
+    assert_eq!(report.applied, ["consul_drain_grace_secs"]);
+    assert_eq!(live_config.rp_config.load().consul_drain_grace_secs, 5);
+    assert!(!discovery_notified(&live_config).await);
+}
+
+#[tokio::test]
+async fn reload_rejects_invalid_file_and_keeps_current_config() {
+    let file = TempConfig::new();
+    file.write(7777, 30, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let live_config = file.live_config();
+
+    file.write(
+        7777,
+        30,
+        "kibana = { upstream = \"pipeline-kibana\" }\nKibana = { upstream = \"other\" }",
+    );
+    let err = live_config.reload().await.expect_err("ambiguous keys are rejected");
+
+    assert!(err.to_string().contains("ambiguous"));
+    assert_eq!(resolve(&live_config, "kibana.example.com"), Some("pipeline-kibana".into()));
+    assert_eq!(live_config.rp_config.load().host_to_upstream.len(), 1);
+    assert!(!discovery_notified(&live_config).await);
+}
+
+#[tokio::test]
+async fn reload_rejects_unreadable_file() {
+    let file = TempConfig::new();
+    file.write(7777, 30, r#"kibana = { upstream = "pipeline-kibana" }"#);
+    let live_config = file.live_config();
+
+    std::fs::write(&file.0, "port = ").expect("write broken config");
+
+    assert!(live_config.reload().await.is_err());
+    assert_eq!(resolve(&live_config, "kibana.example.com"), Some("pipeline-kibana".into()));
+}
+
+#[test]
+fn fields_left_out_of_the_applied_ones_need_a_restart() {
+    let current = RPConfig::default();
+    let new = RPConfig {
+        port: 8888,
+        r53_fqdns: vec!["rproxy.example.com".to_string()],
+        consul_drain_grace_secs: 5,
+        ..RPConfig::default()
+    };
+
+    let unapplied = unapplied_fields(&current, &new, &["consul_drain_grace_secs"]).expect("configs serialize");
+
+    assert_eq!(unapplied, ["port", "r53_fqdns"]);
+    assert!(unapplied_fields(&current, &current, &[]).expect("configs serialize").is_empty());
+}
+
@@ -0,0 +1,91 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::RetryConfig;
+use crate::structs::{Context, NetIqLoadBalancer, RetryBudget};
+use crate::log_warn;
+use pingora::http::Method;
+use std::time::{Duration, Instant};
+
+const BUDGET_WINDOW: Duration = Duration::from_secs(1);
+
+impl NetIqLoadBalancer {
+    /// Counts a request towards the retry budget of its upstream.
+    pub fn count_request(&self, upstream: &str, now: Instant) {
+        self.retry_budgets
+            .entry(upstream.to_string())
+            .or_insert_with(|| RetryBudget::new(now))
+            .roll(now)
+            .requests += 1;
+    }
+
+    /// Whether the request may go to another backend, spending from the retry
+    /// budget of its upstream when it may.
+    pub fn may_retry(&self, ctx: &Context, now: Instant) -> bool {
+        let Some(upstream) = ctx.fully_qualified_upstream.as_deref() else {
+            return false;
+        };
+        let routes = self.live_config.routes.load();
+        let Some(conf) = routes.retry(upstream) else {
+            return false;
+        };
+        if ctx.tried.len() > conf.attempts as usize {
+            return false;
+        }
+        let mut budget = self
+            .retry_budgets
+            .entry(upstream.to_string())
+            .or_insert_with(|| RetryBudget::new(now));
+        if !budget.roll(now).try_spend(conf) {
+            log_warn!("Retry budget of {} exhausted, not retrying", upstream);
+            return false;
+        }
+        true
+    }
+
+    /// Whether a 502 or 503 answer to an idempotent request goes to another backend.
+    pub fn may_retry_status(&self, ctx: &Context, status: u16, method: &Method, now: Instant) -> bool {
+        let on_status = ctx
+            .fully_qualified_upstream
+            .as_deref()
+            .and_then(|upstream| self.live_config.routes.load().retry(upstream).map(|conf| conf.on_502_503))
+            .unwrap_or(false);
+        on_status && matches!(status, 502 | 503) && is_idempotent(method) && self.may_retry(ctx, now)
+    }
+}
+
+impl RetryBudget {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window: now,
+            requests: 0,
+            retries: 0,
+        }
+    }
+
+    /// Starts a new window once the current one is over.
+    fn roll(&mut self, now: Instant) -> &mut Self {
+        if now.duration_since(self.window) >= BUDGET_WINDOW {
+            *self = Self::new(now);
+        }
+        self
+    }
+
+    fn try_spend(&mut self, conf: &RetryConfig) -> bool {
+        let allowed = (u64::from(self.requests) * u64::from(conf.budget_percent) / 100)
+            .max(u64::from(conf.budget_min_per_sec));
+        if u64::from(self.retries) >= allowed {
+            return false;
+        }
+        self.retries += 1;
+        true
+    }
+}
+
+/// Methods that can be sent again without changing the result.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
@@ -0,0 +1,78 @@
+use super::*;
+use crate::config::{RPConfig, UpstreamDetails};
+use crate::routing::RouteTable;
+use std::collections::HashMap;
+use pingora::prelude::ProxyHttp;
+use std::sync::Arc;
+
+fn lb_with(retry: serde_json::Value) -> NetIqLoadBalancer {
+    let details: UpstreamDetails = serde_json::from_value(serde_json::json!({ "upstream": "svc", "retry": retry }))
+        .expect("valid upstream details");
+    let rp_config = RPConfig {
+        host_to_upstream: HashMap::from([("svc".to_string(), details)]),
+        ..RPConfig::default()
+    };
+    let lb = NetIqLoadBalancer::new_for_tests(rp_config.clone());
+    lb.live_config
+        .routes
+        .store(Arc::new(RouteTable::compile(&rp_config).expect("routes should compile")));
+    lb
+}
+
+fn ctx(tried: &[&str]) -> Context {
+    let mut ctx = NetIqLoadBalancer::new_for_tests(RPConfig::default()).new_ctx();
+    ctx.fully_qualified_upstream = Some("svc".to_string());
+    ctx.tried = tried.iter().map(|b| b.to_string()).collect();
+    ctx
+}
+
+#[test]
+fn retries_stop_after_configured_attempts() {
+    let lb = lb_with(serde_json::json!({"attempts": 2}));
+    let now = Instant::now();
+
+    assert!(lb.may_retry(&ctx(&["a"]), now));
+    assert!(lb.may_retry(&ctx(&["a", "b"]), now));
+    assert!(!lb.may_retry(&ctx(&["a", "b", "c"]), now));
+}
+
+#[test]
+fn upstream_without_retry_config_is_not_retried() {
+    let lb = lb_with(serde_json::json!(null));
+
+    assert!(!lb.may_retry(&ctx(&["a"]), Instant::now()));
+}
+
+#[test]
+fn retry_budget_allows_a_share_of_requests_per_window() {
+    let lb = lb_with(serde_json::json!({"attempts": 5, "budget_percent": 10, "budget_min_per_sec": 2}));
+    let now = Instant::now();
+
+    let retries = (0..10).filter(|_| lb.may_retry(&ctx(&["a"]), now)).count();
+    assert_eq!(retries, 2);
+
+    for _ in 0..50 {
+        lb.count_request("svc", now);
+    }
+    let retries = (0..10).filter(|_| lb.may_retry(&ctx(&["a"]), now)).count();
+    assert_eq!(retries, 3);
+
+    let next_window = now + Duration::from_secs(1);
+    let retries = (0..10).filter(|_| lb.may_retry(&ctx(&["a"]), next_window)).count();
+    assert_eq!(retries, 2);
+}
+
+#[test]
+fn only_idempotent_502_and_503_are_retried_when_enabled() {
+    let lb = lb_with(serde_json::json!({"on_502_503": true, "budget_min_per_sec": 100}));
+    let now = Instant::now();
+    let ctx = ctx(&["a"]);
+
+    assert!(lb.may_retry_status(&ctx, 502, &Method::GET, now));
+    assert!(lb.may_retry_status(&ctx, 503, &Method::PUT, now));
+    assert!(!lb.may_retry_status(&ctx, 500, &Method::GET, now));
+    assert!(!lb.may_retry_status(&ctx, 503, &Method::POST, now));
+
+    let lb = lb_with(serde_json::json!({}));
+    assert!(!lb.may_retry_status(&ctx, 503, &Method::GET, now));
+}
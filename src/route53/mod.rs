@@ -14,13 +14,13 @@ use rand::rng;
 use rand::seq::SliceRandom;
 use tokio::runtime::Runtime;
 
-use crate::structs::{RuntimeState, R53};
+use crate::structs::{Metrics, RuntimeState, R53};
 use crate::utils::{get_res_record_sets, update_res_record_sets};
 use crate::{log_error, log_info};
 
 impl R53 {
-    pub fn new(rp_config: RPConfig, runtime_state: RuntimeState) -> Self {
-        Self { rp_config , runtime_state }
+    pub fn new(rp_config: RPConfig, runtime_state: RuntimeState, metrics: Metrics) -> Self {
+        Self { rp_config , runtime_state, metrics }
     }
 
     pub fn non_async_r53_register(&self) {
@@ -32,7 +32,7 @@ impl R53 {
                 .runtime_state
                 .aws_r53_client;
             match register_ip_route53(&self.rp_config, &ip, aws_r53_client.as_ref()).await {
-                Ok(_) => {}
+                Ok(_) => self.metrics.record_r53_action("register", true),
                 Err(err) => {
                     log_error!("{:?}", err);
                     std::process::exit(1);
@@ -54,8 +54,9 @@ impl BackgroundService for R53 {
                         .runtime_state
                         .aws_r53_client;
                     match deregister_ip_route53(&self.rp_config, &ip, aws_r53_client.as_ref()).await {
-                        Ok(_) => {}
+                        Ok(_) => self.metrics.record_r53_action("deregister", true),
                         Err(err) => {
+                            self.metrics.record_r53_action("deregister", false);
                             log_error!("{:?}", err);
                         }
                     };
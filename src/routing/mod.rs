@@ -0,0 +1,543 @@
+#[cfg(test)]
+mod tests;
+
+use crate::config::{ActiveHealthCheckConfig, AffinityConfig, HashOn, HeaderRules, LoadBalancing, OutlierDetectionConfig, PlainHttp, RPConfig, RetryConfig, RouteConfig, UpstreamDetails, UpstreamTlsConfig};
+use crate::log_warn;
+use crate::structs::{ClientCert, HeaderActions, UpstreamTls};
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use pingora::http::{Method, RequestHeader};
+use pingora_core::utils::tls::CertKey;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Routing rules compiled from `routes` followed by `host_to_upstream`, evaluated
+/// top to bottom; the first rule that matches wins.
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    routes: Vec<Route>,
+    upstream_tls: HashMap<String, Arc<UpstreamTls>>,
+    health_checks: HashMap<String, ActiveHealthCheckConfig>,
+    outlier_detection: HashMap<String, OutlierDetectionConfig>,
+    retry: HashMap<String, RetryConfig>,
+    load_balancing: HashMap<String, LoadBalancing>,
+    affinity: HashMap<String, AffinityConfig>,
+    host_rewrite: HashMap<String, String>,
+    trusted_proxies: Vec<IpNet>,
+    request_headers: HashMap<String, HeaderActions>,
+    response_headers: HashMap<String, HeaderActions>,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    /// Host pattern of the rule, `*` when it matches any host.
+    name: String,
+    host: Option<HostMatch>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<Method>,
+    headers: Vec<(String, String)>,
+    upstream: UpstreamDetails,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum HostMatch {
+    /// `portal.example.com`
+    Exact(String),
+    /// `*.example.com`, stored as `.example.com`
+    Wildcard(String),
+    /// `example.com` and any of its subdomains
+    Suffix(String),
+    /// `kibana`, matched against the leftmost label of the hostname
+    Label(String),
+}
+
+impl RouteTable {
+    pub fn compile(rp_config: &RPConfig) -> anyhow::Result<Self> {
+        check_plain_http(rp_config)?;
+        check_sso(rp_config)?;
+        let mut routes = Vec::with_capacity(rp_config.routes.len() + rp_config.host_to_upstream.len());
+        for (i, route) in rp_config.routes.iter().enumerate() {
+            routes.push(Route::compile(route).map_err(|e| anyhow!("routes[{}]: {}", i, e))?);
+        }
+
+        for (host, upstream) in compile_host_to_upstream(rp_config)? {
+            routes.push(Route {
+                name: host.pattern(),
+                host: Some(host),
+                path_prefix: None,
+                path_regex: None,
+                methods: vec![],
+                headers: vec![],
+                upstream,
+            });
+        }
+
+        Ok(Self {
+            routes,
+            upstream_tls: compile_upstream_tls(rp_config)?,
+            health_checks: compile_health_checks(rp_config)?,
+            outlier_detection: compile_outlier_detection(rp_config)?,
+            retry: per_upstream(rp_config, "retry", |details| details.retry.as_ref())?
+                .into_iter()
+                .map(|(upstream, retry)| (upstream.to_string(), retry.clone()))
+                .collect(),
+            load_balancing: compile_load_balancing(rp_config)?,
+            affinity: compile_affinity(rp_config)?,
+            host_rewrite: compile_host_rewrite(rp_config)?,
+            trusted_proxies: compile_trusted_proxies(rp_config)?,
+            request_headers: compile_header_rules(rp_config, "request_headers", |d| d.request_headers.as_ref())?,
+            response_headers: compile_header_rules(rp_config, "response_headers", |d| d.response_headers.as_ref())?,
+        })
+    }
+
+    /// The upstream of the first matching rule, with the rule's host pattern.
+    pub fn resolve(&self, hostname: &str, req: &RequestHeader) -> Option<(&str, &UpstreamDetails)> {
+        let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+        self.routes
+            .iter()
+            .find(|route| route.matches(&hostname, req))
+            .map(|route| (route.name.as_str(), &route.upstream))
+    }
+
+    /// TLS settings of an upstream, `None` for plaintext upstreams.
+    pub fn upstream_tls(&self, upstream: &str) -> Option<Arc<UpstreamTls>> {
+        self.upstream_tls.get(upstream).cloned()
+    }
+
+    /// Upstreams probed by the proxy, with their check settings.
+    pub fn health_checks(&self) -> &HashMap<String, ActiveHealthCheckConfig> {
+        &self.health_checks
+    }
+
+    pub fn outlier_detection(&self, upstream: &str) -> Option<&OutlierDetectionConfig> {
+        self.outlier_detection.get(upstream)
+    }
+
+    pub fn retry(&self, upstream: &str) -> Option<&RetryConfig> {
+        self.retry.get(upstream)
+    }
+
+    pub fn load_balancing(&self, upstream: &str) -> LoadBalancing {
+        self.load_balancing.get(upstream).cloned().unwrap_or_default()
+    }
+
+    pub fn affinity(&self, upstream: &str) -> Option<&AffinityConfig> {
+        self.affinity.get(upstream)
+    }
+
+    pub fn host_rewrite(&self, upstream: &str) -> Option<&str> {
+        self.host_rewrite.get(upstream).map(String::as_str)
+    }
+
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn request_headers(&self, upstream: &str) -> Option<&HeaderActions> {
+        self.request_headers.get(upstream)
+    }
+
+    pub fn response_headers(&self, upstream: &str) -> Option<&HeaderActions> {
+        self.response_headers.get(upstream)
+    }
+}
+
+impl Route {
+    fn compile(conf: &RouteConfig) -> anyhow::Result<Self> {
+        let host = match (conf.host.is_empty(), conf.host_suffix.is_empty()) {
+            (false, false) => bail!("host and host_suffix are mutually exclusive"),
+            (false, true) => Some(HostMatch::parse_host(&conf.host)?),
+            (true, false) => {
+                let domain = normalize_host(&conf.host_suffix);
+                if domain.is_empty() || domain.contains('*') {
+                    bail!("invalid host_suffix '{}'", conf.host_suffix);
+                }
+                Some(HostMatch::Suffix(domain))
+            }
+            (true, true) => None,
+        };
+
+        let path_prefix = if conf.path_prefix.is_empty() {
+            None
+        } else if conf.path_prefix.starts_with('/') {
+            Some(conf.path_prefix.clone())
+        } else {
+            bail!("path_prefix '{}' must start with '/'", conf.path_prefix)
+        };
+
+        let path_regex = if conf.path_regex.is_empty() {
+            None
+        } else {
+            Some(Regex::new(&conf.path_regex).map_err(|e| anyhow!("invalid path_regex: {}", e))?)
+        };
+
+        let methods = conf
+            .methods
+            .iter()
+            .map(|m| {
+                Method::from_str(&m.to_ascii_uppercase()).map_err(|_| anyhow!("invalid method '{}'", m))
+            })
+            .collect::<anyhow::Result<Vec<Method>>>()?;
+
+        let mut headers: Vec<(String, String)> = conf
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+            .collect();
+        headers.sort();
+
+        if conf.details.upstream.is_empty() {
+            bail!("upstream must be set");
+        }
+
+        Ok(Self {
+            name: host.as_ref().map_or_else(|| "*".to_string(), HostMatch::pattern),
+            host,
+            path_prefix,
+            path_regex,
+            methods,
+            headers,
+            upstream: conf.details.clone(),
+        })
+    }
+
+    fn matches(&self, hostname: &str, req: &RequestHeader) -> bool {
+        if let Some(host) = &self.host
+            && !host.matches(hostname)
+        {
+            return false;
+        }
+        let path = req.uri.path();
+        if let Some(prefix) = &self.path_prefix
+            && !path.starts_with(prefix.as_str())
+        {
+            return false;
+        }
+        if let Some(regex) = &self.path_regex
+            && !regex.is_match(path)
+        {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.contains(&req.method) {
+            return false;
+        }
+        self.headers.iter().all(|(name, value)| {
+            req.headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v == value)
+        })
+    }
+}
+
+impl HostMatch {
+    fn parse_host(host: &str) -> anyhow::Result<Self> {
+        let host = normalize_host(host);
+        if let Some(suffix) = host.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                bail!("invalid wildcard host '{}'", host);
+            }
+            return Ok(HostMatch::Wildcard(format!(".{suffix}")));
+        }
+        if host.contains('*') {
+            bail!("wildcard is only allowed as the leftmost label in '{}'", host);
+        }
+        Ok(HostMatch::Exact(host))
+    }
+
+    fn matches(&self, hostname: &str) -> bool {
+        match self {
+            HostMatch::Exact(host) => hostname == host,
+            HostMatch::Wildcard(suffix) => hostname.len() > suffix.len() && hostname.ends_with(suffix.as_str()),
+            HostMatch::Suffix(domain) => {
+                hostname == domain
+                    || hostname
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|rest| rest.ends_with('.'))
+            }
+            HostMatch::Label(label) => hostname.split('.').next() == Some(label.as_str()),
+        }
+    }
+
+    /// The host as configured, after normalization.
+    fn pattern(&self) -> String {
+        match self {
+            HostMatch::Exact(host) | HostMatch::Suffix(host) | HostMatch::Label(host) => host.clone(),
+            HostMatch::Wildcard(suffix) => format!("*{suffix}"),
+        }
+    }
+
+    /// Lower sorts first: exact hosts, then labels, then wildcards with the
+    /// longest suffix first.
+    fn precedence(&self) -> (u8, usize) {
+        match self {
+            HostMatch::Exact(_) => (0, 0),
+            HostMatch::Label(_) => (1, 0),
+            HostMatch::Wildcard(suffix) => (2, usize::MAX - suffix.len()),
+            HostMatch::Suffix(domain) => (3, usize::MAX - domain.len()),
+        }
+    }
+}
+
+/// Parses the `host_to_upstream` keys and orders them by precedence, so the first
+/// match is also the most specific one. Keys are an exact hostname
+/// (`kibana.example.com`), a wildcard (`*.example.com`) or a single label
+/// (`kibana`) matching the leftmost label of the hostname. Keys that normalize to
+/// the same matcher are rejected, as either of them could win.
+fn compile_host_to_upstream(rp_config: &RPConfig) -> anyhow::Result<Vec<(HostMatch, UpstreamDetails)>> {
+    let mut seen: HashMap<HostMatch, &str> = HashMap::new();
+    let mut hosts = Vec::with_capacity(rp_config.host_to_upstream.len());
+    for (key, upstream) in &rp_config.host_to_upstream {
+        let host = parse_host_key(key).map_err(|e| anyhow!("host_to_upstream: {}", e))?;
+        if let Some(other) = seen.insert(host.clone(), key) {
+            let (a, b) = if other < key.as_str() { (other, key.as_str()) } else { (key.as_str(), other) };
+            bail!("host_to_upstream: keys '{}' and '{}' are ambiguous", a, b);
+        }
+        hosts.push((host, upstream.clone()));
+    }
+    hosts.sort_by(|(a, _), (b, _)| a.precedence().cmp(&b.precedence()).then_with(|| a.cmp(b)));
+    Ok(hosts)
+}
+
+fn parse_host_key(key: &str) -> anyhow::Result<HostMatch> {
+    if key.contains(':') || key.contains('/') {
+        bail!("key '{}' must be a hostname without scheme, port or path", key);
+    }
+    let host = HostMatch::parse_host(key)?;
+    match host {
+        HostMatch::Exact(h) if h.is_empty() => bail!("empty key"),
+        HostMatch::Exact(h) if !h.contains('.') => Ok(HostMatch::Label(h)),
+        HostMatch::Exact(h) => {
+            // keys used to match any hostname containing them
+            log_warn!("host_to_upstream: key '{}' only matches {} itself, use '*.{}' for its subdomains", key, h, h);
+            Ok(HostMatch::Exact(h))
+        }
+        other => Ok(other),
+    }
+}
+
+/// Settings of every upstream that has them. An upstream referenced from several
+/// places has to use the same settings everywhere, as they share one balancer.
+fn per_upstream<'a, T: PartialEq>(
+    rp_config: &'a RPConfig,
+    what: &str,
+    settings: impl Fn(&'a UpstreamDetails) -> Option<&'a T>,
+) -> anyhow::Result<HashMap<&'a str, &'a T>> {
+    let mut seen: HashMap<&str, Option<&T>> = HashMap::new();
+    for details in rp_config.all_upstreams() {
+        if let Some(other) = seen.insert(details.upstream.as_str(), settings(details))
+            && other != settings(details)
+        {
+            bail!("upstream '{}' is configured with different {} settings", details.upstream, what);
+        }
+    }
+    Ok(seen.into_iter().filter_map(|(upstream, s)| s.map(|s| (upstream, s))).collect())
+}
+
+/// Loads the TLS settings of every upstream.
+fn compile_upstream_tls(rp_config: &RPConfig) -> anyhow::Result<HashMap<String, Arc<UpstreamTls>>> {
+    per_upstream(rp_config, "tls", |details| details.tls.as_ref())?
+        .into_iter()
+        .map(|(upstream, tls)| {
+            let compiled = UpstreamTls::compile(tls).map_err(|e| anyhow!("upstream '{}' tls: {}", upstream, e))?;
+            Ok((upstream.to_string(), Arc::new(compiled)))
+        })
+        .collect()
+}
+
+fn compile_health_checks(rp_config: &RPConfig) -> anyhow::Result<HashMap<String, ActiveHealthCheckConfig>> {
+    per_upstream(rp_config, "active_health_check", |details| details.active_health_check.as_ref())?
+        .into_iter()
+        .map(|(upstream, check)| {
+            validate_health_check(check).map_err(|e| anyhow!("upstream '{}' active_health_check: {}", upstream, e))?;
+            Ok((upstream.to_string(), check.clone()))
+        })
+        .collect()
+}
+
+fn validate_health_check(check: &ActiveHealthCheckConfig) -> anyhow::Result<()> {
+    if !check.path.is_empty() && !check.path.starts_with('/') {
+        bail!("path '{}' must start with '/'", check.path);
+    }
+    if check.path.is_empty() && !check.expected_body.is_empty() {
+        bail!("expected_body requires an http path");
+    }
+    if check.interval_secs == 0 || check.timeout_ms == 0 {
+        bail!("interval_secs and timeout_ms must be greater than 0");
+    }
+    if check.rise == 0 || check.fall == 0 {
+        bail!("rise and fall must be greater than 0");
+    }
+    Ok(())
+}
+
+fn compile_outlier_detection(rp_config: &RPConfig) -> anyhow::Result<HashMap<String, OutlierDetectionConfig>> {
+    per_upstream(rp_config, "outlier_detection", |details| details.outlier_detection.as_ref())?
+        .into_iter()
+        .map(|(upstream, conf)| {
+            if conf.consecutive_failures == 0 || conf.base_ejection_secs == 0 {
+                bail!("upstream '{}' outlier_detection: consecutive_failures and base_ejection_secs must be greater than 0", upstream);
+            }
+            if conf.max_ejection_secs < conf.base_ejection_secs || conf.max_ejection_percent > 100 {
+                bail!("upstream '{}' outlier_detection: max_ejection_secs must be at least base_ejection_secs and max_ejection_percent at most 100", upstream);
+            }
+            Ok((upstream.to_string(), conf.clone()))
+        })
+        .collect()
+}
+
+fn compile_load_balancing(rp_config: &RPConfig) -> anyhow::Result<HashMap<String, LoadBalancing>> {
+    per_upstream(rp_config, "load_balancing", |details| Some(&details.load_balancing))?
+        .into_iter()
+        .map(|(upstream, algorithm)| {
+            if let LoadBalancing::Ketama { hash_on: HashOn::Header | HashOn::Cookie, key } = algorithm
+                && key.is_empty()
+            {
+                bail!("upstream '{}' load_balancing: key is required to hash on a header or cookie", upstream);
+            }
+            Ok((upstream.to_string(), algorithm.clone()))
+        })
+        .collect()
+}
+
+fn compile_affinity(rp_config: &RPConfig) -> anyhow::Result<HashMap<String, AffinityConfig>> {
+    per_upstream(rp_config, "affinity", |details| details.affinity.as_ref())?
+        .into_iter()
+        .map(|(upstream, affinity)| {
+            let valid = !affinity.cookie_name.is_empty()
+                && affinity.cookie_name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+            if !valid {
+                bail!("upstream '{}' affinity: invalid cookie_name '{}'", upstream, affinity.cookie_name);
+            }
+            Ok((upstream.to_string(), affinity.clone()))
+        })
+        .collect()
+}
+
+/// Without a TLS listener there is no https to redirect plain requests to.
+fn check_plain_http(rp_config: &RPConfig) -> anyhow::Result<()> {
+    if rp_config.http_port == 0 || rp_config.tls_enabled {
+        return Ok(());
+    }
+    match rp_config.all_upstreams().find(|details| details.plain_http != PlainHttp::Proxy) {
+        Some(details) => bail!(
+            "upstream '{}': plain_http must be \"proxy\" when tls_enabled is false, there is no https listener to redirect to",
+            details.upstream
+        ),
+        None => Ok(()),
+    }
+}
+
+/// ID tokens of `sso_req` upstreams are verified against the issuer's keys,
+/// which come from discovery or from `jwks_url` and `id_token_issuer`.
+fn check_sso(rp_config: &RPConfig) -> anyhow::Result<()> {
+    if !rp_config.oidc_issuer.is_empty() || (!rp_config.jwks_url.is_empty() && !rp_config.id_token_issuer.is_empty()) {
+        return Ok(());
+    }
+    match rp_config.all_upstreams().find(|details| details.sso_req) {
+        Some(details) => bail!(
+            "upstream '{}': sso_req requires oidc_issuer, or jwks_url and id_token_issuer, to verify ID tokens",
+            details.upstream
+        ),
+        None => Ok(()),
+    }
+}
+
+fn compile_host_rewrite(rp_config: &RPConfig) -> anyhow::Result<HashMap<String, String>> {
+    per_upstream(rp_config, "host_rewrite", |details| details.host_rewrite.as_ref())?
+        .into_iter()
+        .map(|(upstream, host)| {
+            if host.is_empty() || !host.bytes().all(|b| b.is_ascii_graphic()) {
+                bail!("upstream '{}': invalid host_rewrite '{}'", upstream, host);
+            }
+            Ok((upstream.to_string(), host.clone()))
+        })
+        .collect()
+}
+
+fn compile_header_rules<'a>(
+    rp_config: &'a RPConfig,
+    what: &str,
+    rules: impl Fn(&'a UpstreamDetails) -> Option<&'a HeaderRules>,
+) -> anyhow::Result<HashMap<String, HeaderActions>> {
+    per_upstream(rp_config, what, rules)?
+        .into_iter()
+        .map(|(upstream, rules)| {
+            let actions = HeaderActions::compile(rules).map_err(|e| anyhow!("upstream '{}' {}: {}", upstream, what, e))?;
+            Ok((upstream.to_string(), actions))
+        })
+        .collect()
+}
+
+/// `10.0.0.0/8` or a single address.
+fn compile_trusted_proxies(rp_config: &RPConfig) -> anyhow::Result<Vec<IpNet>> {
+    rp_config
+        .trusted_proxies
+        .iter()
+        .map(|proxy| {
+            let proxy = proxy.trim();
+            IpNet::from_str(proxy)
+                .or_else(|_| IpAddr::from_str(proxy).map(IpNet::from))
+                .map(|net| net.trunc())
+                .map_err(|_| anyhow!("trusted_proxies: invalid CIDR or address '{}'", proxy))
+        })
+        .collect()
+}
+
+impl UpstreamTls {
+    fn compile(conf: &UpstreamTlsConfig) -> anyhow::Result<Self> {
+        let ca = if conf.ca_file.is_empty() {
+            None
+        } else {
+            let certs = read_certs(&conf.ca_file)?;
+            Some(Arc::new(certs.into_boxed_slice()))
+        };
+
+        let from_files = !conf.client_cert_file.is_empty() || !conf.client_key_file.is_empty();
+        let client_cert = match (&conf.vault_pki, from_files) {
+            (Some(_), true) => bail!("vault_pki and client_cert_file/client_key_file are mutually exclusive"),
+            (Some(pki), false) => ClientCert::VaultPki(pki.clone()),
+            (None, true) => ClientCert::Static(Arc::new(read_cert_key(&conf.client_cert_file, &conf.client_key_file)?)),
+            (None, false) => ClientCert::None,
+        };
+
+        Ok(Self {
+            sni: (!conf.sni.is_empty()).then(|| conf.sni.clone()),
+            verify: conf.verify,
+            ca,
+            client_cert,
+        })
+    }
+}
+
+fn read_certs(path: &str) -> anyhow::Result<Vec<X509>> {
+    let pem = std::fs::read(path).map_err(|e| anyhow!("unable to read '{}': {}", path, e))?;
+    let certs = X509::stack_from_pem(&pem).map_err(|e| anyhow!("invalid certificate in '{}': {}", path, e))?;
+    if certs.is_empty() {
+        bail!("'{}' holds no certificate", path);
+    }
+    Ok(certs)
+}
+
+fn read_cert_key(cert_file: &str, key_file: &str) -> anyhow::Result<CertKey> {
+    if cert_file.is_empty() || key_file.is_empty() {
+        bail!("client_cert_file and client_key_file must both be set");
+    }
+    let certs = read_certs(cert_file)?;
+    let pem = std::fs::read(key_file).map_err(|e| anyhow!("unable to read '{}': {}", key_file, e))?;
+    let key = PKey::private_key_from_pem(&pem).map_err(|e| anyhow!("invalid private key in '{}': {}", key_file, e))?;
+    if !certs[0].public_key()?.public_eq(&key) {
+        bail!("'{}' does not match the certificate in '{}'", key_file, cert_file);
+    }
+    Ok(CertKey::new(certs, key))
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
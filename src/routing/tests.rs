@@ -0,0 +1,566 @@
+use super::*;
+use crate::config::UpstreamTlsVerify;
+use std::collections::HashMap;
+
+fn details(upstream: &str) -> UpstreamDetails {
+    serde_json::from_value(serde_json::json!({ "upstream": upstream })).expect("valid upstream details")
+}
+
+fn route(value: serde_json::Value) -> RouteConfig {
+    serde_json::from_value(value).expect("valid route config")
+}
+
+fn table(routes: Vec<RouteConfig>, hosts: &[(&str, &str)]) -> RouteTable {
+    let rp_config = RPConfig {
+        routes,
+        host_to_upstream: hosts
+            .iter()
+            .map(|(k, v)| (k.to_string(), details(v)))
+            .collect::<HashMap<_, _>>(),
+        ..RPConfig::default()
+    };
+    RouteTable::compile(&rp_config).expect("routes should compile")
+}
+
+fn req(method: &str, path: &str, headers: &[(&str, &str)]) -> RequestHeader {
+    let mut req = RequestHeader::build(method, path.as_bytes(), None).expect("valid request");
+    for (name, value) in headers {
+        req.insert_header(name.to_string(), value.to_string()).expect("valid header");
+    }
+    req
+}
+
+fn resolve(table: &RouteTable, host: &str, req: &RequestHeader) -> Option<String> {
+    table.resolve(host, req).map(|(_, u)| u.upstream.clone())
+}
+
+#[test]
+fn route_config_flattens_upstream_details() {
+    let r = route(serde_json::json!({
+        "host": "portal.example.com",
+        "path_prefix": "/api/",
+        "upstream": "api",
+        "sso_req": true
+    }));
+
+    assert_eq!(r.details.upstream, "api");
+    assert!(r.details.sso_req);
+    assert_eq!(r.details.health_checks, "passing");
+}
+
+#[test]
+fn path_prefix_splits_one_host_between_upstreams() {
+    let t = table(
+        vec![
+            route(serde_json::json!({"host": "portal.example.com", "path_prefix": "/api/", "upstream": "api"})),
+            route(serde_json::json!({"host": "portal.example.com", "path_prefix": "/ui/", "upstream": "ui"})),
+        ],
+        &[],
+    );
+
+    assert_eq!(resolve(&t, "portal.example.com", &req("GET", "/api/v1/items", &[])), Some("api".into()));
+    assert_eq!(resolve(&t, "portal.example.com", &req("GET", "/ui/index.html", &[])), Some("ui".into()));
+    assert_eq!(resolve(&t, "portal.example.com", &req("GET", "/other", &[])), None);
+}
+
+#[test]
+fn routes_are_evaluated_in_declaration_order() {
+    let t = table(
+        vec![
+            route(serde_json::json!({"host": "portal.example.com", "path_prefix": "/api/admin", "upstream": "admin"})),
+            route(serde_json::json!({"host": "portal.example.com", "path_prefix": "/api/", "upstream": "api"})),
+        ],
+        &[],
+    );
+
+    assert_eq!(resolve(&t, "portal.example.com", &req("GET", "/api/admin/users", &[])), Some("admin".into()));
+    assert_eq!(resolve(&t, "portal.example.com", &req("GET", "/api/users", &[])), Some("api".into()));
+}
+
+#[test]
+fn routes_take_precedence_over_host_to_upstream() {
+    let t = table(
+        vec![route(serde_json::json!({"host": "kibana.example.com", "path_prefix": "/api/", "upstream": "kibana-api"}))],
+        &[("kibana", "kibana")],
+    );
+
+    assert_eq!(resolve(&t, "kibana.example.com", &req("GET", "/api/status", &[])), Some("kibana-api".into()));
+    assert_eq!(resolve(&t, "kibana.example.com", &req("GET", "/app/home", &[])), Some("kibana".into()));
+}
+
+#[test]
+fn host_exact_wildcard_and_suffix_match_as_documented() {
+    let t = table(
+        vec![
+            route(serde_json::json!({"host": "Exact.Example.com.", "upstream": "exact"})),
+            route(serde_json::json!({"host": "*.wild.com", "upstream": "wild"})),
+            route(serde_json::json!({"host_suffix": "suffix.com", "upstream": "suffix"})),
+        ],
+        &[],
+    );
+    let r = req("GET", "/", &[]);
+
+    assert_eq!(resolve(&t, "exact.example.com", &r), Some("exact".into()));
+    assert_eq!(resolve(&t, "sub.exact.example.com", &r), None);
+    assert_eq!(resolve(&t, "a.wild.com", &r), Some("wild".into()));
+    assert_eq!(resolve(&t, "a.b.wild.com", &r), Some("wild".into()));
+    assert_eq!(resolve(&t, "wild.com", &r), None);
+    assert_eq!(resolve(&t, "notwild.com", &r), None);
+    assert_eq!(resolve(&t, "suffix.com", &r), Some("suffix".into()));
+    assert_eq!(resolve(&t, "a.suffix.com", &r), Some("suffix".into()));
+    assert_eq!(resolve(&t, "notsuffix.com", &r), None);
+}
+
+#[test]
+fn resolve_names_the_route_by_its_host_pattern() {
+    let t = table(
+        vec![
+            route(serde_json::json!({"host": "*.Wild.com", "upstream": "wild"})),
+            route(serde_json::json!({"host_suffix": "suffix.com", "upstream": "suffix"})),
+            route(serde_json::json!({"path_prefix": "/api/", "upstream": "api"})),
+        ],
+        &[("kibana", "pipeline-kibana")],
+    );
+    let name = |host: &str, path: &str| t.resolve(host, &req("GET", path, &[])).map(|(name, _)| name.to_string());
+
+    assert_eq!(name("a.wild.com", "/").as_deref(), Some("*.wild.com"));
+    assert_eq!(name("a.suffix.com", "/").as_deref(), Some("suffix.com"));
+    assert_eq!(name("any.example.org", "/api/v1").as_deref(), Some("*"));
+    assert_eq!(name("kibana.example.org", "/").as_deref(), Some("kibana"));
+}
+
+#[test]
+fn path_regex_method_and_header_conditions_must_all_match() {
+    let t = table(
+        vec![route(serde_json::json!({
+            "path_regex": "^/ui/(v1|v2)/",
+            "methods": ["get", "HEAD"],
+            "headers": {"X-Canary": "true"},
+            "upstream": "canary"
+        }))],
+        &[],
+    );
+
+    assert_eq!(resolve(&t, "any", &req("GET", "/ui/v2/app", &[("x-canary", "true")])), Some("canary".into()));
+    assert_eq!(resolve(&t, "any", &req("HEAD", "/ui/v1/app", &[("X-Canary", "true")])), Some("canary".into()));
+    assert_eq!(resolve(&t, "any", &req("POST", "/ui/v1/app", &[("X-Canary", "true")])), None);
+    assert_eq!(resolve(&t, "any", &req("GET", "/ui/v3/app", &[("X-Canary", "true")])), None);
+    assert_eq!(resolve(&t, "any", &req("GET", "/ui/v1/app", &[("X-Canary", "false")])), None);
+    assert_eq!(resolve(&t, "any", &req("GET", "/ui/v1/app", &[])), None);
+}
+
+#[test]
+fn host_to_upstream_precedence_table() {
+    let hosts = [
+        ("kibana.example.com", "exact"),
+        ("kibana", "label"),
+        ("*.eu.example.com", "long-wildcard"),
+        ("*.example.com", "wildcard"),
+        ("config-ui", "config-ui"),
+        ("config-service-ui", "config-service-ui"),
+    ];
+    let cases = [
+        ("kibana.example.com", Some("exact")),
+        ("KIBANA.example.com.", Some("exact")),
+        ("kibana.eu.example.com", Some("label")),
+        ("kibana.other.org", Some("label")),
+        ("grafana.eu.example.com", Some("long-wildcard")),
+        ("grafana.example.com", Some("wildcard")),
+        ("config-ui.other.org", Some("config-ui")),
+        ("config-service-ui.other.org", Some("config-service-ui")),
+        ("my-config-ui.other.org", None),
+        ("example.com", None),
+        ("kibana-old.other.org", None),
+    ];
+
+    // HashMap iteration order must not matter, so build the table repeatedly
+    for _ in 0..32 {
+        let t = table(vec![], &hosts);
+        for (host, expected) in cases {
+            assert_eq!(
+                resolve(&t, host, &req("GET", "/", &[])).as_deref(),
+                expected,
+                "host {host}"
+            );
+        }
+    }
+}
+
+#[test]
+fn overlapping_wildcard_and_suffix_keys_resolve_by_precedence() {
+    let hosts = [
+        ("*.example.com", "wildcard"),
+        ("*.eu.example.com", "eu-wildcard"),
+        ("*.prod.eu.example.com", "prod-eu-wildcard"),
+        ("eu.example.com", "exact"),
+        ("prod", "label"),
+    ];
+    let suffix_route = route(serde_json::json!({"host_suffix": "eu.example.com", "path_prefix": "/suffix/", "upstream": "suffix"}));
+    let cases = [
+        ("x.prod.eu.example.com", "/", Some("prod-eu-wildcard")),
+        ("x.eu.example.com", "/", Some("eu-wildcard")),
+        ("x.example.com", "/", Some("wildcard")),
+        ("eu.example.com", "/", Some("exact")),
+        ("prod.eu.example.com", "/", Some("label")),
+        ("prod.example.com", "/", Some("label")),
+        ("x.prod.eu.example.com", "/suffix/a", Some("suffix")),
+        ("eu.example.com", "/suffix/a", Some("suffix")),
+        ("x.example.com", "/suffix/a", Some("wildcard")),
+        ("example.com", "/", None),
+    ];
+
+    for _ in 0..32 {
+        let t = table(vec![suffix_route.clone()], &hosts);
+        for (host, path, expected) in cases {
+            assert_eq!(resolve(&t, host, &req("GET", path, &[])).as_deref(), expected, "host {host}{path}");
+        }
+    }
+}
+
+fn host_keys_err(hosts: &[(&str, &str)]) -> String {
+    let rp_config = RPConfig {
+        host_to_upstream: hosts
+            .iter()
+            .map(|(k, v)| (k.to_string(), details(v)))
+            .collect::<HashMap<_, _>>(),
+        ..RPConfig::default()
+    };
+    RouteTable::compile(&rp_config)
+        .expect_err("host keys should be rejected")
+        .to_string()
+}
+
+#[test]
+fn compile_rejects_ambiguous_host_to_upstream_keys() {
+    assert_eq!(
+        host_keys_err(&[("Kibana", "a"), ("kibana", "b")]),
+        "host_to_upstream: keys 'Kibana' and 'kibana' are ambiguous"
+    );
+    assert_eq!(
+        host_keys_err(&[("grafana.example.com.", "a"), ("grafana.example.com", "b")]),
+        "host_to_upstream: keys 'grafana.example.com' and 'grafana.example.com.' are ambiguous"
+    );
+    assert!(host_keys_err(&[("*.example.com", "a"), ("*.EXAMPLE.com", "b")]).contains("ambiguous"));
+}
+
+#[test]
+fn compile_rejects_malformed_host_to_upstream_keys() {
+    assert!(host_keys_err(&[("kibana:443", "a")]).contains("without scheme, port or path"));
+    assert!(host_keys_err(&[("https://kibana", "a")]).contains("without scheme, port or path"));
+    assert!(host_keys_err(&[("kib*na", "a")]).contains("leftmost"));
+    assert!(host_keys_err(&[("*.*.com", "a")]).contains("invalid wildcard"));
+    assert!(host_keys_err(&[("", "a")]).contains("empty key"));
+}
+
+fn compile_err(value: serde_json::Value) -> String {
+    let rp_config = RPConfig {
+        routes: vec![route(value)],
+        ..RPConfig::default()
+    };
+    RouteTable::compile(&rp_config)
+        .expect_err("route should be rejected")
+        .to_string()
+}
+
+#[test]
+fn compile_rejects_invalid_routes() {
+    assert!(compile_err(serde_json::json!({"path_regex": "(", "upstream": "x"})).contains("path_regex"));
+    assert!(compile_err(serde_json::json!({"path_prefix": "api", "upstream": "x"})).contains("must start with '/'"));
+    assert!(compile_err(serde_json::json!({"methods": ["GE T"], "upstream": "x"})).contains("invalid method"));
+    assert!(compile_err(serde_json::json!({"host": "a.*.com", "upstream": "x"})).contains("leftmost"));
+    assert!(compile_err(serde_json::json!({"host": "a.com", "host_suffix": "a.com", "upstream": "x"})).contains("mutually exclusive"));
+    assert!(compile_err(serde_json::json!({"host": "a.com", "upstream": ""})).contains("upstream must be set"));
+}
+
+#[test]
+fn compile_error_names_the_offending_route() {
+    let rp_config = RPConfig {
+        routes: vec![
+            route(serde_json::json!({"path_prefix": "/ok", "upstream": "x"})),
+            route(serde_json::json!({"path_prefix": "bad", "upstream": "x"})),
+        ],
+        ..RPConfig::default()
+    };
+
+    let err = RouteTable::compile(&rp_config).expect_err("second route is invalid").to_string();
+
+    assert!(err.starts_with("routes[1]:"));
+}
+
+fn tls_upstream(upstream: &str, tls: serde_json::Value) -> UpstreamDetails {
+    serde_json::from_value(serde_json::json!({ "upstream": upstream, "tls": tls })).expect("valid upstream details")
+}
+
+fn tls_err(hosts: Vec<(&str, UpstreamDetails)>) -> String {
+    let rp_config = RPConfig {
+        host_to_upstream: hosts.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+        ..RPConfig::default()
+    };
+    RouteTable::compile(&rp_config)
+        .expect_err("tls settings should be rejected")
+        .to_string()
+}
+
+#[test]
+fn upstream_tls_is_compiled_per_upstream() {
+    let rp_config = RPConfig {
+        routes: vec![route(serde_json::json!({
+            "path_prefix": "/api/",
+            "upstream": "api",
+            "tls": {"sni": "api.internal", "verify": "chain"}
+        }))],
+        host_to_upstream: HashMap::from([
+            ("api".to_string(), tls_upstream("api", serde_json::json!({"sni": "api.internal", "verify": "chain"}))),
+            ("ui".to_string(), details("ui")),
+        ]),
+        ..RPConfig::default()
+    };
+
+    let t = RouteTable::compile(&rp_config).expect("routes should compile");
+    let tls = t.upstream_tls("api").expect("api uses tls");
+
+    assert_eq!(tls.sni.as_deref(), Some("api.internal"));
+    assert_eq!(tls.verify, UpstreamTlsVerify::Chain);
+    assert!(tls.ca.is_none());
+    assert!(matches!(tls.client_cert, ClientCert::None));
+    assert!(t.upstream_tls("ui").is_none());
+}
+
+#[test]
+fn compile_rejects_invalid_upstream_tls() {
+    assert!(
+        tls_err(vec![("a", tls_upstream("svc", serde_json::json!({}))), ("b", details("svc"))])
+            .contains("upstream 'svc' is configured with different tls settings")
+    );
+    assert!(
+        tls_err(vec![("a", tls_upstream("svc", serde_json::json!({"ca_file": "/nonexistent/ca.pem"})))])
+            .contains("unable to read '/nonexistent/ca.pem'")
+    );
+    assert!(
+        tls_err(vec![("a", tls_upstream("svc", serde_json::json!({"client_cert_file": "/nonexistent/c.pem"})))])
+            .contains("must both be set")
+    );
+    assert!(
+        tls_err(vec![(
+            "a",
+            tls_upstream(
+                "svc",
+                serde_json::json!({"client_cert_file": "c", "client_key_file": "k", "vault_pki": {"role": "r", "common_name": "cn"}})
+            )
+        )])
+        .contains("mutually exclusive")
+    );
+}
+
+fn checked_upstream(upstream: &str, check: serde_json::Value) -> UpstreamDetails {
+    serde_json::from_value(serde_json::json!({ "upstream": upstream, "active_health_check": check }))
+        .expect("valid upstream details")
+}
+
+#[test]
+fn health_checks_are_compiled_per_upstream() {
+    let rp_config = RPConfig {
+        host_to_upstream: HashMap::from([
+            ("api".to_string(), checked_upstream("api", serde_json::json!({"path": "/health"}))),
+            ("ui".to_string(), details("ui")),
+        ]),
+        ..RPConfig::default()
+    };
+
+    let t = RouteTable::compile(&rp_config).expect("routes should compile");
+    let check = &t.health_checks()["api"];
+
+    assert_eq!(check.path, "/health");
+    assert_eq!((check.expected_status, check.interval_secs, check.rise, check.fall), (200, 5, 2, 3));
+    assert!(!t.health_checks().contains_key("ui"));
+
+    let err = |check: serde_json::Value| {
+        let rp_config = RPConfig {
+            host_to_upstream: HashMap::from([("a".to_string(), checked_upstream("svc", check))]),
+            ..RPConfig::default()
+        };
+        RouteTable::compile(&rp_config).expect_err("check should be rejected").to_string()
+    };
+    assert!(err(serde_json::json!({"path": "health"})).contains("must start with '/'"));
+    assert!(err(serde_json::json!({"expected_body": "ok"})).contains("requires an http path"));
+    assert!(err(serde_json::json!({"fall": 0})).contains("rise and fall"));
+    let rp_config = RPConfig {
+        host_to_upstream: HashMap::from([
+            ("a".to_string(), checked_upstream("svc", serde_json::json!({}))),
+            ("b".to_string(), details("svc")),
+        ]),
+        ..RPConfig::default()
+    };
+    assert!(
+        RouteTable::compile(&rp_config)
+            .expect_err("conflicting checks should be rejected")
+            .to_string()
+            .contains("upstream 'svc' is configured with different active_health_check settings")
+    );
+}
+
+#[test]
+fn load_balancing_defaults_to_round_robin_and_ketama_needs_a_key() {
+    let rp_config = RPConfig {
+        host_to_upstream: HashMap::from([
+            ("grafana".to_string(), serde_json::from_value(serde_json::json!({
+                "upstream": "grafana",
+                "load_balancing": {"algorithm": "ketama", "hash_on": "cookie", "key": "grafana_session"}
+            })).expect("valid upstream details")),
+            ("api".to_string(), details("api")),
+        ]),
+        ..RPConfig::default()
+    };
+    let t = RouteTable::compile(&rp_config).expect("routes should compile");
+
+    assert_eq!(t.load_balancing("api"), LoadBalancing::RoundRobin);
+    assert_eq!(
+        t.load_balancing("grafana"),
+        LoadBalancing::Ketama { hash_on: HashOn::Cookie, key: "grafana_session".to_string() }
+    );
+
+    let rp_config = RPConfig {
+        host_to_upstream: HashMap::from([("a".to_string(), serde_json::from_value(serde_json::json!({
+            "upstream": "svc",
+            "load_balancing": {"algorithm": "ketama", "hash_on": "header"}
+        })).expect("valid upstream details"))]),
+        ..RPConfig::default()
+    };
+    assert!(
+        RouteTable::compile(&rp_config)
+            .expect_err("ketama on a header needs its name")
+            .to_string()
+            .contains("key is required")
+    );
+}
+
+#[test]
+fn affinity_cookie_name_must_be_a_valid_token() {
+    let affinity = |value: serde_json::Value| RPConfig {
+        host_to_upstream: HashMap::from([("kibana".to_string(), serde_json::from_value(serde_json::json!({
+            "upstream": "pipeline-kibana",
+            "affinity": value
+        })).expect("valid upstream details"))]),
+        ..RPConfig::default()
+    };
+
+    let t = RouteTable::compile(&affinity(serde_json::json!({}))).expect("routes should compile");
+    assert_eq!(t.affinity("pipeline-kibana").map(|a| a.cookie_name.as_str()), Some("rproxy_affinity"));
+    assert!(t.affinity("other").is_none());
+
+    assert!(
+        RouteTable::compile(&affinity(serde_json::json!({"cookie_name": "a b;"})))
+            .expect_err("cookie name with separators")
+            .to_string()
+            .contains("invalid cookie_name")
+    );
+}
+
+#[test]
+fn host_rewrite_must_be_a_visible_host() {
+    let rewrite = |value: &str| RPConfig {
+        host_to_upstream: HashMap::from([("kibana".to_string(), serde_json::from_value(serde_json::json!({
+            "upstream": "pipeline-kibana",
+            "host_rewrite": value
+        })).expect("valid upstream details"))]),
+        ..RPConfig::default()
+    };
+
+    let t = RouteTable::compile(&rewrite("kibana.internal:5601")).expect("routes should compile");
+    assert_eq!(t.host_rewrite("pipeline-kibana"), Some("kibana.internal:5601"));
+    assert_eq!(t.host_rewrite("other"), None);
+
+    assert!(
+        RouteTable::compile(&rewrite("kibana internal"))
+            .expect_err("host with a space")
+            .to_string()
+            .contains("invalid host_rewrite")
+    );
+}
+
+#[test]
+fn plain_http_redirects_require_a_tls_listener() {
+    let config = |http_port: u64, tls_enabled: bool, plain_http: &str| RPConfig {
+        http_port,
+        tls_enabled,
+        host_to_upstream: HashMap::from([("kibana".to_string(), serde_json::from_value(serde_json::json!({
+            "upstream": "pipeline-kibana",
+            "plain_http": plain_http
+        })).expect("valid upstream details"))]),
+        ..RPConfig::default()
+    };
+
+    let e = RouteTable::compile(&config(8080, false, "redirect_301")).expect_err("nowhere to redirect to");
+    assert!(e.to_string().contains("upstream 'pipeline-kibana': plain_http must be \"proxy\""), "{e}");
+    assert!(RouteTable::compile(&config(8080, false, "redirect_308")).is_err());
+
+    RouteTable::compile(&config(8080, false, "proxy")).expect("plain http proxied");
+    RouteTable::compile(&config(8080, true, "redirect_301")).expect("redirected to the tls listener");
+    RouteTable::compile(&config(0, false, "redirect_301")).expect("no plain http listener");
+}
+
+#[test]
+fn sso_upstreams_require_keys_to_verify_id_tokens() {
+    let config = |oidc_issuer: &str, jwks_url: &str, id_token_issuer: &str| RPConfig {
+        oidc_issuer: oidc_issuer.to_string(),
+        jwks_url: jwks_url.to_string(),
+        id_token_issuer: id_token_issuer.to_string(),
+        host_to_upstream: HashMap::from([("kibana".to_string(), serde_json::from_value(serde_json::json!({
+            "upstream": "pipeline-kibana",
+            "sso_req": true
+        })).expect("valid upstream details"))]),
+        ..RPConfig::default()
+    };
+
+    let e = RouteTable::compile(&config("", "", "")).expect_err("no keys");
+    assert!(e.to_string().contains("upstream 'pipeline-kibana': sso_req requires oidc_issuer"), "{e}");
+    assert!(RouteTable::compile(&config("", "https://idp.example.com/keys", "")).is_err());
+    assert!(RouteTable::compile(&config("", "", "https://idp.example.com")).is_err());
+
+    RouteTable::compile(&config("https://idp.example.com", "", "")).expect("keys discovered");
+    RouteTable::compile(&config("", "https://idp.example.com/keys", "https://idp.example.com")).expect("static keys");
+}
+
+#[test]
+fn trusted_proxies_accept_cidrs_and_addresses() {
+    let trusted = |proxies: &[&str]| RPConfig {
+        trusted_proxies: proxies.iter().map(|p| p.to_string()).collect(),
+        ..RPConfig::default()
+    };
+
+    let t = RouteTable::compile(&trusted(&["10.1.2.3/8", "192.0.2.7", "2001:db8::/32"])).expect("routes should compile");
+    assert!(t.is_trusted_proxy("10.200.0.1".parse().expect("ip")));
+    assert!(t.is_trusted_proxy("192.0.2.7".parse().expect("ip")));
+    assert!(!t.is_trusted_proxy("192.0.2.8".parse().expect("ip")));
+    assert!(t.is_trusted_proxy("2001:db8::42".parse().expect("ip")));
+    assert!(!RouteTable::compile(&trusted(&[])).expect("routes should compile").is_trusted_proxy("10.0.0.1".parse().expect("ip")));
+
+    assert!(
+        RouteTable::compile(&trusted(&["10.0.0.0/33"]))
+            .expect_err("prefix too long")
+            .to_string()
+            .contains("trusted_proxies: invalid CIDR or address")
+    );
+}
+
+#[test]
+fn header_rules_are_compiled_per_upstream() {
+    let with_rules = |value: serde_json::Value| RPConfig {
+        host_to_upstream: HashMap::from([("kibana".to_string(), serde_json::from_value(serde_json::json!({
+            "upstream": "pipeline-kibana",
+            "response_headers": value
+        })).expect("valid upstream details"))]),
+        ..RPConfig::default()
+    };
+
+    let t = RouteTable::compile(&with_rules(serde_json::json!({"remove": ["Server"]}))).expect("routes should compile");
+    assert_eq!(t.response_headers("pipeline-kibana").map(|a| a.remove.len()), Some(1));
+    assert!(t.request_headers("pipeline-kibana").is_none());
+
+    assert!(
+        RouteTable::compile(&with_rules(serde_json::json!({"set": {"X-User": "{nope}"}})))
+            .expect_err("unknown variable")
+            .to_string()
+            .contains("upstream 'pipeline-kibana' response_headers: header 'X-User'")
+    );
+}
@@ -1,24 +1,48 @@
 #[cfg(test)]
 mod tests;
 
-use crate::config::RPConfig;
+use crate::config::{HashOn, RPConfig, TlsCertConfig, UpstreamTlsVerify, VaultPkiConfig};
+use crate::routing::RouteTable;
 use dashmap::DashMap;
+use jsonwebtoken::jwk::JwkSet;
 use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
 use oauth2::basic::{
-    BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenResponse,
+    BasicRevocationErrorResponse, BasicTokenIntrospectionResponse, BasicTokenType,
 };
-use oauth2::{EndpointNotSet, EndpointSet, StandardRevocableToken};
+use oauth2::{AuthUrl, EndpointNotSet, ExtraTokenFields, StandardRevocableToken, StandardTokenResponse, TokenUrl};
+use http::HeaderName;
 use pingora::lb::LoadBalancer;
+use pingora::lb::selection::{Consistent, Random};
 use pingora::prelude::RoundRobin;
+use pingora_core::protocols::tls::CaType;
+use pingora_core::utils::tls::CertKey;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use prometheus::{GaugeVec, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Registry};
 use serde_derive::{Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use tokio::sync::Notify;
 use aws_sdk_route53::Client;
 use serde::Deserialize;
 use crate::utils::{aws_r53_client, resolve_ip};
 
 pub type ConsulNodes = DashMap<String, Vec<ConsulNode>>;
-pub type LoadBalancers = DashMap<String, LoadBalancer<RoundRobin>>;
+pub type LoadBalancers = DashMap<String, Balancer>;
+/// Backend address -> requests being proxied to it.
+pub type InFlight = DashMap<String, usize>;
+pub type DrainingUpstreams = DashMap<String, Instant>;
+pub type ClientCerts = DashMap<VaultPkiConfig, IssuedClientCert>;
+/// Upstream -> backend address -> result of the active health checks.
+pub type BackendHealth = DashMap<String, HashMap<String, HealthStatus>>;
+/// Upstream -> backend address -> failures seen while proxying to it.
+pub type Outliers = DashMap<String, HashMap<String, OutlierState>>;
+pub type RetryBudgets = DashMap<String, RetryBudget>;
 
 #[derive(Serialize, Debug, Clone, Eq, PartialEq)]
 pub struct ConsulNode {
@@ -122,32 +146,285 @@ struct ConsulCheckRaw {
 
 pub struct Context {
     pub hostname: Option<String>,
+    /// Host pattern of the route the request matched, the host label of its metrics.
+    pub route: Option<String>,
     pub fully_qualified_upstream: Option<String>,
+    /// `Strict-Transport-Security` value for the response, HTTPS requests only.
+    pub hsts: Option<String>,
+    /// Address of the backend picked by `upstream_peer`.
+    pub backend: Option<String>,
+    /// Whether the outcome of the current attempt went into outlier detection.
+    pub outcome_recorded: bool,
+    /// Backends tried so far, in order; retries go to the ones not in here.
+    pub tried: Vec<String>,
+    /// Backend counted in `InFlight` for this request.
+    pub in_flight: Option<String>,
+    /// `Set-Cookie` pinning the client to the backend, when it is not pinned to it yet.
+    pub affinity_cookie: Option<String>,
+    pub started: Instant,
+    /// When the current attempt asked the balancer for a backend.
+    pub upstream_started: Option<Instant>,
+    /// Time from `upstream_started` to the response header of the backend.
+    pub upstream_latency: Option<Duration>,
+    /// `sub` of the SSO cookie, for upstreams behind SSO.
+    pub sso_subject: Option<String>,
+    /// `tid` of the SSO cookie.
+    pub sso_tenant: Option<String>,
+    /// `X-Request-Id` of the client, or a generated one; sent upstream and back.
+    pub request_id: String,
+    /// Carries `request_id` into every log line of the request.
+    pub span: tracing::Span,
+}
+
+/// One request in the access log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogEntry {
+    pub time: DateTime<Utc>,
+    pub client_ip: Option<String>,
+    pub host: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    /// 0 when no response was sent.
+    pub status: u16,
+    /// Request and response body bytes.
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub upstream: Option<String>,
+    pub backend: Option<String>,
+    pub upstream_latency: Option<Duration>,
+    pub total_latency: Duration,
+    pub sso_subject: Option<String>,
+    pub request_id: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct NetIqLoadBalancer {
     pub nodes: Arc<ConsulNodes>,
     pub balancers: Arc<LoadBalancers>,
+    pub draining: Arc<DrainingUpstreams>,
+    pub client_certs: Arc<ClientCerts>,
+    pub health: Arc<BackendHealth>,
+    pub outliers: Arc<Outliers>,
+    pub retry_budgets: Arc<RetryBudgets>,
+    pub in_flight: Arc<InFlight>,
     pub auth_verifier: AuthVerifier,
-    pub rp_config: RPConfig,
+    pub live_config: LiveConfig,
+    pub metrics: Metrics,
+}
+
+/// Runs the `active_health_check` of every upstream against its backends.
+#[derive(Clone)]
+pub struct HealthChecker {
+    pub lb: NetIqLoadBalancer,
+    pub connector: Arc<pingora_core::connectors::http::Connector>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    /// Consecutive results contradicting `healthy`, flipped at rise/fall.
+    #[serde(skip)]
+    pub streak: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutlierState {
+    pub consecutive_failures: u32,
+    /// Ejections in a row, doubling the ejection time each.
+    pub ejections: u32,
+    /// End of the last ejection, kept after it passed.
+    pub ejected_until: Option<Instant>,
+}
+
+/// Requests and retries of an upstream in the current one second window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryBudget {
+    pub window: Instant,
+    pub requests: u32,
+    pub retries: u32,
+}
+
+/// Backends of an upstream with the algorithm picking among them.
+pub enum Balancer {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    Random(LoadBalancer<Random>),
+    LeastConn(LoadBalancer<RoundRobin>),
+    P2c(LoadBalancer<RoundRobin>),
+    Ketama {
+        lb: LoadBalancer<Consistent>,
+        hash_on: HashOn,
+        key: String,
+    },
+}
+
+/// Config that can be swapped at runtime, together with the route table compiled from it.
+#[derive(Clone)]
+pub struct LiveConfig {
+    pub config_path: PathBuf,
+    pub rp_config: Arc<ArcSwap<RPConfig>>,
+    pub routes: Arc<ArcSwap<RouteTable>>,
+    pub discovery_changed: Arc<Notify>,
+    pub reload_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct ReloadReport {
+    pub applied: Vec<&'static str>,
+    pub requires_restart: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct ConfigReloader {
+    pub live_config: LiveConfig,
+}
+
+/// `HeaderRules` of an upstream with the names parsed and the values split into templates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderActions {
+    pub remove: Vec<HeaderName>,
+    pub set: Vec<(HeaderName, HeaderTemplate)>,
+    pub add: Vec<(HeaderName, HeaderTemplate)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderTemplate(pub Vec<TemplatePart>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Literal(String),
+    Var(TemplateVar),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateVar {
+    ClientIp,
+    Host,
+    Upstream,
+    RequestId,
+    Method,
+    Path,
+    Scheme,
+    SsoSubject,
+    SsoTenant,
+}
+
+/// What the header templates of a request are rendered with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TemplateValues {
+    pub client_ip: Option<String>,
+    pub host: Option<String>,
+    pub upstream: Option<String>,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub scheme: &'static str,
+    pub sso_subject: Option<String>,
+    pub sso_tenant: Option<String>,
+}
+
+/// TLS settings of an upstream with the CA bundle and client certificate loaded.
+#[derive(Debug)]
+pub struct UpstreamTls {
+    pub sni: Option<String>,
+    pub verify: UpstreamTlsVerify,
+    pub ca: Option<Arc<CaType>>,
+    pub client_cert: ClientCert,
+}
+
+#[derive(Debug)]
+pub enum ClientCert {
+    None,
+    Static(Arc<CertKey>),
+    /// Issued and renewed by the vault background service.
+    VaultPki(VaultPkiConfig),
+}
+
+/// A client certificate issued from Vault PKI and when to issue the next one.
+#[derive(Clone)]
+pub struct IssuedClientCert {
+    pub cert_key: Arc<CertKey>,
+    pub renew_at: Instant,
+}
+
+/// Prometheus metrics served on `/metrics`, recorded by the proxy and the
+/// background services.
+#[derive(Clone)]
+pub struct Metrics {
+    pub registry: Registry,
+    /// By host, upstream and status class.
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub connect_errors: IntCounterVec,
+    pub active_connections: IntGaugeVec,
+    pub consul_poll_duration: HistogramVec,
+    pub consul_poll_failures: IntCounterVec,
+    pub leader: IntGauge,
+    /// By action (register, deregister, reconcile) and result.
+    pub r53_actions: IntCounterVec,
+    pub cert_expiry: GaugeVec,
 }
 
 #[derive(Clone)]
 pub struct R53 {
     pub rp_config: RPConfig,
     pub runtime_state: RuntimeState,
+    pub metrics: Metrics,
 }
 
 #[derive(Clone)]
 pub struct Vault {
     pub rp_config: RPConfig,
+    pub live_config: LiveConfig,
+    pub certs: Arc<ArcSwapOption<CertBundle>>,
+    pub sni_certs: Arc<Vec<SniCert>>,
+    pub client_certs: Arc<ClientCerts>,
+    pub metrics: Metrics,
+}
+
+/// Listener key and certificate chain parsed from the Vault secret.
+pub struct CertBundle {
+    /// The decoded secret, kept to tell whether a renewal changed anything.
+    pub source: Vec<u8>,
+    pub key: PKey<Private>,
+    pub leaf: X509,
+    pub chain: Vec<X509>,
+}
+
+/// A certificate from `tls_certs`, selected by the SNI of the handshake.
+pub struct SniCert {
+    pub config: TlsCertConfig,
+    pub hosts: Vec<SniHost>,
+    pub bundle: ArcSwapOption<CertBundle>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniHost {
+    /// `portal.example.com`
+    Exact(String),
+    /// `*.example.com`, stored as `.example.com`
+    Wildcard(String),
+}
+
+/// Hands the current certificate to every TLS handshake, so a renewed
+/// certificate is served to new connections while open ones keep theirs.
+pub struct CertResolver {
+    pub certs: Arc<ArcSwapOption<CertBundle>>,
+    pub sni_certs: Arc<Vec<SniCert>>,
 }
 
 #[derive(Clone)]
 pub struct Web {
     pub rp_config: RPConfig,
     pub nodes: Arc<DashMap<String, Vec<ConsulNode>>>,
+    pub draining: Arc<DrainingUpstreams>,
+    pub health: Arc<BackendHealth>,
+    pub outliers: Arc<Outliers>,
+    pub metrics: Metrics,
+    pub live_config: LiveConfig,
     pub runtime_state: RuntimeState,
 }
 
@@ -157,6 +434,7 @@ pub struct LeaderRoutine {
     pub session_id: Arc<Mutex<String>>,
     pub http_client: reqwest::Client,
     pub runtime_state: RuntimeState,
+    pub metrics: Metrics,
 }
 
 #[derive(Clone)]
@@ -164,20 +442,85 @@ pub struct AuthVerifier {
     pub rp_config: RPConfig,
     pub decoding_key: DecodingKey,
     pub encoding_key: EncodingKey,
+    /// Signs rproxy's own cookies, derived from the JWT private key.
+    pub hmac_key: Vec<u8>,
     pub validation: Validation,
+    /// The authorization and token endpoints are set from `endpoints` for each login.
     pub client: oauth2::Client<
         oauth2::basic::BasicErrorResponse,
-        BasicTokenResponse,
+        OidcTokenResponse,
         BasicTokenIntrospectionResponse,
         StandardRevocableToken,
         BasicRevocationErrorResponse,
-        EndpointSet,
         EndpointNotSet,
         EndpointNotSet,
         EndpointNotSet,
-        EndpointSet,
+        EndpointNotSet,
+        EndpointNotSet,
     >,
     pub http_client: oauth2::reqwest::Client,
+    /// None until discovered from `oidc_issuer`.
+    pub endpoints: Arc<ArcSwapOption<OidcEndpoints>>,
+    pub jwks: Arc<Jwks>,
+}
+
+/// Where the IdP is, from `oidc_issuer` discovery or the static settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidcEndpoints {
+    /// `iss` of the ID tokens.
+    pub issuer: String,
+    pub auth_url: AuthUrl,
+    pub token_url: TokenUrl,
+    pub jwks_url: String,
+    pub userinfo_url: Option<String>,
+    pub end_session_url: Option<String>,
+}
+
+/// `/.well-known/openid-configuration` of the IdP, the parts rproxy uses.
+#[derive(Debug, Deserialize)]
+pub struct OidcProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    pub end_session_endpoint: Option<String>,
+}
+
+/// Token endpoint response with the OIDC `id_token`.
+pub type OidcTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    #[serde(default)]
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+/// Claims of the IdP's ID token that rproxy reads; `exp`, `iss` and `aud` are
+/// checked while decoding.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tid: Option<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+/// Signing keys of the IdP, fetched again when a token names a key not in here.
+pub struct Jwks {
+    pub http_client: reqwest::Client,
+    pub keys: ArcSwap<JwkSet>,
+    /// When `keys` were last fetched, None until the first fetch.
+    pub fetched: ArcSwapOption<Instant>,
+    /// When a fetch last started; locked only to decide on a fetch and to store its keys,
+    /// never across it.
+    pub attempted: Mutex<Option<Instant>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -192,9 +535,27 @@ pub struct AuthClaims {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AuthDecision {
-    Exchange { code: String },
+    Exchange { code: String, signed: OAuthState },
     RedirectToSso,
-    Proceed,
+    Proceed { subject: String, tenant: String },
+    /// An OAuth2 callback whose `state` doesn't match the signed state cookie.
+    StateMismatch,
+}
+
+/// Signed into the state cookie while the user logs in at the IdP.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OAuthState {
+    pub state: String,
+    /// Unix time the login has to finish by.
+    pub exp: u64,
+    /// Expected in the ID token, ties it to this login.
+    pub nonce: String,
+    /// PKCE code verifier sent with the code, when the IdP got a challenge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pkce_verifier: Option<String>,
+    /// Path and query the user asked for, where the login sends them back to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_to: Option<String>,
 }
 
 #[derive(Clone)]
@@ -11,11 +11,13 @@ use aws_sdk_route53::types::{
     Change, ChangeAction, ChangeBatch, ResourceRecord, ResourceRecordSet, RrType,
 };
 use aws_sdk_route53::{Client, Config};
-use jsonpath_rust::JsonPath;
-use serde_json::Value;
+use crate::config::UpstreamDetails;
 use crate::structs::{ConsulEntryRaw, ConsulNode};
+use pingora::http::RequestHeader;
+use std::time::Duration;
 
 const AWS_CHECK_IP_URL: &str = "http://checkip.amazonaws.com";
+const CONSUL_INDEX_HEADER: &str = "X-Consul-Index";
 
 pub fn resolve_ip() -> anyhow::Result<String> {
     let body = reqwest::blocking::get(AWS_CHECK_IP_URL)?.text()?;
@@ -37,22 +39,66 @@ pub async fn get_consul_nodes(
         consul_url, service_name, health_checks
     )).await?.text().await?;
 
+    parse_consul_nodes(&body, weighted, check_name, check_condition, weight_on_true, weight_on_false)
+}
+
+/// Runs a Consul blocking query for the upstream's health endpoint and returns
+/// the `X-Consul-Index` of the response together with the parsed nodes.
+pub async fn get_consul_nodes_blocking(
+    client: &reqwest::Client,
+    consul_url: &str,
+    upstream: &UpstreamDetails,
+    index: u64,
+    wait: Duration,
+) -> anyhow::Result<(u64, VecConsulNode)> {
+    // Consul adds up to wait/16 of jitter on top of the requested wait
+    let timeout = wait + wait / 16 + Duration::from_secs(5);
+    let response = client
+        .get(format!(
+            "{}v1/health/service/{}?{}=true&index={}&wait={}s",
+            consul_url,
+            upstream.upstream,
+            upstream.health_checks,
+            index,
+            wait.as_secs()
+        ))
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let returned_index = response
+        .headers()
+        .get(CONSUL_INDEX_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Missing or malformed {} header", CONSUL_INDEX_HEADER))?;
+    let body = response.text().await?;
+
+    let nodes = parse_consul_nodes(
+        &body,
+        upstream.weighted,
+        &upstream.check_name,
+        &upstream.check_condition,
+        upstream.weight_on_true,
+        upstream.weight_on_false,
+    )?;
+    Ok((returned_index, nodes))
+}
 
-    let raws: Vec<ConsulEntryRaw> = serde_json::from_str(&body)?;
-    let nodes: VecConsulNode = raws
+fn parse_consul_nodes(
+    body: &str,
+    weighted: bool,
+    check_name: &str,
+    check_condition: &str,
+    weight_on_true: u16,
+    weight_on_false: u16,
+) -> anyhow::Result<VecConsulNode> {
+    let raws: Vec<ConsulEntryRaw> = serde_json::from_str(body)?;
+    Ok(raws
         .into_iter()
         .map(|raw| ConsulNode::from_raw(raw, weighted, check_name, check_condition, weight_on_true, weight_on_false))
-        .collect();
-
-
-    if nodes.is_empty() {
-        return Err(anyhow::anyhow!(
-            "No consul healthy nodes found for service {}",
-            service_name
-        ));
-    }
-
-    Ok(nodes)
+        .collect())
 }
 
 pub async fn get_res_record_sets(
@@ -115,4 +161,16 @@ pub fn aws_r53_client(aws_access_key: String, aws_secret_key: String) -> Client
         .build();
 
     Client::from_conf(config)
-}
\ No newline at end of file
+}
+/// Value of the named cookie in the request's `Cookie` headers.
+pub fn cookie_value(req: &RequestHeader, name: &str) -> Option<String> {
+    req.headers
+        .get_all("Cookie")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .find_map(|part| {
+            let (k, v) = part.trim().split_once('=')?;
+            (k == name).then(|| v.to_string())
+        })
+}
@@ -1,31 +1,74 @@
-use crate::config::RPConfig;
-use crate::structs::Vault;
-use crate::{log_error, log_info};
-use anyhow::{Error, Result};
+#[cfg(test)]
+mod tests;
+
+use crate::config::{RPConfig, TlsCertConfig, VaultPkiConfig};
+use crate::structs::{
+    CertBundle, CertResolver, ClientCerts, IssuedClientCert, LiveConfig, Metrics, SniCert, SniHost, Vault,
+};
+use crate::{log_error, log_info, log_trace};
+use anyhow::{Error, Result, anyhow, bail};
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use openssl::asn1::{Asn1Time, TimeDiff};
+use openssl::pkey::PKey;
+use openssl::ssl::{NameType, SslRef};
+use openssl::x509::{X509, X509VerifyResult};
 use pem::parse_many;
-use std::collections::HashMap;
+use pingora_core::listeners::TlsAccept;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_core::tls::ext::{ssl_add_chain_cert, ssl_use_certificate, ssl_use_private_key};
+use pingora_core::utils::tls::CertKey;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
 use tokio_retry::Retry;
 use tokio_retry::strategy::{ExponentialBackoff, jitter};
 use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
-use vaultrs::kv2;
+use vaultrs::api::pki::requests::GenerateCertificateRequest;
+use vaultrs::{kv2, pki};
 use vaultrs_login::LoginClient;
 use vaultrs_login::engines::approle::AppRoleLogin;
 
+/// How often Vault PKI client certificates are checked for issuance or renewal.
+const CLIENT_CERT_CHECK: Duration = Duration::from_secs(30);
+/// Certificates issued this shortly before being fetched are accepted, the issuer's
+/// clock may be ahead of ours.
+const NOT_BEFORE_SKEW_SECS: i64 = 300;
+
 impl Vault {
-    pub fn new(rp_config: RPConfig) -> Self {
-        Self { rp_config }
+    pub fn try_new(
+        rp_config: RPConfig,
+        live_config: LiveConfig,
+        client_certs: Arc<ClientCerts>,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        let sni_certs = rp_config
+            .tls_certs
+            .iter()
+            .enumerate()
+            .map(|(i, c)| SniCert::new(c).map_err(|e| anyhow!("tls_certs[{}]: {}", i, e)))
+            .collect::<Result<Vec<SniCert>>>()?;
+        Ok(Self {
+            rp_config,
+            live_config,
+            certs: Arc::new(ArcSwapOption::empty()),
+            sni_certs: Arc::new(sni_certs),
+            client_certs,
+            metrics,
+        })
     }
 
     pub fn non_async_fetch_ssl_certs(&self) {
         log_info!("Fetching certs...");
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
-            match fetch_ssl_certs(&self.rp_config).await {
+            match self.refresh_certs().await {
                 Ok(_) => {}
                 Err(err) => {
                     log_error!("{:?}", err);
@@ -34,15 +77,374 @@ impl Vault {
             };
         });
     }
+
+    pub fn cert_resolver(&self) -> CertResolver {
+        CertResolver {
+            certs: self.certs.clone(),
+            sni_certs: self.sni_certs.clone(),
+        }
+    }
+
+    /// Refreshes the default certificate and every SNI certificate. One of them
+    /// failing does not keep the others from being renewed.
+    async fn refresh_certs(&self) -> Result<bool> {
+        let mut changed = false;
+        let mut errors = Vec::new();
+        match fetch_ssl_certs(&self.rp_config, &self.rp_config.path_to_cert_secret).await {
+            Ok(source) => match self.apply_secret(source) {
+                Ok(c) => changed |= c,
+                Err(e) => errors.push(format!("default certificate: {:?}", e)),
+            },
+            Err(e) => errors.push(format!("default certificate: {:?}", e)),
+        }
+        for sni in self.sni_certs.iter() {
+            match sni.refresh(&self.rp_config).await {
+                Ok(c) => changed |= c,
+                Err(e) => errors.push(format!("certificate for {}: {:?}", sni.config.hosts.join(","), e)),
+            }
+        }
+        self.record_cert_expiry();
+        if errors.is_empty() {
+            Ok(changed)
+        } else {
+            Err(anyhow!(errors.join("; ")))
+        }
+    }
+
+    /// Swaps in a changed default certificate and writes it to the cert files.
+    fn apply_secret(&self, source: Vec<u8>) -> Result<bool> {
+        swap_if_changed(&self.certs, source, |bundle| bundle.write_files(&self.rp_config))
+    }
+
+    /// Issues the Vault PKI client certificates of upstreams that have none yet or
+    /// whose certificate is due for renewal, and forgets the ones no longer configured.
+    async fn refresh_client_certs(&self) {
+        let rp_config = self.live_config.rp_config.load();
+        let configured: HashSet<&VaultPkiConfig> = rp_config
+            .all_upstreams()
+            .filter_map(|u| u.tls.as_ref()?.vault_pki.as_ref())
+            .collect();
+        self.client_certs.retain(|pki, _| configured.contains(pki));
+
+        let now = Instant::now();
+        for pki in configured {
+            if self.client_certs.get(pki).is_some_and(|issued| issued.renew_at > now) {
+                continue;
+            }
+            match issue_client_cert(&self.rp_config, pki).await {
+                Ok(issued) => {
+                    log_info!("Client certificate {} issued from {}/{}", pki.common_name, pki.mount, pki.role);
+                    self.client_certs.insert(pki.clone(), issued);
+                }
+                Err(e) => log_error!("Unable to issue client certificate {}: {:?}", pki.common_name, e),
+            }
+        }
+        self.record_cert_expiry();
+    }
+
+    /// Exposes the expiry of every certificate in use, forgetting replaced ones.
+    fn record_cert_expiry(&self) {
+        self.metrics.cert_expiry.reset();
+        if let Some(bundle) = self.certs.load().as_ref() {
+            self.metrics.record_cert_expiry("listener", "default", &bundle.leaf);
+        }
+        for sni in self.sni_certs.iter() {
+            if let Some(bundle) = sni.bundle.load().as_ref() {
+                self.metrics.record_cert_expiry("sni", &sni.config.hosts.join(","), &bundle.leaf);
+            }
+        }
+        for issued in self.client_certs.iter() {
+            self.metrics.record_cert_expiry("client", &issued.key().common_name, issued.cert_key.leaf());
+        }
+    }
+}
+
+/// Validates a changed secret and serves it to new handshakes. Returns false
+/// when the secret is the one already in use.
+fn swap_if_changed(
+    slot: &ArcSwapOption<CertBundle>,
+    source: Vec<u8>,
+    on_change: impl FnOnce(&CertBundle) -> Result<()>,
+) -> Result<bool> {
+    if let Some(current) = slot.load().as_ref()
+        && current.source == source
+    {
+        return Ok(false);
+    }
+    let bundle = CertBundle::parse(source)?;
+    on_change(&bundle)?;
+    log_info!(
+        "Certificate updated, subject {:?}, expires {}",
+        bundle.leaf.subject_name(),
+        bundle.leaf.not_after()
+    );
+    slot.store(Some(Arc::new(bundle)));
+    Ok(true)
+}
+
+impl SniCert {
+    fn new(config: &TlsCertConfig) -> Result<Self> {
+        if config.hosts.is_empty() {
+            bail!("hosts must not be empty");
+        }
+        let from_files = !config.cert_file.is_empty() || !config.key_file.is_empty();
+        match (config.vault_path.is_empty(), from_files) {
+            (false, true) => bail!("vault_path and cert_file/key_file are mutually exclusive"),
+            (true, false) => bail!("either vault_path or cert_file and key_file must be set"),
+            (true, true) if config.cert_file.is_empty() || config.key_file.is_empty() => {
+                bail!("cert_file and key_file must both be set")
+            }
+            _ => {}
+        }
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|h| SniHost::parse(h))
+            .collect::<Result<Vec<SniHost>>>()?;
+        Ok(Self {
+            config: config.clone(),
+            hosts,
+            bundle: ArcSwapOption::empty(),
+        })
+    }
+
+    async fn refresh(&self, conf: &RPConfig) -> Result<bool> {
+        let source = if self.config.vault_path.is_empty() {
+            [std::fs::read(&self.config.key_file)?, std::fs::read(&self.config.cert_file)?].concat()
+        } else {
+            fetch_ssl_certs(conf, &self.config.vault_path).await?
+        };
+        swap_if_changed(&self.bundle, source, |_| Ok(()))
+    }
+}
+
+impl SniHost {
+    fn parse(host: &str) -> Result<Self> {
+        let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() {
+            bail!("empty host");
+        }
+        if let Some(suffix) = host.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                bail!("invalid wildcard host '{}'", host);
+            }
+            return Ok(SniHost::Wildcard(format!(".{suffix}")));
+        }
+        if host.contains('*') {
+            bail!("wildcard is only allowed as the leftmost label in '{}'", host);
+        }
+        Ok(SniHost::Exact(host))
+    }
+
+    /// A wildcard covers exactly one label, as it does in a certificate.
+    fn matches(&self, server_name: &str) -> bool {
+        match self {
+            SniHost::Exact(host) => server_name == host,
+            SniHost::Wildcard(suffix) => server_name
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Vault {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let renew_secs = self.rp_config.vault_cert_renew_secs;
+        let renew_listener = self.rp_config.tls_enabled && renew_secs > 0;
+        if self.rp_config.tls_enabled && !renew_listener {
+            log_info!("Certificate renewal disabled");
+        }
+        let mut renew = tokio::time::interval(Duration::from_secs(renew_secs.max(1)));
+        // the first tick fires immediately, the certificates were just fetched at startup
+        renew.tick().await;
+        let mut client_certs = tokio::time::interval(CLIENT_CERT_CHECK);
+        loop {
+            tokio::select! {
+                _ = renew.tick(), if renew_listener => {
+                    match self.refresh_certs().await {
+                        Ok(true) => {}
+                        Ok(false) => log_trace!("Certificates unchanged"),
+                        Err(e) => log_error!("Certificate renewal failed, keeping current certificates: {}", e),
+                    }
+                }
+                _ = client_certs.tick() => {
+                    self.refresh_client_certs().await;
+                }
+                _ = shutdown.changed() => {
+                    log_info!("Shutting down (vault background service)...");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl CertResolver {
+    /// Picks the certificate for the SNI of a handshake: exact hosts first, then
+    /// wildcards, then the default certificate.
+    fn select(&self, server_name: Option<&str>) -> Option<Arc<CertBundle>> {
+        let sni = server_name.and_then(|name| {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            let find = |exact: bool| {
+                self.sni_certs
+                    .iter()
+                    .filter(|cert| {
+                        cert.hosts
+                            .iter()
+                            .any(|h| matches!(h, SniHost::Exact(_)) == exact && h.matches(&name))
+                    })
+                    .find_map(|cert| cert.bundle.load_full())
+            };
+            find(true).or_else(|| find(false))
+        });
+        sni.or_else(|| self.certs.load_full())
+    }
+}
+
+#[async_trait]
+impl TlsAccept for CertResolver {
+    async fn certificate_callback(&self, ssl: &mut SslRef) {
+        let server_name = ssl.servername(NameType::HOST_NAME).map(str::to_string);
+        match self.select(server_name.as_deref()) {
+            Some(bundle) => {
+                if let Err(e) = bundle.use_in(ssl) {
+                    log_error!("Unable to set certificate on TLS handshake: {}", e);
+                }
+            }
+            None => log_error!("No certificate loaded for {:?}, TLS handshake will fail", server_name),
+        }
+    }
+}
+
+impl CertBundle {
+    /// Parses a secret holding the private key followed by the certificate chain,
+    /// leaf first. The key has to belong to the leaf, every certificate has to be
+    /// issued by the next one and none of them may be expired.
+    pub fn parse(source: Vec<u8>) -> Result<Self> {
+        let pem = parse_many(&source)?;
+        let Some((key, certs)) = pem.split_first() else {
+            bail!("certificate secret is empty");
+        };
+        let key = PKey::private_key_from_pem(key.to_string().as_bytes())
+            .map_err(|e| anyhow!("invalid private key: {}", e))?;
+        let mut certs = certs
+            .iter()
+            .map(|c| X509::from_der(c.contents()).map_err(|e| anyhow!("invalid certificate: {}", e)))
+            .collect::<Result<Vec<X509>>>()?;
+        if certs.is_empty() {
+            bail!("certificate secret holds no certificate");
+        }
+
+        let leaf = certs.remove(0);
+        if !leaf.public_key()?.public_eq(&key) {
+            bail!("private key does not match certificate {:?}", leaf.subject_name());
+        }
+        let now = Asn1Time::days_from_now(0)?;
+        let unix_now = i64::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())?;
+        let skewed = Asn1Time::from_unix(unix_now + NOT_BEFORE_SKEW_SECS)?;
+        for cert in std::iter::once(&leaf).chain(&certs) {
+            if cert.not_after() <= now {
+                bail!("certificate {:?} expired at {}", cert.subject_name(), cert.not_after());
+            }
+            if cert.not_before() > skewed {
+                bail!("certificate {:?} is not valid before {}", cert.subject_name(), cert.not_before());
+            }
+        }
+        let mut issued = &leaf;
+        for issuer in &certs {
+            if issuer.issued(issued) != X509VerifyResult::OK {
+                bail!(
+                    "certificate chain is broken, {:?} was not issued by {:?}",
+                    issued.subject_name(),
+                    issuer.subject_name()
+                );
+            }
+            issued = issuer;
+        }
+
+        Ok(Self {
+            source,
+            key,
+            leaf,
+            chain: certs,
+        })
+    }
+
+    fn use_in(&self, ssl: &mut SslRef) -> Result<()> {
+        ssl_use_certificate(ssl, &self.leaf)?;
+        for cert in &self.chain {
+            ssl_add_chain_cert(ssl, cert)?;
+        }
+        ssl_use_private_key(ssl, &self.key)?;
+        Ok(())
+    }
+
+    /// Keeps the cert files in sync with the served certificate.
+    fn write_files(&self, conf: &RPConfig) -> Result<()> {
+        let pem = parse_many(&self.source)?;
+
+        //Writing private [0] cert to separate a file
+        std::fs::write(conf.tls_private_cert.clone(), pem[0].clone().to_string())?;
+
+        //Writing another cert chain [1..] to separate a file
+        let mut f = File::create(conf.tls_chain_cert.clone())?;
+        for i in pem[1..].iter() {
+            f.write_all(i.to_string().as_ref())?;
+        }
+        Ok(())
+    }
 }
 
-async fn fetch_ssl_certs(conf: &RPConfig) -> Result<(), Error> {
+async fn fetch_ssl_certs(conf: &RPConfig, path: &str) -> Result<Vec<u8>, Error> {
     let retry_strategy = ExponentialBackoff::from_millis(10).map(jitter).take(4);
 
-    Retry::spawn(retry_strategy, move || internal_fetch_ssl_certs(conf)).await
+    Retry::spawn(retry_strategy, move || internal_fetch_ssl_certs(conf, path)).await
 }
 
-async fn internal_fetch_ssl_certs(conf: &RPConfig) -> Result<(), Error> {
+async fn internal_fetch_ssl_certs(conf: &RPConfig, path: &str) -> Result<Vec<u8>, Error> {
+    let client = login(conf).await?;
+
+    let full_cert: HashMap<String, String> = kv2::read(&client, "kv2", path).await?;
+
+    let data = full_cert
+        .get("data")
+        .ok_or_else(|| anyhow!("secret {} has no data field", path))?;
+    Ok(BASE64_STANDARD.decode(data)?)
+}
+
+/// Issues a client certificate and schedules its renewal once two thirds of its
+/// lifetime have passed.
+async fn issue_client_cert(conf: &RPConfig, pki: &VaultPkiConfig) -> Result<IssuedClientCert, Error> {
+    let client = login(conf).await?;
+
+    let mut request = GenerateCertificateRequest::builder();
+    request.common_name(pki.common_name.clone());
+    if !pki.ttl.is_empty() {
+        request.ttl(pki.ttl.clone());
+    }
+    let issued = pki::cert::generate(&client, &pki.mount, &pki.role, Some(&mut request)).await?;
+
+    let leaf = X509::from_pem(issued.certificate.as_bytes())?;
+    let mut certs = vec![leaf];
+    for ca in issued.ca_chain.unwrap_or_default() {
+        certs.push(X509::from_pem(ca.as_bytes())?);
+    }
+    let key = PKey::private_key_from_pem(issued.private_key.as_bytes())?;
+
+    let now = Asn1Time::days_from_now(0)?;
+    let left = now.diff(certs[0].not_after())?;
+    let lifetime = certs[0].not_before().diff(certs[0].not_after())?;
+    let secs = |d: TimeDiff| i64::from(d.days) * 86400 + i64::from(d.secs);
+    let renew_in = (secs(left) - secs(lifetime) / 3).max(0);
+
+    Ok(IssuedClientCert {
+        cert_key: Arc::new(CertKey::new(certs, key)),
+        renew_at: Instant::now() + Duration::from_secs(renew_in.unsigned_abs()),
+    })
+}
+
+async fn login(conf: &RPConfig) -> Result<VaultClient, Error> {
     let mut client = VaultClient::new(
         VaultClientSettingsBuilder::default()
             .address(conf.vault_address.clone())
@@ -54,21 +456,5 @@ async fn internal_fetch_ssl_certs(conf: &RPConfig) -> Result<(), Error> {
     let login = AppRoleLogin { role_id, secret_id };
 
     client.login("approle", &login).await?;
-
-    let full_cert: HashMap<String, String> =
-        kv2::read(&client, "kv2", &conf.path_to_cert_secret.clone()).await?;
-
-    let vec = BASE64_STANDARD.decode(full_cert.get("data").unwrap())?;
-    let pem = parse_many(vec)?;
-
-    //Writing private [0] cert to separate a file
-    std::fs::write(conf.tls_private_cert.clone(), pem[0].clone().to_string())?;
-
-    //Writing another cert chain [1..] to separate a file
-    let mut f = File::create(conf.tls_chain_cert.clone())?;
-    for i in pem[1..].iter() {
-        f.write_all(i.to_string().as_ref())?;
-    }
-    log_info!("Certificate updated...");
-    Ok(())
+    Ok(client)
 }
@@ -0,0 +1,459 @@
+use super::*;
+use crate::config::UpstreamDetails;
+use crate::routing::RouteTable;
+use axum::Router;
+use axum::extract::{Path as AxumPath, State};
+use axum::routing::post;
+use axum::Json;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::Private;
+use openssl::ssl::{Ssl, SslContext, SslMethod};
+use openssl::x509::X509NameBuilder;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static FILE_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("p256 group");
+    PKey::from_ec_key(EcKey::generate(&group).expect("ec key")).expect("pkey")
+}
+
+/// Builds a certificate for `cn`, self signed unless an issuer is given.
+fn cert(cn: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, not_after: Asn1Time) -> X509 {
+    cert_valid_from(cn, key, issuer, days(0), not_after)
+}
+
+fn cert_valid_from(
+    cn: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+    not_before: Asn1Time,
+    not_after: Asn1Time,
+) -> X509 {
+    let mut name = X509NameBuilder::new().expect("name builder");
+    name.append_entry_by_nid(Nid::COMMONNAME, cn).expect("common name");
+    let name = name.build();
+
+    let mut builder = X509::builder().expect("x509 builder");
+    builder.set_version(2).expect("version");
+    let serial = BigNum::from_u32(FILE_SEQ.fetch_add(1, Ordering::SeqCst) as u32 + 1).expect("serial");
+    builder.set_serial_number(&serial.to_asn1_integer().expect("serial")).expect("serial");
+    builder.set_subject_name(&name).expect("subject");
+    builder.set_pubkey(key).expect("pubkey");
+    builder.set_not_before(&not_before).expect("not before");
+    builder.set_not_after(&not_after).expect("not after");
+    match issuer {
+        Some((ca, ca_key)) => {
+            builder.set_issuer_name(ca.subject_name()).expect("issuer");
+            builder.sign(ca_key, MessageDigest::sha256()).expect("sign");
+        }
+        None => {
+            builder.set_issuer_name(&name).expect("issuer");
+            builder.sign(key, MessageDigest::sha256()).expect("sign");
+        }
+    }
+    builder.build()
+}
+
+fn days(n: u32) -> Asn1Time {
+    Asn1Time::days_from_now(n).expect("asn1 time")
+}
+
+/// Secret layout as stored in Vault: private key first, then the chain leaf first.
+fn secret(key: &PKey<Private>, chain: &[&X509]) -> Vec<u8> {
+    let mut out = key.private_key_to_pem_pkcs8().expect("key pem");
+    for c in chain {
+        out.extend(c.to_pem().expect("cert pem"));
+    }
+    out
+}
+
+struct Issued {
+    ca: X509,
+    key: PKey<Private>,
+    leaf: X509,
+}
+
+fn issue(cn: &str) -> Issued {
+    let ca_key = key();
+    let ca = cert("test-ca", &ca_key, None, days(30));
+    let key = key();
+    let leaf = cert(cn, &key, Some((&ca, &ca_key)), days(10));
+    Issued { ca, key, leaf }
+}
+
+impl Issued {
+    fn secret(&self) -> Vec<u8> {
+        secret(&self.key, &[&self.leaf, &self.ca])
+    }
+}
+
+fn try_vault(rp_config: RPConfig) -> Result<Vault> {
+    let live_config = LiveConfig::new(PathBuf::new(), rp_config.clone(), RouteTable::default());
+    Vault::try_new(rp_config, live_config, Arc::new(ClientCerts::new()), Metrics::try_new().expect("metrics should register"))
+}
+
+fn vault() -> Vault {
+    let n = FILE_SEQ.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir();
+    let prefix = format!("rproxy-vault-{}-{n}", std::process::id());
+    try_vault(RPConfig {
+        tls_private_cert: dir.join(format!("{prefix}-private.pem")).to_string_lossy().into_owned(),
+        tls_chain_cert: dir.join(format!("{prefix}-chain.pem")).to_string_lossy().into_owned(),
+        ..RPConfig::default()
+    })
+    .expect("no tls_certs to reject")
+}
+
+fn common_name(cert: &X509) -> String {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|e| e.data().as_utf8().ok())
+        .map(|s| s.to_string())
+        .unwrap_or_default()
+}
+
+fn remove_files(vault: &Vault) {
+    let _ = std::fs::remove_file(&vault.rp_config.tls_private_cert);
+    let _ = std::fs::remove_file(&vault.rp_config.tls_chain_cert);
+}
+
+fn der(cert: &X509) -> Vec<u8> {
+    cert.to_der().expect("der")
+}
+
+fn chain_file(vault: &Vault) -> Vec<Vec<u8>> {
+    let chain = std::fs::read(&vault.rp_config.tls_chain_cert).expect("chain file written");
+    X509::stack_from_pem(&chain).expect("chain file parses").iter().map(der).collect()
+}
+
+async fn served_common_name(resolver: &CertResolver) -> Option<String> {
+    served_for_sni(resolver, None).await
+}
+
+async fn served_for_sni(resolver: &CertResolver, server_name: Option<&str>) -> Option<String> {
+    let ctx = SslContext::builder(SslMethod::tls()).expect("ssl context").build();
+    let mut ssl = Ssl::new(&ctx).expect("ssl");
+    if let Some(name) = server_name {
+        ssl.set_hostname(name).expect("sni");
+    }
+    resolver.certificate_callback(&mut ssl).await;
+    ssl.certificate().map(|c| common_name(&c.to_owned()))
+}
+
+#[test]
+fn parse_accepts_key_matching_leaf_and_chain() {
+    let issued = issue("portal.example.com");
+
+    let bundle = CertBundle::parse(issued.secret()).expect("valid bundle");
+
+    assert_eq!(common_name(&bundle.leaf), "portal.example.com");
+    assert_eq!(bundle.chain.len(), 1);
+    assert_eq!(common_name(&bundle.chain[0]), "test-ca");
+}
+
+#[test]
+fn parse_rejects_key_that_does_not_belong_to_leaf() {
+    let issued = issue("portal.example.com");
+
+    let err = CertBundle::parse(secret(&key(), &[&issued.leaf, &issued.ca])).err().expect("mismatched key");
+
+    assert!(err.to_string().contains("does not match"));
+}
+
+#[test]
+fn parse_rejects_expired_certificate() {
+    let leaf_key = key();
+    let expired = cert("old.example.com", &leaf_key, None, Asn1Time::from_unix(1_000_000).expect("past"));
+
+    let err = CertBundle::parse(secret(&leaf_key, &[&expired])).err().expect("expired cert");
+
+    assert!(err.to_string().contains("expired"));
+}
+
+#[test]
+fn parse_tolerates_issuer_clock_skew_on_not_before() {
+    let leaf_key = key();
+    let from_now = |secs: u64| {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).expect("after epoch") + Duration::from_secs(secs);
+        Asn1Time::from_unix(at.as_secs() as i64).expect("asn1 time")
+    };
+
+    let skewed = cert_valid_from("new.example.com", &leaf_key, None, from_now(120), days(10));
+    CertBundle::parse(secret(&leaf_key, &[&skewed])).expect("issued two minutes ahead");
+
+    let future = cert_valid_from("new.example.com", &leaf_key, None, from_now(3600), days(10));
+    let err = CertBundle::parse(secret(&leaf_key, &[&future])).err().expect("not valid yet");
+    assert!(err.to_string().contains("is not valid before"), "{err}");
+}
+
+#[test]
+fn parse_rejects_broken_chain() {
+    let issued = issue("portal.example.com");
+    let other_ca = cert("other-ca", &key(), None, days(30));
+
+    let err = CertBundle::parse(secret(&issued.key, &[&issued.leaf, &other_ca])).err().expect("broken chain");
+
+    assert!(err.to_string().contains("chain is broken"));
+}
+
+#[test]
+fn parse_rejects_secret_without_certificate() {
+    let err = CertBundle::parse(key().private_key_to_pem_pkcs8().expect("key pem")).err().expect("no cert");
+
+    assert!(err.to_string().contains("no certificate"));
+}
+
+#[tokio::test]
+async fn renewed_secret_is_served_to_new_handshakes() {
+    let vault = vault();
+    let resolver = vault.cert_resolver();
+    let first = issue("first.example.com");
+    let second = issue("second.example.com");
+
+    assert!(vault.apply_secret(first.secret()).expect("first secret applies"));
+    assert_eq!(served_common_name(&resolver).await.as_deref(), Some("first.example.com"));
+
+    assert!(!vault.apply_secret(first.secret()).expect("unchanged secret"));
+
+    assert!(vault.apply_secret(second.secret()).expect("renewed secret applies"));
+    assert_eq!(served_common_name(&resolver).await.as_deref(), Some("second.example.com"));
+    assert_eq!(chain_file(&vault), [der(&second.leaf), der(&second.ca)]);
+    remove_files(&vault);
+}
+
+#[tokio::test]
+async fn invalid_renewal_keeps_current_certificate() {
+    let vault = vault();
+    let resolver = vault.cert_resolver();
+    let first = issue("first.example.com");
+    let second = issue("second.example.com");
+    vault.apply_secret(first.secret()).expect("first secret applies");
+
+    assert!(vault.apply_secret(secret(&first.key, &[&second.leaf, &second.ca])).is_err());
+
+    assert_eq!(served_common_name(&resolver).await.as_deref(), Some("first.example.com"));
+    assert_eq!(chain_file(&vault), [der(&first.leaf), der(&first.ca)]);
+    remove_files(&vault);
+}
+
+fn tls_cert(value: serde_json::Value) -> TlsCertConfig {
+    serde_json::from_value(value).expect("valid tls cert config")
+}
+
+fn sni_vault(tls_certs: Vec<TlsCertConfig>) -> Vault {
+    try_vault(RPConfig {
+        tls_certs,
+        ..RPConfig::default()
+    })
+    .expect("valid tls_certs")
+}
+
+fn load(sni: &SniCert, issued: &Issued) {
+    swap_if_changed(&sni.bundle, issued.secret(), |_| Ok(())).expect("sni secret applies");
+}
+
+#[tokio::test]
+async fn sni_selects_exact_then_wildcard_then_default() {
+    let vault = sni_vault(vec![
+        tls_cert(serde_json::json!({"hosts": ["*.example.com"], "vault_path": "certs/wildcard"})),
+        tls_cert(serde_json::json!({"hosts": ["Portal.Example.com"], "vault_path": "certs/portal"})),
+    ]);
+    load(&vault.sni_certs[0], &issue("wildcard"));
+    load(&vault.sni_certs[1], &issue("portal"));
+    swap_if_changed(&vault.certs, issue("default").secret(), |_| Ok(())).expect("default applies");
+    let resolver = vault.cert_resolver();
+
+    assert_eq!(served_for_sni(&resolver, Some("portal.example.com")).await.as_deref(), Some("portal"));
+    assert_eq!(served_for_sni(&resolver, Some("grafana.example.com")).await.as_deref(), Some("wildcard"));
+    assert_eq!(served_for_sni(&resolver, Some("a.b.example.com")).await.as_deref(), Some("default"));
+    assert_eq!(served_for_sni(&resolver, Some("example.com")).await.as_deref(), Some("default"));
+    assert_eq!(served_for_sni(&resolver, None).await.as_deref(), Some("default"));
+}
+
+#[tokio::test]
+async fn sni_cert_not_loaded_yet_falls_back_to_default() {
+    let vault = sni_vault(vec![tls_cert(serde_json::json!({"hosts": ["portal.example.com"], "vault_path": "certs/portal"}))]);
+    swap_if_changed(&vault.certs, issue("default").secret(), |_| Ok(())).expect("default applies");
+
+    assert_eq!(served_for_sni(&vault.cert_resolver(), Some("portal.example.com")).await.as_deref(), Some("default"));
+}
+
+#[tokio::test]
+async fn cert_expiry_is_exposed_for_loaded_certificates() {
+    let vault = sni_vault(vec![
+        tls_cert(serde_json::json!({"hosts": ["portal.example.com", "*.portal.example.com"], "vault_path": "certs/portal"})),
+        tls_cert(serde_json::json!({"hosts": ["grafana.example.com"], "vault_path": "certs/grafana"})),
+    ]);
+    load(&vault.sni_certs[0], &issue("portal"));
+    swap_if_changed(&vault.certs, issue("default").secret(), |_| Ok(())).expect("default applies");
+
+    vault.record_cert_expiry();
+
+    let in_ten_days = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("clock after epoch")
+        .as_secs_f64()
+        + 10.0 * 86400.0;
+    for (kind, name) in [("listener", "default"), ("sni", "portal.example.com,*.portal.example.com")] {
+        let expiry = vault.metrics.cert_expiry.with_label_values(&[kind, name]).get();
+        assert!((expiry - in_ten_days).abs() < 120.0, "{kind} {name} expires at {expiry}");
+    }
+    let rendered = vault.metrics.render().expect("metrics render");
+    assert!(!rendered.contains("grafana.example.com"));
+}
+
+#[tokio::test]
+async fn sni_cert_is_read_from_files_and_renewed() {
+    let n = FILE_SEQ.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir();
+    let key_file = dir.join(format!("rproxy-sni-{}-{n}-private.pem", std::process::id()));
+    let cert_file = dir.join(format!("rproxy-sni-{}-{n}-chain.pem", std::process::id()));
+    let write = |issued: &Issued| {
+        std::fs::write(&key_file, issued.key.private_key_to_pem_pkcs8().expect("key pem")).expect("write key");
+        std::fs::write(&cert_file, [issued.leaf.to_pem().expect("pem"), issued.ca.to_pem().expect("pem")].concat())
+            .expect("write chain");
+    };
+    let vault = sni_vault(vec![tls_cert(serde_json::json!({
+        "hosts": ["grafana.other.org"],
+        "cert_file": cert_file.to_string_lossy(),
+        "key_file": key_file.to_string_lossy(),
+    }))]);
+    let sni = &vault.sni_certs[0];
+    let resolver = vault.cert_resolver();
+
+    write(&issue("first"));
+    assert!(sni.refresh(&vault.rp_config).await.expect("files load"));
+    assert!(!sni.refresh(&vault.rp_config).await.expect("files unchanged"));
+    write(&issue("second"));
+    assert!(sni.refresh(&vault.rp_config).await.expect("files renewed"));
+
+    assert_eq!(served_for_sni(&resolver, Some("grafana.other.org")).await.as_deref(), Some("second"));
+    let _ = std::fs::remove_file(&key_file);
+    let _ = std::fs::remove_file(&cert_file);
+}
+
+#[test]
+fn try_new_rejects_invalid_tls_certs() {
+    let err = |value: serde_json::Value| {
+        try_vault(RPConfig {
+            tls_certs: vec![tls_cert(serde_json::json!({"hosts": ["ok.example.com"], "vault_path": "ok"})), tls_cert(value)],
+            ..RPConfig::default()
+        })
+        .err()
+        .expect("tls cert should be rejected")
+        .to_string()
+    };
+
+    assert!(err(serde_json::json!({"hosts": [], "vault_path": "x"})).starts_with("tls_certs[1]: hosts must not be empty"));
+    assert!(err(serde_json::json!({"hosts": ["a.com"]})).contains("either vault_path"));
+    assert!(err(serde_json::json!({"hosts": ["a.com"], "vault_path": "x", "cert_file": "c", "key_file": "k"})).contains("mutually exclusive"));
+    assert!(err(serde_json::json!({"hosts": ["a.com"], "cert_file": "c"})).contains("must both be set"));
+    assert!(err(serde_json::json!({"hosts": ["a.*.com"], "vault_path": "x"})).contains("leftmost"));
+}
+
+#[derive(Clone)]
+struct MockPki {
+    ca: Arc<(X509, PKey<Private>)>,
+    issued: Arc<AtomicUsize>,
+}
+
+async fn approle_login() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "request_id": "login", "lease_id": "", "lease_duration": 0, "renewable": false,
+        "data": null, "warnings": null, "wrap_info": null,
+        "auth": {
+            "client_token": "token", "accessor": "", "policies": [], "token_policies": [],
+            "metadata": null, "lease_duration": 3600, "renewable": true, "entity_id": "",
+            "token_type": "service", "orphan": true
+        }
+    }))
+}
+
+async fn pki_issue(
+    State(mock): State<MockPki>,
+    AxumPath(role): AxumPath<String>,
+    body: axum::body::Bytes,
+) -> Json<serde_json::Value> {
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+    mock.issued.fetch_add(1, Ordering::SeqCst);
+    let (ca, ca_key) = &*mock.ca;
+    let cn = body["common_name"].as_str().unwrap_or_default();
+    let key = key();
+    let leaf = cert(cn, &key, Some((ca, ca_key)), days(3));
+    let pem = |c: &X509| String::from_utf8(c.to_pem().expect("pem")).expect("utf8");
+    Json(serde_json::json!({
+        "request_id": role, "lease_id": "", "lease_duration": 0, "renewable": false,
+        "warnings": null, "wrap_info": null, "auth": null,
+        "data": {
+            "certificate": pem(&leaf),
+            "issuing_ca": pem(ca),
+            "ca_chain": [pem(ca)],
+            "private_key": String::from_utf8(key.private_key_to_pem_pkcs8().expect("key pem")).expect("utf8"),
+            "private_key_type": "ec",
+            "serial_number": "01",
+            "expiration": 0
+        }
+    }))
+}
+
+async fn mock_vault(mock: MockPki) -> String {
+    let app = Router::new()
+        .route("/v1/auth/approle/login", post(approle_login))
+        .route("/v1/pki/issue/{role}", post(pki_issue))
+        .with_state(mock);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind mock vault");
+    let addr = listener.local_addr().expect("mock vault addr");
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    format!("http://{addr}")
+}
+
+#[tokio::test]
+async fn vault_pki_client_certs_are_issued_renewed_and_pruned() {
+    let ca_key = key();
+    let mock = MockPki {
+        ca: Arc::new((cert("pki-ca", &ca_key, None, days(30)), ca_key)),
+        issued: Arc::new(AtomicUsize::new(0)),
+    };
+    let pki: VaultPkiConfig =
+        serde_json::from_value(serde_json::json!({"role": "rproxy", "common_name": "rproxy.internal"})).expect("pki");
+    let upstream: UpstreamDetails = serde_json::from_value(serde_json::json!({
+        "upstream": "svc",
+        "tls": {"vault_pki": {"role": "rproxy", "common_name": "rproxy.internal"}}
+    }))
+    .expect("upstream");
+    let vault = try_vault(RPConfig {
+        vault_address: mock_vault(mock.clone()).await,
+        host_to_upstream: HashMap::from([("svc".to_string(), upstream)]),
+        ..RPConfig::default()
+    })
+    .expect("vault");
+
+    vault.refresh_client_certs().await;
+    let issued = vault.client_certs.get(&pki).map(|c| c.clone()).expect("client cert issued");
+    assert_eq!(common_name(issued.cert_key.leaf()), "rproxy.internal");
+    assert_eq!(issued.cert_key.intermediates().len(), 1);
+    // 3 day certificate, renewed once a third of its lifetime is left
+    let now = std::time::Instant::now();
+    assert!(issued.renew_at > now + Duration::from_secs(47 * 3600));
+    assert!(issued.renew_at < now + Duration::from_secs(49 * 3600));
+
+    vault.refresh_client_certs().await;
+    assert_eq!(mock.issued.load(Ordering::SeqCst), 1, "not due for renewal yet");
+
+    vault.client_certs.alter(&pki, |_, mut c| {
+        c.renew_at = now;
+        c
+    });
+    vault.refresh_client_certs().await;
+    assert_eq!(mock.issued.load(Ordering::SeqCst), 2, "renewed when due");
+
+    vault.live_config.rp_config.store(Arc::new(RPConfig::default()));
+    vault.refresh_client_certs().await;
+    assert!(vault.client_certs.is_empty());
+}
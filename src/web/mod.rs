@@ -1,16 +1,18 @@
 use crate::config::RPConfig;
-use crate::log_info;
-use crate::structs::{ConsulNode, RuntimeState, Web};
+use crate::{log_error, log_info};
+use crate::structs::{NetIqLoadBalancer, RuntimeState, Web};
 use async_trait::async_trait;
 use axum::response::Redirect;
-use axum::routing::get;
+use axum::extract::ConnectInfo;
+use axum::http::{StatusCode, header};
+use axum::routing::{get, post};
 use axum::{Json, Router};
-use dashmap::DashMap;
 use pingora_core::server::ShutdownWatch;
 use pingora_core::services::background::BackgroundService;
 use serde_json::{Value, json};
-use std::sync::Arc;
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 #[async_trait]
 impl BackgroundService for Web {
@@ -29,17 +31,39 @@ impl BackgroundService for Web {
 }
 
 impl Web {
-    pub fn new(rp_config: RPConfig, nodes: Arc<DashMap<String, Vec<ConsulNode>>>, runtime_state: RuntimeState) -> Self {
-        Self { rp_config, nodes , runtime_state }
+    /// Shares the discovery, health and metrics state of `lb`.
+    pub fn new(rp_config: RPConfig, lb: &NetIqLoadBalancer, runtime_state: RuntimeState) -> Self {
+        Self {
+            rp_config,
+            nodes: lb.nodes.clone(),
+            draining: lb.draining.clone(),
+            health: lb.health.clone(),
+            outliers: lb.outliers.clone(),
+            metrics: lb.metrics.clone(),
+            live_config: lb.live_config.clone(),
+            runtime_state,
+        }
     }
 
     pub async fn bind_http(&self) {
         let self_clone = self.clone();
+        let metrics_clone = self.clone();
+        let reload_clone = self.clone();
         let router = Router::new()
             .route("/", get(|| async { Redirect::permanent("/stats") }))
             .route(
                 "/stats",
                 get(move || async move { self_clone.stats().await }),
+            )
+            .route(
+                "/metrics",
+                get(move || async move { metrics_clone.metrics() }),
+            )
+            .route(
+                "/reload",
+                post(move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                    reload_clone.reload(peer).await
+                }),
             );
         log_info!(
             "{}",
@@ -51,7 +75,44 @@ impl Web {
         let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", self.rp_config.port))
             .await
             .unwrap();
-        axum::serve(listener, router).await.unwrap();
+        axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    }
+
+    /// Reloads the config file; only accepted from the host itself.
+    async fn reload(&self, peer: SocketAddr) -> (StatusCode, Json<Value>) {
+        if !peer.ip().is_loopback() {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "status": "ERROR", "error": "reload is only allowed from localhost" })),
+            );
+        }
+        match self.live_config.reload().await {
+            Ok(report) => (
+                StatusCode::OK,
+                Json(json!({
+                    "status": "OK",
+                    "applied": report.applied,
+                    "requires_restart": report.requires_restart
+                })),
+            ),
+            Err(e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "status": "ERROR", "error": e.to_string() })),
+            ),
+        }
+    }
+
+    /// Prometheus text format.
+    fn metrics(&self) -> (StatusCode, [(header::HeaderName, &'static str); 1], String) {
+        match self.metrics.render() {
+            Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body),
+            Err(e) => {
+                log_error!("Unable to render metrics: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], e.to_string())
+            }
+        }
     }
 
     async fn stats(&self) -> Json<Value> {
@@ -69,10 +130,50 @@ impl Web {
             })
             .collect::<serde_json::Map<String, Value>>();
 
+        let grace = Duration::from_secs(self.live_config.rp_config.load().consul_drain_grace_secs);
+        let draining = self
+            .draining
+            .iter()
+            .map(|entry| {
+                let remaining = grace.saturating_sub(entry.value().elapsed()).as_secs();
+                (entry.key().clone(), json!({ "removal_in_secs": remaining }))
+            })
+            .collect::<serde_json::Map<String, Value>>();
+
+        let health = self
+            .health
+            .iter()
+            .map(|entry| (entry.key().clone(), json!(entry.value())))
+            .collect::<serde_json::Map<String, Value>>();
+
+        let now = Instant::now();
+        let outliers = self
+            .outliers
+            .iter()
+            .map(|entry| {
+                let backends = entry
+                    .value()
+                    .iter()
+                    .map(|(backend, state)| {
+                        let ejection = json!({
+                            "consecutive_failures": state.consecutive_failures,
+                            "ejections": state.ejections,
+                            "ejected_for_secs": state.ejected_for(now).as_secs()
+                        });
+                        (backend.clone(), ejection)
+                    })
+                    .collect::<serde_json::Map<String, Value>>();
+                (entry.key().clone(), json!(backends))
+            })
+            .collect::<serde_json::Map<String, Value>>();
+
         Json(json!({
             "status": "OK",
             "leader": self.runtime_state.is_leader.load(Ordering::Relaxed),
-            "nodes" : nodes
+            "nodes" : nodes,
+            "draining" : draining,
+            "health" : health,
+            "outliers" : outliers
         }))
     }
 }